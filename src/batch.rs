@@ -0,0 +1,55 @@
+/// One change in a `Batch`
+#[derive(Clone, Debug, PartialEq)]
+pub enum BatchOp {
+    Set(String, Vec<u8>, Option<i64>), // key, value, and expiry time
+    Delete(String),
+}
+
+/// Sets and deletes to make all together, or not at all, with `Store::apply()`
+///
+/// They get written to the data file between a begin and a commit marker, so if the process
+/// dies part-way through, the next open finds the batch without its commit, and drops all of it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Batch {
+    ops: Vec<BatchOp>,
+}
+
+impl Batch {
+    pub fn new() -> Batch {
+        Batch::default()
+    }
+
+    pub fn set(&mut self, key: &str, val: &[u8]) -> &mut Batch {
+        self.ops
+            .push(BatchOp::Set(key.to_string(), val.to_vec(), None));
+        self
+    }
+
+    /// Set `key` to `val`, to expire at `expires_at` (seconds since the epoch)
+    pub fn set_expiring(&mut self, key: &str, val: &[u8], expires_at: i64) -> &mut Batch {
+        self.ops.push(BatchOp::Set(
+            key.to_string(),
+            val.to_vec(),
+            Some(expires_at),
+        ));
+        self
+    }
+
+    pub fn delete(&mut self, key: &str) -> &mut Batch {
+        self.ops.push(BatchOp::Delete(key.to_string()));
+        self
+    }
+
+    /// The changes, in the order they were added (later ones win, for the same key)
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
@@ -0,0 +1,185 @@
+use crate::error::Error;
+use nix::errno::Errno;
+use nix::unistd::fsync;
+use std::collections::HashMap;
+use std::mem;
+use std::os::fd::BorrowedFd;
+use std::str::FromStr;
+use std::sync::{Condvar, Mutex};
+
+/// When writes get flushed (`fsync`) to the disk, before they count as done
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Durability {
+    /// Every write, before it is acknowledged (concurrent ones sharing an fsync, and concurrent
+    /// sets a write as well)
+    #[default]
+    Always,
+    /// Every so many milliseconds, in the background, so a crash can lose that much
+    Interval(u64),
+    /// Whenever the kernel gets round to it
+    Never,
+}
+
+impl FromStr for Durability {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(Durability::Always),
+            "never" => Ok(Durability::Never),
+            _ => match s.strip_suffix("ms").unwrap_or(s).parse::<u64>() {
+                Ok(0) | Err(_) => Err(format!(
+                    "unknown durability {s:?} (always, never, or an interval such as 100ms)"
+                )),
+                Ok(millis) => Ok(Durability::Interval(millis)),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct SyncState {
+    written: u64, // writes completed so far
+    synced: u64,  // writes known to be on the disk
+    syncing: bool,
+    syncs: u64, // fsync calls made
+}
+
+/// Group commit: writers waiting on an fsync at the same time share one
+///
+/// Each writer takes a ticket once its write is done, then waits until a sync covers it.
+/// Whoever finds nobody else syncing becomes the leader, and fsyncs on behalf of every
+/// write completed so far, while the rest wait for it, rather than each making their own.
+#[derive(Debug, Default)]
+pub(crate) struct GroupCommit {
+    state: Mutex<SyncState>,
+    synced: Condvar,
+}
+
+impl GroupCommit {
+    /// Note a completed write, returning the ticket to `sync()` it with
+    pub(crate) fn written(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.written += 1;
+        state.written
+    }
+
+    /// Whether there are writes no sync has covered yet
+    pub(crate) fn is_dirty(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.written > state.synced
+    }
+
+    pub(crate) fn syncs(&self) -> u64 {
+        self.state.lock().unwrap().syncs
+    }
+
+    /// Wait until everything written so far is on the disk
+    pub(crate) fn sync_all(&self, fd: &BorrowedFd) -> Result<(), Errno> {
+        let ticket = self.state.lock().unwrap().written;
+        self.sync(fd, ticket)
+    }
+
+    /// Wait until the write with `ticket` is on the disk, fsyncing `fd` (open on the data file) if it comes to it
+    pub(crate) fn sync(&self, fd: &BorrowedFd, ticket: u64) -> Result<(), Errno> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.synced >= ticket {
+                return Ok(());
+            }
+            if !state.syncing {
+                break;
+            }
+            state = self.synced.wait(state).unwrap();
+        }
+
+        // lead: everything written by now gets covered by this one fsync
+        state.syncing = true;
+        let target = state.written;
+        drop(state);
+        let result = fsync(fd);
+
+        let mut state = self.state.lock().unwrap();
+        state.syncing = false;
+        state.syncs += 1;
+        if result.is_ok() {
+            state.synced = state.synced.max(target);
+        }
+        // (on failure, whoever is still waiting takes a turn at leading)
+        self.synced.notify_all();
+        result
+    }
+}
+
+#[derive(Debug)]
+struct QueueState<T> {
+    queued: Vec<(u64, T)>, // with the ticket each was queued with
+    results: HashMap<u64, Result<usize, Error>>,
+    next: u64,
+    writing: bool,
+}
+
+/// Group commit for the writes themselves: writers queued up at the same time share one
+///
+/// Each writer queues its change, then waits until it has been written. Whoever finds nobody
+/// else writing becomes the leader, and takes every change queued by then, to write them all
+/// out in one go (and sync them with one fsync), handing each writer back its own result.
+#[derive(Debug)]
+pub(crate) struct WriteQueue<T> {
+    state: Mutex<QueueState<T>>,
+    written: Condvar,
+}
+
+impl<T> Default for WriteQueue<T> {
+    fn default() -> Self {
+        WriteQueue {
+            state: Mutex::new(QueueState {
+                queued: Vec::new(),
+                results: HashMap::new(),
+                next: 0,
+                writing: false,
+            }),
+            written: Condvar::new(),
+        }
+    }
+}
+
+impl<T> WriteQueue<T> {
+    /// Queue `change`, and wait for its result, calling `write` (with everything queued by then,
+    /// which it returns a result for each of, in order) if it comes to it
+    pub(crate) fn submit<F>(&self, change: T, write: F) -> Result<usize, Error>
+    where
+        F: FnOnce(&[T]) -> Vec<Result<usize, Error>>,
+    {
+        let mut state = self.state.lock().unwrap();
+        let ticket = state.next;
+        state.next += 1;
+        state.queued.push((ticket, change));
+        loop {
+            if let Some(result) = state.results.remove(&ticket) {
+                return result;
+            }
+            // (with nobody writing, and no result, the change is still queued)
+            if !state.writing {
+                break;
+            }
+            state = self.written.wait(state).unwrap();
+        }
+
+        // lead: everything queued by now goes out together
+        state.writing = true;
+        let (tickets, changes): (Vec<u64>, Vec<T>) =
+            mem::take(&mut state.queued).into_iter().unzip();
+        drop(state);
+        let results = write(&changes);
+
+        let mut state = self.state.lock().unwrap();
+        state.writing = false;
+        state.results.extend(tickets.into_iter().zip(results));
+        self.written.notify_all();
+        state
+            .results
+            .remove(&ticket)
+            .unwrap_or(Err(Error::Sys(Errno::EIO)))
+    }
+}
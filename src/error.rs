@@ -0,0 +1,81 @@
+use nix::errno::Errno;
+use std::fmt;
+
+/// What can go wrong when working with a data file
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// A system call failed
+    Sys(Errno),
+    /// The record starting at this byte offset fails its checksum, or cannot possibly fit in the file
+    Corrupt(u64),
+    /// The file does not start with a data file header (e.g. it was written before format version 1)
+    NotDataFile,
+    /// The data file was written in a format version this build does not know how to read
+    UnsupportedVersion(u16),
+    /// The data file header names a hash algorithm this build does not know about
+    UnsupportedHasher(u8),
+    /// The data file header has flags set for record fields this build does not know about
+    UnsupportedFlags(u8),
+    /// The key hashes the same as a different key, which is already stored in the data file
+    KeyCollision,
+    /// The data file was created before records had room for an expiry time
+    NoExpiry,
+    /// The data file was created before records could be written in batches
+    NoBatches,
+    /// The data file was created before records stored the original key alongside its hash, so
+    /// there is no telling which keys it holds
+    NoKeys,
+    /// The value is not a (64-bit, signed) integer to count with, or the count would overflow
+    NotInteger,
+    /// Appending would make the value longer than the limit it was appended with (in bytes)
+    TooLong(usize),
+    /// A migration was asked to upgrade a data file which already has a header
+    AlreadyMigrated,
+    /// Fewer (or more) records came out of a migration than went into it: (expected, found)
+    Mismatch(usize, usize),
+}
+
+impl Error {
+    /// Short description, in the same spirit as `Errno::desc()`
+    pub fn desc(&self) -> String {
+        match self {
+            Error::Sys(e) => String::from(e.desc()),
+            Error::Corrupt(offset) => format!("corrupt record at offset {offset}"),
+            Error::NotDataFile => String::from("not a coat-check data file"),
+            Error::UnsupportedVersion(version) => {
+                format!("unsupported data file format version {version}")
+            }
+            Error::UnsupportedHasher(id) => format!("unsupported hash algorithm id {id}"),
+            Error::UnsupportedFlags(flags) => format!("unsupported data file flags {flags:#04x}"),
+            Error::KeyCollision => {
+                String::from("key collides with a different key of the same hash")
+            }
+            Error::NoExpiry => String::from("data file does not support expiry times"),
+            Error::NoBatches => String::from("data file does not support batches"),
+            Error::NoKeys => String::from("data file does not store keys"),
+            Error::NotInteger => String::from("value is not an integer, or out of range"),
+            Error::TooLong(limit) => format!("value would be longer than {limit} bytes"),
+            Error::AlreadyMigrated => String::from("data file is already in the current format"),
+            Error::Mismatch(expected, found) => {
+                format!("expected {expected} records, but found {found}")
+            }
+        }
+    }
+}
+
+impl From<Errno> for Error {
+    fn from(e: Errno) -> Self {
+        Error::Sys(e)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Sys(e) => write!(f, "{e}"),
+            _ => write!(f, "{}", self.desc()),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -1,329 +1,1154 @@
-use crate::hasher;
-use crate::signal_syscalls::COMPACT_SIGNALED;
+use crate::error::Error;
+use crate::format::{
+    FILE_HEADER_SIZE, FLAG_KEYS, FileHeader, RecordHeader, RecordKind, encode_record,
+    legacy_header_size, looks_like_header,
+};
+use crate::inspect::{Filter, Listing, inspect_file};
+use crate::keydir::{Keydir, KeydirEntry};
+use crate::store::{Stats, Store, StoreOptions};
+use crate::verify::{Report, Salvage, repair_file, verify_file};
 use chrono::Utc;
+use log::warn;
+use nix::dir::Dir;
 use nix::errno::Errno;
-use nix::fcntl::{Flock, FlockArg, OFlag, open, renameat};
-use nix::sys::stat::Mode;
-use nix::unistd::{Whence, close, lseek, read, write};
+use nix::fcntl::{AT_FDCWD, Flock, FlockArg, OFlag, open, renameat};
+use nix::sys::stat::{Mode, fstat, stat};
+use nix::sys::uio::{pread, pwrite};
+use nix::unistd::{Whence, close, fsync, ftruncate, lseek, read, unlink, write};
+use std::collections::HashSet;
 use std::os::fd::{AsFd, BorrowedFd, OwnedFd};
 use std::path::PathBuf;
-use std::sync::atomic::Ordering;
 
-const SPACER: usize = std::mem::size_of::<usize>();
+// what compaction temp files are called, after the name of the data file: `<data file>.compact-<random>`
+const COMPACT_TMP_INFIX: &str = ".compact-";
 
-fn record_reader<F>(fd: &BorrowedFd, key: &str, matchop: F) -> Result<Option<Vec<u8>>, Errno>
+pub(crate) fn file_mode() -> Mode {
+    Mode::S_IRUSR | Mode::S_IWUSR | Mode::S_IRGRP | Mode::S_IWGRP | Mode::S_IROTH | Mode::S_IWOTH
+}
+
+/// Open `filepath` and take the requested flock on it
+///
+/// `compact()` swaps a new file in place of the old one while holding its lock, so anyone
+/// who was waiting on that lock wakes up holding the (now unlinked) old file: in that case,
+/// let go of it and try again with whatever is at `filepath` now.
+pub(crate) fn open_locked(
+    filepath: &str,
+    oflag: OFlag,
+    arg: FlockArg,
+) -> Result<Flock<OwnedFd>, Errno> {
+    loop {
+        let fd: OwnedFd = open(filepath, oflag, file_mode())?;
+        let lock = match Flock::lock(fd, arg) {
+            Ok(locked) => locked,
+            Err((_, e)) => return Err(e),
+        };
+        match stat(filepath) {
+            Ok(current) if current.st_ino == fstat(lock.as_fd())?.st_ino => return Ok(lock),
+            Ok(_) | Err(Errno::ENOENT) => release(lock)?,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Release the lock and close the fd
+pub(crate) fn release(lock: Flock<OwnedFd>) -> Result<(), Errno> {
+    close(unlock(lock)?)
+}
+
+/// Release the lock, but keep the fd open (e.g. to fsync it without holding up anyone else)
+pub(crate) fn unlock(lock: Flock<OwnedFd>) -> Result<OwnedFd, Errno> {
+    match lock.unlock() {
+        Ok(unlocked) => Ok(unlocked),
+        Err((_, e)) => Err(e),
+    }
+}
+
+pub(crate) fn record_reader<F>(
+    fd: &BorrowedFd,
+    file_header: &FileHeader,
+    from: u64,
+    mut matchop: F,
+) -> Result<Option<Vec<u8>>, Error>
 where
-    F: Fn(&BorrowedFd, [u8; SPACER]) -> Result<Option<Vec<u8>>, Errno>,
+    F: FnMut(&BorrowedFd, &RecordHeader) -> Result<Option<Vec<u8>>, Error>,
 {
-    let size_buf: &mut [u8] = &mut vec![0; SPACER];
-    let mut sizer: [u8; SPACER] = [0; SPACER];
-
-    let hash = hasher::hash_key(key);
-    let key_buf: &mut [u8] = &mut vec![0; hash.len()];
-    let del_buf: &mut [u8] = &mut vec![0; 1];
-
-    // iterate through the records (`[(hashed) key][size of value][deleted?][value]` byte arrays) in file
-    let mut nbytes = read(fd, key_buf)?;
-    while nbytes > 0 {
-        if hash != str::from_utf8(key_buf).unwrap() {
-            // no match at the current key position,
-            // so read the value size, and skip ahead
-            // to the next key/value array
-            _ = read(fd, size_buf)?;
-            sizer.clone_from_slice(size_buf);
-            _ = read(fd, del_buf)?; // skip over the delete flag
-            lseek(fd, i64::from_ne_bytes(sizer), Whence::SeekCur)?;
-        } else {
-            // matched, so get the value size, and execute the matchop function
-            _ = read(fd, size_buf)?;
-            sizer.clone_from_slice(size_buf);
-
-            match matchop(fd, sizer) {
-                Ok(result) => match result {
-                    Some(data) => return Ok(Some(data)), // stop iterating through the file
-                    None => (),
-                },
-                Err(e) => return Err(e),
+    let head_buf: &mut [u8] = &mut vec![0; file_header.record_header_size()];
+    let file_len = fstat(fd)?.st_size as u64;
+
+    // iterate through the records (`[(hashed) key][size of value][deleted?][checksum][size of key][expires at][key][value]`
+    // byte arrays) in file;
+    // a short read of the header means the last record was cut off by a crash part-way through its append,
+    // so stop there, as if it were the EOF
+    let mut offset = lseek(fd, from as i64, Whence::SeekSet)? as u64;
+    while read(fd, &mut *head_buf)? == head_buf.len() {
+        let header = RecordHeader::decode(offset, head_buf, file_header);
+
+        // likewise for a value cut off by the EOF; but if there are intact records after it, then
+        // it was the size that got damaged, and the next lseek would send us off into nowhere
+        if header.overruns(file_len) {
+            match next_valid_record(fd, file_header, offset + 1)? {
+                Some(_) => return Err(Error::Corrupt(offset)),
+                None => break,
             }
         }
-        nbytes = read(fd, key_buf)?;
+
+        // the fd is now positioned at the key (or the value, if there is none), for matchop to read if it wants to
+        if let Some(data) = matchop(fd, &header)? {
+            return Ok(Some(data)); // stop iterating through the file
+        }
+
+        // skip ahead to the next key/value array, however much of this one matchop read
+        offset = lseek(fd, header.next_offset() as i64, Whence::SeekSet)? as u64;
     }
 
     // reached the EOF without a match: use EKEYEXPIRED (Key has expired) as the return value
-    Err(Errno::EKEYEXPIRED)
+    Err(Error::Sys(Errno::EKEYEXPIRED))
+}
+
+/// Look for the start of an intact record anywhere from `from` onwards
+///
+/// Candidates are the positions where a plausible header (a hex hash, a deleted flag of 0 or 1, or another known status,
+/// a size which fits in the file) is followed by a value which matches its checksum.
+pub(crate) fn next_valid_record(
+    fd: &BorrowedFd,
+    file_header: &FileHeader,
+    from: u64,
+) -> Result<Option<u64>, Error> {
+    const CHUNK_SIZE: usize = 64 * 1024;
+    let head_size = file_header.record_header_size();
+    let file_len = fstat(fd)?.st_size as u64;
+
+    // read ahead a chunk at a time, with enough overlap for a header to straddle two chunks
+    let chunk: &mut [u8] = &mut vec![0; CHUNK_SIZE + head_size];
+    let mut start = from;
+    while start + head_size as u64 <= file_len {
+        let nbytes = pread(fd, chunk, start as i64)?;
+        if nbytes < head_size {
+            break;
+        }
+        for i in 0..=nbytes - head_size {
+            let head = &chunk[i..i + head_size];
+            if !looks_like_header(head, file_header) {
+                continue;
+            }
+            let header = RecordHeader::decode(start + i as u64, head, file_header);
+            if header.overruns(file_len) {
+                continue;
+            }
+            let body_buf: &mut [u8] = &mut vec![0; header.body_size()];
+            _ = pread(fd, body_buf, header.body_offset() as i64)?;
+            if header.verify(body_buf).is_ok() {
+                return Ok(Some(header.offset));
+            }
+        }
+        start += (nbytes - head_size + 1) as u64;
+    }
+
+    Ok(None)
+}
+
+/// Bring `keydir` up to date with the data file at `filepath`, which `fd` has open
+///
+/// Only the records appended since the last call get read, unless the file was swapped
+/// (or shrunk) underneath the keydir, in which case it is rebuilt: from the hint file
+/// left by `compact()` plus whatever was appended after it, or from the start otherwise.
+pub(crate) fn index_records(
+    filepath: &str,
+    fd: &BorrowedFd,
+    keydir: &mut Keydir,
+) -> Result<(), Error> {
+    let current = fstat(fd)?;
+    let file_len = current.st_size as u64;
+    if current.st_ino != keydir.ino
+        || file_len < keydir.indexed_len
+        || keydir.indexed_len < FILE_HEADER_SIZE as u64
+    {
+        keydir.reset(current.st_ino);
+        let file_header = match read_file_header(fd)? {
+            Some(file_header) => file_header,
+            None => return Ok(()), // nothing but (part of) a header so far, which truncate_torn_tail can clean up
+        };
+        keydir.file_header = file_header;
+        if !load_hint(filepath, fd, file_len, keydir) {
+            keydir.reset(current.st_ino);
+            keydir.file_header = file_header;
+            keydir.indexed_len = FILE_HEADER_SIZE as u64;
+        }
+    }
+    if file_len == keydir.indexed_len {
+        return Ok(());
+    }
+
+    let mut indexed_len = keydir.indexed_len;
+    let file_header = keydir.file_header;
+    // the changes made by a batch, held back until its commit marker turns up
+    let mut batch: Option<Vec<(String, Option<KeydirEntry>)>> = None;
+    let result = record_reader(fd, &file_header, keydir.indexed_len, |_, header| {
+        // later records win, since an upsert only ever appends after the original
+        let change = match header.kind {
+            RecordKind::Value if !header.deleted => Some((
+                header.hash.clone(),
+                Some(KeydirEntry {
+                    offset: header.offset,
+                    size: header.size,
+                    len: header.next_offset() - header.offset,
+                }),
+            )),
+            RecordKind::Value => None,
+            RecordKind::Tombstone => Some((header.hash.clone(), None)),
+            RecordKind::BatchBegin => {
+                // (a batch left open before this one never got committed, so it never happened)
+                batch = Some(Vec::new());
+                None
+            }
+            RecordKind::BatchCommit => {
+                for (hash, entry) in batch.take().unwrap_or_default() {
+                    index_change(keydir, hash, entry);
+                }
+                None
+            }
+        };
+        match (&mut batch, change) {
+            (Some(pending), Some(change)) => pending.push(change),
+            (None, Some((hash, entry))) => index_change(keydir, hash, entry),
+            _ => (),
+        }
+        // a batch still open at the end of the file is as incomplete as a torn record
+        if batch.is_none() {
+            indexed_len = header.next_offset();
+        }
+        Ok(None)
+    });
+    match result {
+        Err(Error::Sys(Errno::EKEYEXPIRED)) => {
+            keydir.indexed_len = indexed_len;
+            Ok(())
+        }
+        Err(e) => Err(e),
+        Ok(_) => Ok(()),
+    }
+}
+
+// the keydir after a record for `hash` (`None` for a tombstone)
+fn index_change(keydir: &mut Keydir, hash: String, entry: Option<KeydirEntry>) {
+    match entry {
+        Some(entry) => keydir.insert(hash, entry),
+        None => _ = keydir.remove(&hash),
+    }
 }
 
-/* Higher order functions, to use as `matchop: F` in `record_reader<F>`
+/// Read the header at the start of the data file, refusing any file this version cannot read
+///
+/// Returns `None` while the file is empty, or when a crash cut its very first append short.
+pub(crate) fn read_file_header(fd: &BorrowedFd) -> Result<Option<FileHeader>, Error> {
+    let buf: &mut [u8] = &mut [0; FILE_HEADER_SIZE];
+    let nbytes = pread(fd, buf, 0)?;
+    if FileHeader::is_torn(&buf[0..nbytes]) {
+        return Ok(None);
+    }
+    FileHeader::decode(&buf[0..nbytes]).map(Some)
+}
+
+/// Crash recovery: cut off anything `index_records` found after the last complete record, i.e. an
+/// append that a crash stopped part-way through, so that the next append does not land after it
+///
+/// Only for callers holding the exclusive lock, with `fd` open for writing.
+pub(crate) fn truncate_torn_tail(
+    filepath: &str,
+    fd: &BorrowedFd,
+    keydir: &Keydir,
+) -> Result<(), Error> {
+    let file_len = fstat(fd)?.st_size as u64;
+    if keydir.indexed_len < file_len {
+        warn!(
+            "{filepath}: discarding {} bytes of incomplete record at offset {}",
+            file_len - keydir.indexed_len,
+            keydir.indexed_len
+        );
+        ftruncate(fd, keydir.indexed_len as i64)?;
+    }
+    Ok(())
+}
+
+/* Hint files
  *
- * find()   used by read_key()
- * delete() used by delete_key()
+ * Written by compact() next to the data file, so that opening it again does not mean
+ * scanning every record: `[magic][inode of data file][length of data file covered]`
+ * followed by one `[(hashed) key][offset][size of value][deleted?][length of record]` entry
+ * per live record
  *
  */
 
-fn find(fd: &BorrowedFd, sizer: [u8; SPACER]) -> Result<Option<Vec<u8>>, Errno> {
-    // read the deleted flag
-    let del_buf: &mut [u8] = &mut vec![0; 1];
-    _ = read(fd, del_buf)?;
-    // also read the value, regardless, otherwise the record_reader() loop will not continue in the correct position
-    let val_buf: &mut [u8] = &mut vec![0; usize::from_ne_bytes(sizer)];
-    _ = read(fd, val_buf)?;
-    if del_buf[0] == 0 {
-        // not deleted, so return the corresponding value as a match
-        let mut result = Vec::new();
-        result.extend_from_slice(val_buf);
-        return Ok(Some(result));
-    }
-    // not found on this iteration of the record_reader() loop
-    Ok(None)
+const HINT_MAGIC: &[u8; 8] = b"COATHNT2"; // (the first version of hint files had no record lengths)
+const HINT_HEADER_SIZE: usize = HINT_MAGIC.len() + 8 + 8;
+
+fn hint_path(filepath: &str) -> String {
+    format!("{filepath}.hint")
 }
 
-fn delete(fd: &BorrowedFd, sizer: [u8; SPACER]) -> Result<Option<Vec<u8>>, Errno> {
-    // record the current file position, before reading the deleted flag
-    let current_pos = lseek(fd, 0, Whence::SeekCur)?;
-    // read the deleted flag
-    let del_buf: &mut [u8] = &mut vec![0; 1];
-    _ = read(fd, del_buf)?;
-    // also read the value, regardless, otherwise the record_reader() loop will not continue in the correct position
-    let val_buf: &mut [u8] = &mut vec![0; usize::from_ne_bytes(sizer)];
-    _ = read(fd, val_buf)?;
-    if del_buf[0] == 0 {
-        // not deleted, so back up, and overwrite the deleted flag to true
-        lseek(fd, current_pos, Whence::SeekSet)?;
-        let deleted: &mut [u8] = &mut vec![1; 1];
-        _ = write(fd, deleted)?;
-
-        // return the corresponding value, so that the caller knows to stop iterating
-        let mut result = Vec::new();
-        result.extend_from_slice(val_buf);
-        return Ok(Some(result));
-    }
-    // not found on this iteration of the record_reader() loop
-    Ok(None)
+fn hint_entry_size(hash_size: usize) -> usize {
+    hash_size + 8 + 8 + 1 + 8
 }
 
-/* Public API  */
+pub(crate) fn write_all(fd: &BorrowedFd, buf: &[u8]) -> Result<usize, Errno> {
+    let mut written = 0;
+    while written < buf.len() {
+        written += write(fd, &buf[written..])?;
+    }
+    Ok(written)
+}
 
-pub fn read_key(filepath: String, key: &str) -> Result<Option<Vec<u8>>, Errno> {
-    let fd: OwnedFd = open(filepath.as_str(), OFlag::O_RDONLY, Mode::empty())?;
-    let lock = match Flock::lock(fd, FlockArg::LockShared) {
-        Ok(locked) => locked,
-        Err((_, e)) => return Err(e),
-    };
+fn write_hint(filepath: &str, keydir: &Keydir) -> Result<(), Errno> {
+    let hash_size = keydir.file_header.hash_size();
+    let entry_size = hint_entry_size(hash_size);
+    let mut buffer = vec![0; HINT_HEADER_SIZE + keydir.len() * entry_size];
+    buffer[0..8].copy_from_slice(HINT_MAGIC);
+    buffer[8..16].copy_from_slice(&keydir.ino.to_le_bytes());
+    buffer[16..24].copy_from_slice(&keydir.indexed_len.to_le_bytes());
+    for (i, (hash, entry)) in keydir.iter().enumerate() {
+        let start = HINT_HEADER_SIZE + i * entry_size;
+        buffer[start..start + hash_size].copy_from_slice(hash.as_bytes());
+        buffer[start + hash_size..start + hash_size + 8]
+            .copy_from_slice(&entry.offset.to_le_bytes());
+        buffer[start + hash_size + 8..start + hash_size + 16]
+            .copy_from_slice(&(entry.size as u64).to_le_bytes());
+        buffer[start + hash_size + 16] = 0; // only live records make it into the keydir
+        buffer[start + hash_size + 17..start + hash_size + 25]
+            .copy_from_slice(&entry.len.to_le_bytes());
+    }
 
-    let mut result: Result<Option<Vec<u8>>, Errno>;
-    loop {
-        result = record_reader(&lock.as_fd(), key, find);
-        // stop if found a matching, non-deleted key
-        if result.is_ok() {
-            break;
-        }
-        // or if reached EOF
-        if result.is_err_and(|x| x == Errno::EKEYEXPIRED) {
-            result = Ok(None);
-            break;
-        }
+    // write it alongside, then swap it in, so a reader never sees half a hint file
+    let hint_filepath = hint_path(filepath);
+    let tmp_filepath = format!("{hint_filepath}.tmp");
+    let tmp_fd: OwnedFd = open(
+        tmp_filepath.as_str(),
+        OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_TRUNC,
+        file_mode(),
+    )?;
+    // (on the disk before the rename, so a crash cannot leave a hint file with only part of it there)
+    let written = write_all(&tmp_fd.as_fd(), &buffer).and_then(|_| fsync(tmp_fd.as_fd()));
+    let closed = close(tmp_fd);
+    let renamed = written.and(closed).and_then(|_| {
+        renameat(
+            AT_FDCWD,
+            tmp_filepath.as_str(),
+            AT_FDCWD,
+            hint_filepath.as_str(),
+        )
+    });
+    if let Err(e) = renamed {
+        _ = unlink(tmp_filepath.as_str());
+        return Err(e);
     }
 
-    match lock.unlock() {
-        Ok(unlocked) => {
-            close(unlocked)?;
-            result
+    // and the rename only survives a crash once the directory is on the disk too
+    sync_dir(&hint_filepath)
+}
+
+fn read_hint(filepath: &str) -> Result<Vec<u8>, Errno> {
+    let fd: OwnedFd = open(hint_path(filepath).as_str(), OFlag::O_RDONLY, Mode::empty())?;
+    let mut hint = vec![0; fstat(fd.as_fd())?.st_size as usize];
+    let mut nbytes = 0;
+    while nbytes < hint.len() {
+        match read(fd.as_fd(), &mut hint[nbytes..])? {
+            0 => return Err(Errno::EIO), // shrank while being read
+            n => nbytes += n,
         }
-        Err((_, e)) => Err(e),
     }
+    close(fd)?;
+    Ok(hint)
 }
 
-pub fn delete_key(filepath: String, key: &str) -> Result<Option<Vec<u8>>, Errno> {
-    let fd: OwnedFd = open(
-        filepath.as_str(),
-        OFlag::O_RDWR,
-        Mode::S_IRUSR
-            | Mode::S_IWUSR
-            | Mode::S_IRGRP
-            | Mode::S_IWGRP
-            | Mode::S_IROTH
-            | Mode::S_IWOTH,
-    )?;
-    let lock = match Flock::lock(fd, FlockArg::LockExclusive) {
-        Ok(locked) => locked,
-        Err((_, e)) => return Err(e),
+/// Fill `keydir` from the hint file for `filepath`, if there is one that describes the
+/// data file `fd` has open: returns false if the hint is missing, stale or unreadable
+fn load_hint(filepath: &str, fd: &BorrowedFd, file_len: u64, keydir: &mut Keydir) -> bool {
+    let hint = match read_hint(filepath) {
+        Ok(bytes) => bytes,
+        Err(_) => return false,
     };
+    let hash_size = keydir.file_header.hash_size();
+    let entry_size = hint_entry_size(hash_size);
+    if hint.len() < HINT_HEADER_SIZE
+        || &hint[0..8] != HINT_MAGIC
+        || !(hint.len() - HINT_HEADER_SIZE).is_multiple_of(entry_size)
+    {
+        return false;
+    }
 
-    let mut result: Result<Option<Vec<u8>>, Errno>;
-    loop {
-        result = record_reader(&lock.as_fd(), key, delete);
-        // stop if found a matching key which was previously non-deleted
-        if result.is_ok() {
-            break;
+    // the hint has to be for this very file (compaction gives it a new inode), and the
+    // file can only have grown since, by having records appended to it
+    let ino = u64::from_le_bytes(hint[8..16].try_into().unwrap());
+    let covered_len = u64::from_le_bytes(hint[16..24].try_into().unwrap());
+    if ino != keydir.ino || covered_len > file_len {
+        return false;
+    }
+
+    for entry in hint[HINT_HEADER_SIZE..].chunks_exact(entry_size) {
+        let hash = match str::from_utf8(&entry[0..hash_size]) {
+            Ok(h) => String::from(h),
+            Err(_) => return false,
+        };
+        let offset = u64::from_le_bytes(entry[hash_size..hash_size + 8].try_into().unwrap());
+        let size = u64::from_le_bytes(entry[hash_size + 8..hash_size + 16].try_into().unwrap());
+        let deleted = entry[hash_size + 16];
+        let len = u64::from_le_bytes(entry[hash_size + 17..hash_size + 25].try_into().unwrap());
+        if deleted == 0 {
+            keydir.insert(
+                hash,
+                KeydirEntry {
+                    offset,
+                    size: size as usize,
+                    len,
+                },
+            );
         }
-        // or if reached EOF
-        if result.is_err_and(|x| x == Errno::EKEYEXPIRED) {
-            result = Ok(None);
-            break;
+        if offset + len > covered_len
+            || len < (keydir.file_header.record_header_size() as u64) + size
+        {
+            return false;
         }
     }
 
-    match lock.unlock() {
-        Ok(unlocked) => {
-            close(unlocked)?;
-            result
+    // spot check that the hint agrees with the data file, in case the inode was reused
+    let key_buf: &mut [u8] = &mut vec![0; hash_size];
+    for (hash, entry) in keydir.iter().take(2) {
+        match pread(fd, key_buf, entry.offset as i64) {
+            Ok(n) if n == hash_size && key_buf == hash.as_bytes() => (),
+            _ => return false,
         }
-        Err((_, e)) => Err(e),
     }
+
+    keydir.indexed_len = covered_len;
+    true
 }
 
-fn append_new_key_val(filepath: String, key: &str, val: &[u8]) -> Result<usize, Errno> {
-    let fd: OwnedFd = open(
-        filepath.as_str(),
-        OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_APPEND,
-        Mode::S_IRUSR
-            | Mode::S_IWUSR
-            | Mode::S_IRGRP
-            | Mode::S_IWGRP
-            | Mode::S_IROTH
-            | Mode::S_IWOTH,
-    )?;
-    let lock = match Flock::lock(fd, FlockArg::LockExclusive) {
-        Ok(locked) => locked,
-        Err((_, e)) => return Err(e),
+/* Higher order functions, applied to the record a keydir entry points at
+ *
+ * find()   used by read_key()
+ * delete() used by delete_key()
+ *
+ */
+
+/// Read the record `entry` points at, if it has neither been deleted since it was indexed, nor expired
+///
+/// In files which store the original keys, the one in the record has to be `key`: anything else
+/// is a different key with the same hash, reported as `Error::KeyCollision`.
+pub(crate) fn find_record(
+    fd: &BorrowedFd,
+    file_header: &FileHeader,
+    entry: &KeydirEntry,
+    key: &str,
+) -> Result<Option<(RecordHeader, Vec<u8>)>, Error> {
+    let (header, body) = match read_record(fd, file_header, entry.offset)? {
+        Some(record) => record,
+        None => return Ok(None),
     };
+    let (stored_key, value) = header.split(&body);
+    if header.key_size.is_some() && stored_key != key.as_bytes() {
+        return Err(Error::KeyCollision);
+    }
 
-    // produce a new record (`[(hashed) key][size of value][deleted?][value]` byte array), given the key and value data
-    let hash = hasher::hash_key(key);
-    let hash_size = hash.len();
-    let val_size = val.iter().count();
-    let sizer: [u8; SPACER] = val_size.to_ne_bytes();
-    let sizer_size = sizer.len();
-    let deleted: [u8; 1] = [0];
-    let deleted_size = deleted.len();
-    let buffer: &mut [u8] = &mut vec![0; hash_size + sizer_size + deleted_size + val_size];
-    buffer[0..hash_size].copy_from_slice(hash.as_bytes());
-    buffer[hash_size..hash_size + sizer_size].copy_from_slice(&sizer);
-    buffer[hash_size + sizer_size..hash_size + sizer_size + deleted_size].copy_from_slice(&deleted);
-    buffer[hash_size + sizer_size + deleted_size..].copy_from_slice(val);
-
-    // append it to the end of the file
-    let nbytes = write(lock.as_fd(), buffer)?;
+    // not deleted, so return the corresponding value as a match
+    let value = value.to_vec();
+    Ok(Some((header, value)))
+}
 
-    match lock.unlock() {
-        Ok(unlocked) => {
-            close(unlocked)?;
-            Ok(nbytes)
+/// Read the record at `offset`, header and (checksum-verified) body, unless it is deleted or expired
+pub(crate) fn read_record(
+    fd: &BorrowedFd,
+    file_header: &FileHeader,
+    offset: u64,
+) -> Result<Option<(RecordHeader, Vec<u8>)>, Error> {
+    let head_buf: &mut [u8] = &mut vec![0; file_header.record_header_size()];
+    let nbytes = pread(fd, head_buf, offset as i64)?;
+    if nbytes < head_buf.len() {
+        return Err(Error::Corrupt(offset));
+    }
+    let header = RecordHeader::decode(offset, head_buf, file_header);
+    if header.deleted || header.is_expired(Utc::now().timestamp()) {
+        // flagged as deleted since it was indexed, or past its expiry time, which is as good as deleted
+        return Ok(None);
+    }
+
+    // then the rest of the record, straight from where the header ends
+    let mut body = vec![0; header.body_size()];
+    let nbytes = pread(fd, &mut body, header.body_offset() as i64)?;
+    if nbytes < body.len() {
+        return Err(Error::Corrupt(offset));
+    }
+    header.verify(&body)?;
+    Ok(Some((header, body)))
+}
+
+/// Read the value of the record `entry` points at, as `find_record()`
+pub(crate) fn find(
+    fd: &BorrowedFd,
+    file_header: &FileHeader,
+    entry: &KeydirEntry,
+    key: &str,
+) -> Result<Option<Vec<u8>>, Error> {
+    Ok(find_record(fd, file_header, entry, key)?.map(|(_, value)| value))
+}
+
+pub(crate) fn delete(
+    fd: &BorrowedFd,
+    file_header: &FileHeader,
+    entry: &KeydirEntry,
+    key: &str,
+) -> Result<Option<Vec<u8>>, Error> {
+    match find(fd, file_header, entry, key)? {
+        Some(value) => {
+            // not deleted, so overwrite the deleted flag to true
+            mark_deleted(fd, file_header, entry.offset)?;
+
+            // return the corresponding value, so that the caller knows it was there
+            Ok(Some(value))
         }
-        Err((_, e)) => Err(e),
+        None => Ok(None),
     }
 }
 
-pub fn write_key_val(filepath: String, key: &str, val: &[u8]) -> Result<usize, Errno> {
-    // before writing this as a new key-value pair, make sure it does not already exist
-    match read_key(filepath.clone(), key) {
-        Ok(result) => match result {
-            Some(value_vector) => {
-                // key already exists, so upsert if the value is different
-                if val == value_vector {
-                    Ok(0)
-                } else {
-                    // upsert: delete the current key, and append the new value
-                    match delete_key(filepath.clone(), key) {
-                        Ok(_) => append_new_key_val(filepath, key, val),
-                        Err(e) => Err(e),
-                    }
-                }
-            }
-            None => append_new_key_val(filepath, key, val),
-        },
-        Err(e) => match e {
-            Errno::ENOENT => append_new_key_val(filepath, key, val), // file does not exist yet, so create it with this as the first entry
-            _ => Err(e),
-        },
+/// Flip the deleted flag of the record at `offset`, in place
+pub(crate) fn mark_deleted(
+    fd: &BorrowedFd,
+    file_header: &FileHeader,
+    offset: u64,
+) -> Result<(), Errno> {
+    let deleted: &[u8] = &[1; 1];
+    _ = pwrite(fd, deleted, file_header.deleted_offset(offset) as i64)?;
+    Ok(())
+}
+
+/// Append a new record for `hash` to the end of the file, returning where it starts and its length
+pub(crate) fn append_new_key_val(
+    fd: &BorrowedFd,
+    file_header: &FileHeader,
+    hash: &str,
+    key: &str,
+    val: &[u8],
+    expires_at: Option<i64>,
+) -> Result<(u64, usize), Errno> {
+    let record = encode_record(file_header, hash, key.as_bytes(), val, expires_at);
+    append_records(fd, file_header, &record)
+}
+
+/// Append already encoded `records` to the end of the file in one write, returning where they start and their length
+///
+/// The first records written to an empty file go out together with the file header.
+pub(crate) fn append_records(
+    fd: &BorrowedFd,
+    file_header: &FileHeader,
+    records: &[u8],
+) -> Result<(u64, usize), Errno> {
+    let offset = lseek(fd, 0, Whence::SeekEnd)? as u64;
+    if offset == 0 {
+        let mut buffer = file_header.encode().to_vec();
+        buffer.extend_from_slice(records);
+        let nbytes = write(fd, &buffer)?;
+        return Ok((FILE_HEADER_SIZE as u64, nbytes - FILE_HEADER_SIZE));
     }
+    let nbytes = write(fd, records)?;
+    Ok((offset, nbytes))
 }
 
-pub fn compact(filepath: String) -> Result<Option<Vec<u8>>, Errno> {
-    let read_fd: OwnedFd = open(filepath.as_str(), OFlag::O_RDONLY, Mode::empty())?;
-    let read_lock = match Flock::lock(read_fd, FlockArg::LockExclusive) {
-        Ok(locked) => locked,
-        Err((_, e)) => return Err(e),
-    };
+/// A fresh seed for the keyed hash algorithms, from the kernel's random number generator
+pub(crate) fn random_seed() -> Result<[u8; 16], Errno> {
+    let fd: OwnedFd = open("/dev/urandom", OFlag::O_RDONLY, Mode::empty())?;
+    let mut seed: [u8; 16] = [0; 16];
+    let nbytes = read(fd.as_fd(), &mut seed)?;
+    close(fd)?;
+    match nbytes == seed.len() {
+        true => Ok(seed),
+        false => Err(Errno::EIO),
+    }
+}
 
-    // need to write the tmp file in the same folder, so that renameat can be atomic
-    let parent = match PathBuf::from(filepath.clone()).parent() {
+// the folder containing `filepath`, where tmp files have to go, so that renameat can be atomic
+fn parent_folder(filepath: &str) -> String {
+    match PathBuf::from(filepath).parent() {
+        Some(path) if path.as_os_str().is_empty() => String::from("."), // a bare file name
         Some(path) => match path.to_str() {
             Some(p) => String::from(p),
             None => String::from("/tmp"),
         },
         None => String::from("/tmp"),
+    }
+}
+
+/// fsync the directory `filepath` is in, so that its entry (e.g. for a file just created) is on the disk too
+pub(crate) fn sync_dir(filepath: &str) -> Result<(), Errno> {
+    let fd: OwnedFd = open(
+        parent_folder(filepath).as_str(),
+        OFlag::O_RDONLY | OFlag::O_DIRECTORY,
+        Mode::empty(),
+    )?;
+    fsync(fd.as_fd())?;
+    close(fd)
+}
+
+/// Rewrite the data file with only its non-deleted records, returning the keydir for the new file
+///
+/// The live records go into a temp file next to it first, which is fsynced, then renamed over the
+/// data file, and then the directory is fsynced, so a crash at any point leaves either the old file
+/// or the new one in place, complete, plus at worst an orphaned temp file for `remove_orphans()`.
+///
+/// Only the indexing takes a (shared) lock on the data file, and the copying none at all, so
+/// writers carry on in the meantime: under the exclusive lock at the end, whatever they changed
+/// gets replayed into the temp file, which then takes the place of the data file.
+pub(crate) fn compact_file(filepath: &str) -> Result<Keydir, Error> {
+    // first find the live record for each key, just as opening the file would, so that records
+    // replaced (or deleted) by a batch, and any batch which never got committed, are left behind
+    let read_lock = open_locked(filepath, OFlag::O_RDONLY, FlockArg::LockShared)?;
+    let mut live = Keydir::default();
+    let indexed = index_records(filepath, &read_lock.as_fd(), &mut live);
+    let fd = unlock(read_lock)?;
+    if let Err(e) = indexed {
+        close(fd)?;
+        return Err(e);
+    }
+
+    // the tmp file keeps the format (and hash algorithm) of the original, so without
+    // even a complete header, there are no records, and nothing to compact
+    if live.indexed_len == 0 {
+        close(fd)?;
+        let mut keydir = Keydir::default();
+        keydir.ino = live.ino;
+        return Ok(keydir);
+    }
+
+    let (tmp_lock, tmp_filepath) = match create_compact_tmp(filepath) {
+        Ok(tmp) => tmp,
+        Err(e) => {
+            close(fd)?;
+            return Err(e.into());
+        }
+    };
+    let mut keydir = Keydir::default();
+    keydir.file_header = live.file_header;
+    let copied = write_all(&tmp_lock.as_fd(), &live.file_header.encode())
+        .map_err(Error::from)
+        .and_then(|nbytes| {
+            keydir.indexed_len = nbytes as u64;
+            copy_records(&fd.as_fd(), &tmp_lock.as_fd(), &live, 0, &mut keydir)
+        });
+    close(fd)?;
+    let copied = match copied {
+        Ok(copied) => copied,
+        Err(e) => {
+            _ = unlink(tmp_filepath.as_str());
+            release(tmp_lock)?;
+            return Err(e);
+        }
+    };
+
+    // then catch up with everything written since, this time holding everyone else up
+    let write_lock = match open_locked(filepath, OFlag::O_RDONLY, FlockArg::LockExclusive) {
+        Ok(lock) => lock,
+        Err(e) => {
+            _ = unlink(tmp_filepath.as_str());
+            release(tmp_lock)?;
+            return Err(e.into());
+        }
     };
+    let replayed = fstat(write_lock.as_fd())
+        .map_err(Error::from)
+        .and_then(|stat| match stat.st_ino == live.ino {
+            true => replay_records(
+                filepath,
+                &write_lock.as_fd(),
+                &tmp_lock.as_fd(),
+                live,
+                copied,
+                &mut keydir,
+            )
+            .map(|_| true),
+            // another compaction got there first, so there is nothing left to do
+            false => Ok(false),
+        });
+    let swapped = replayed.and_then(|replayed| {
+        if !replayed {
+            return Ok(false);
+        }
+        // the new file has to be complete, and on the disk, before it can take the place of the old one
+        fsync(tmp_lock.as_fd())?;
+        keydir.ino = fstat(tmp_lock.as_fd())?.st_ino;
+        // atomically replace the original file with the tmp one
+        renameat(AT_FDCWD, tmp_filepath.as_str(), AT_FDCWD, filepath)?;
+        Ok(true)
+    });
+    match swapped {
+        Ok(true) => (),
+        Ok(false) => {
+            _ = unlink(tmp_filepath.as_str());
+            release(tmp_lock)?;
+            let mut keydir = Keydir::default();
+            let indexed = index_records(filepath, &write_lock.as_fd(), &mut keydir);
+            release(write_lock)?;
+            indexed?;
+            return Ok(keydir);
+        }
+        Err(e) => {
+            _ = unlink(tmp_filepath.as_str());
+            release(tmp_lock)?;
+            release(write_lock)?;
+            return Err(e);
+        }
+    }
+
+    // and the rename only survives a crash once the directory is on the disk too
+    let synced = sync_dir(filepath);
+
+    // leave a hint file for the next open, while still holding the lock; if this fails,
+    // the old hint no longer matches the new inode, so opening falls back to a full scan
+    _ = write_hint(filepath, &keydir);
+
+    release(tmp_lock)?;
+    release(write_lock)?;
+    synced?;
+    Ok(keydir)
+}
+
+/// Count up the records of the data file `fd`, for `Store::stats()`, returning its keydir along with them
+///
+/// The records of the `newer` (hashed) keys count as dead, as they are when a newer segment has a
+/// record of the same key; otherwise, a record is live if it is the one the keydir has for its key.
+pub(crate) fn file_stats(
+    filepath: &str,
+    fd: &BorrowedFd,
+    newer: &HashSet<String>,
+) -> Result<(Stats, Keydir), Error> {
+    let mut live = Keydir::default();
+    index_records(filepath, fd, &mut live)?;
+    let mut stats = Stats {
+        file_size: fstat(fd)?.st_size as u64,
+        ..Stats::default()
+    };
+    if live.indexed_len == 0 {
+        return Ok((stats, live)); // not even a file header yet
+    }
 
+    let now = Utc::now().timestamp();
+    let from = FILE_HEADER_SIZE as u64;
+    let result = record_reader(fd, &live.file_header, from, |_, header| {
+        let len = header.next_offset() - header.offset;
+        let is_live = header.kind == RecordKind::Value
+            && !header.deleted
+            && !header.is_expired(now)
+            && !newer.contains(&header.hash)
+            && live
+                .get(&header.hash)
+                .is_some_and(|entry| entry.offset == header.offset);
+        stats.records += 1;
+        if is_live {
+            stats.live += 1;
+            stats.live_bytes += len;
+            stats.largest_value = stats.largest_value.max(header.size as u64);
+        } else {
+            stats.dead_bytes += len;
+        }
+        if header.deleted || header.kind == RecordKind::Tombstone {
+            stats.deleted += 1;
+        }
+        Ok(None)
+    });
+    match result {
+        Err(Error::Sys(Errno::EKEYEXPIRED)) | Ok(_) => Ok((stats, live)),
+        Err(e) => Err(e),
+    }
+}
+
+/// Create (and lock) the temp file for a compaction (or a migration, or a repair) which is to replace `filepath`
+///
+/// The name is one no other compaction can be using (even one started in the same second),
+/// and it stays locked for as long as it is being written, so that `remove_orphans()` leaves it alone.
+/// It is open for reading too, for whatever wants to check what it wrote before the rename.
+pub(crate) fn create_compact_tmp(filepath: &str) -> Result<(Flock<OwnedFd>, String), Errno> {
     let tmp_filepath = format!(
-        "{parent}/compact-{}.coat-check",
-        Utc::now().timestamp().to_string()
+        "{filepath}{COMPACT_TMP_INFIX}{}",
+        random_seed()?[0..8]
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<String>()
     );
-
     let tmp_fd: OwnedFd = open(
         tmp_filepath.as_str(),
-        OFlag::O_WRONLY | OFlag::O_CREAT,
-        Mode::S_IRUSR
-            | Mode::S_IWUSR
-            | Mode::S_IRGRP
-            | Mode::S_IWGRP
-            | Mode::S_IROTH
-            | Mode::S_IWOTH,
+        OFlag::O_RDWR | OFlag::O_CREAT | OFlag::O_EXCL,
+        file_mode(),
     )?;
-    let tmp_lock = match Flock::lock(tmp_fd, FlockArg::LockExclusive) {
-        Ok(locked) => locked,
-        Err((_, e)) => return Err(e),
+    match Flock::lock(tmp_fd, FlockArg::LockExclusive) {
+        Ok(locked) => Ok((locked, tmp_filepath)),
+        Err((_, e)) => {
+            _ = unlink(tmp_filepath.as_str());
+            Err(e)
+        }
+    }
+}
+
+// append the live (and unexpired) records of `live` from `from` on, as found in the data file `fd`,
+// to `tmp_fd`, in the order they were written, indexing them in `keydir` at their new offsets;
+// returns where each one was copied from, and to, as `(hash, old offset, new offset)`
+fn copy_records(
+    fd: &BorrowedFd,
+    tmp_fd: &BorrowedFd,
+    live: &Keydir,
+    from: u64,
+    keydir: &mut Keydir,
+) -> Result<Vec<(String, u64, u64)>, Error> {
+    let mut records: Vec<(&String, &KeydirEntry)> = live
+        .iter()
+        .filter(|(_, entry)| entry.offset >= from)
+        .collect();
+    records.sort_by_key(|(_, entry)| entry.offset);
+
+    let mut copied = Vec::with_capacity(records.len());
+    for (hash, entry) in records {
+        // better to keep the damaged file around than to quietly copy the damage
+        let Some((header, body)) = read_record(fd, &live.file_header, entry.offset)? else {
+            continue;
+        };
+        let (key, val) = header.split(&body);
+        let buffer = encode_record(&live.file_header, hash, key, val, header.expires_at);
+        let nbytes = write_all(tmp_fd, &buffer)?;
+        copied.push((hash.clone(), entry.offset, keydir.indexed_len));
+        keydir.insert(
+            hash.clone(),
+            KeydirEntry {
+                offset: keydir.indexed_len,
+                size: header.size,
+                len: nbytes as u64,
+            },
+        );
+        keydir.indexed_len += nbytes as u64;
+    }
+    Ok(copied)
+}
+
+// bring the temp file up to date with the writes to the data file `fd` since `live` was indexed:
+// the `copied` records which have been replaced or deleted since get flagged deleted in the temp
+// file as well, and any records appended since get appended to the temp file too
+fn replay_records(
+    filepath: &str,
+    fd: &BorrowedFd,
+    tmp_fd: &BorrowedFd,
+    mut live: Keydir,
+    copied: Vec<(String, u64, u64)>,
+    keydir: &mut Keydir,
+) -> Result<(), Error> {
+    let from = live.indexed_len;
+    index_records(filepath, fd, &mut live)?;
+
+    let file_header = live.file_header;
+    for (hash, offset, new_offset) in copied {
+        let replaced = live.get(&hash).is_none_or(|entry| entry.offset != offset);
+        if replaced || is_deleted(fd, &file_header, offset)? {
+            mark_deleted(tmp_fd, &file_header, new_offset)?;
+            keydir.remove(&hash);
+        }
+    }
+    copy_records(fd, tmp_fd, &live, from, keydir)?;
+    Ok(())
+}
+
+// whether the record at `offset` has had its deleted flag flipped
+fn is_deleted(fd: &BorrowedFd, file_header: &FileHeader, offset: u64) -> Result<bool, Error> {
+    let flag: &mut [u8] = &mut [0; 1];
+    let nbytes = pread(fd, flag, file_header.deleted_offset(offset) as i64)?;
+    match nbytes == flag.len() {
+        true => Ok(flag[0] != 0),
+        false => Err(Error::Corrupt(offset)),
+    }
+}
+
+/// Remove the temp files left behind by compactions which never finished, returning how many
+///
+/// A compaction holds an exclusive lock on its temp file until the rename, so any temp
+/// file which can be locked now is an orphan.
+pub(crate) fn remove_orphans(filepath: &str) -> Result<usize, Errno> {
+    let prefix = match PathBuf::from(filepath).file_name() {
+        Some(name) => format!("{}{COMPACT_TMP_INFIX}", name.to_string_lossy()),
+        None => return Ok(0),
     };
+    remove_orphans_in(&parent_folder(filepath), |name| name.starts_with(&prefix))
+}
 
-    let size_buf: &mut [u8] = &mut vec![0; SPACER];
-    let mut sizer: [u8; SPACER] = [0; SPACER];
-    let sizer_size = sizer.len();
+/// As `remove_orphans()`, for all the compaction temp files in `folder` (e.g. a segmented store's)
+pub(crate) fn remove_orphans_in<F>(folder: &str, is_tmp: F) -> Result<usize, Errno>
+where
+    F: Fn(&str) -> bool,
+{
+    let mut dir = Dir::open(folder, OFlag::O_RDONLY | OFlag::O_DIRECTORY, Mode::empty())?;
+    let names: Vec<String> = dir
+        .iter()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .filter(|name| name.contains(COMPACT_TMP_INFIX) && is_tmp(name))
+        .collect();
 
-    let hash = hasher::hash_key("key");
-    let hash_size = hash.len();
-    let key_buf: &mut [u8] = &mut vec![0; hash_size];
-    let del_buf: &mut [u8] = &mut vec![0; 1];
-    let deleted_size = del_buf.len();
-
-    // iterate through the records in file and write the non-deleted ones to the tmp one
-    let mut nbytes = read(read_lock.as_fd(), key_buf)?;
-    while nbytes > 0 {
-        _ = read(read_lock.as_fd(), size_buf)?;
-        sizer.clone_from_slice(size_buf);
-        _ = read(read_lock.as_fd(), del_buf)?;
-        let val_buf: &mut [u8] = &mut vec![0; usize::from_ne_bytes(sizer)];
-        _ = read(read_lock.as_fd(), val_buf)?;
-        if del_buf == [0] {
-            // not deleted, so collect it in a new buffer, to write it to the tmp file
-            let buffer: &mut [u8] =
-                &mut vec![0; hash_size + sizer_size + deleted_size + val_buf.len()];
-            buffer[0..hash_size].copy_from_slice(&key_buf);
-            buffer[hash_size..hash_size + sizer_size].copy_from_slice(&sizer);
-            buffer[hash_size + sizer_size..hash_size + sizer_size + deleted_size]
-                .copy_from_slice(&del_buf);
-            buffer[hash_size + sizer_size + deleted_size..].copy_from_slice(&val_buf);
-            _ = write(tmp_lock.as_fd(), buffer)?;
+    let mut removed = 0;
+    for name in names {
+        let tmp_filepath = format!("{folder}/{name}");
+        let fd: OwnedFd = match open(tmp_filepath.as_str(), OFlag::O_RDONLY, Mode::empty()) {
+            Ok(fd) => fd,
+            Err(Errno::ENOENT) => continue, // someone else got to it first
+            Err(e) => return Err(e),
+        };
+        match Flock::lock(fd, FlockArg::LockExclusiveNonblock) {
+            Ok(lock) => {
+                warn!(
+                    "{tmp_filepath}: removing temp file left by an interrupted compaction (or migration)"
+                );
+                unlink(tmp_filepath.as_str())?;
+                release(lock)?;
+                removed += 1;
+            }
+            Err((fd, Errno::EWOULDBLOCK)) => close(fd)?, // a compaction still in progress
+            Err((fd, e)) => {
+                close(fd)?;
+                return Err(e);
+            }
         }
-        nbytes = read(read_lock.as_fd(), key_buf)?;
     }
+    Ok(removed)
+}
 
-    // atomically replace the original file with the tmp one
-    renameat(
-        tmp_lock.as_fd(),
-        tmp_filepath.as_str(),
-        read_lock.as_fd(),
-        filepath.as_str(),
-    )?;
+// copy the live records of the legacy file in `fd` to `tmp_fd`, in the current layout, returning each
+// one as it was read from `fd`: its hash, the size of its value, and a crc32 of the value
+fn copy_legacy_records(
+    fd: &BorrowedFd,
+    tmp_fd: &BorrowedFd,
+    file_header: &FileHeader,
+) -> Result<Vec<(String, usize, u32)>, Error> {
+    let file_len = fstat(fd)?.st_size as u64;
+    let head_size = legacy_header_size();
+    let head_buf: &mut [u8] = &mut vec![0; head_size];
+
+    // no checksums to tell a crash from damage, so anything which does not add up is reported as corrupt
+    let mut offset: u64 = 0;
+    let mut copied: Vec<(String, usize, u32)> = Vec::new();
+    while offset < file_len {
+        let nbytes = pread(fd, head_buf, offset as i64)?;
+        let header = match RecordHeader::decode_legacy(offset, head_buf) {
+            Some(header) if nbytes == head_size => header,
+            _ => return Err(Error::Corrupt(offset)),
+        };
+        let value_start = offset + head_size as u64;
+        let next_offset = match value_start.checked_add(header.size as u64) {
+            Some(end) if end <= file_len => end,
+            _ => return Err(Error::Corrupt(offset)),
+        };
+        if !header.deleted {
+            let val_buf: &mut [u8] = &mut vec![0; header.size];
+            _ = pread(fd, val_buf, value_start as i64)?;
+            write_all(
+                tmp_fd,
+                &encode_record(file_header, &header.hash, &[], val_buf, None),
+            )?;
+            copied.push((header.hash, header.size, crc32fast::hash(val_buf)));
+        }
+        offset = next_offset;
+    }
+    Ok(copied)
+}
 
-    // reset the signal log
-    COMPACT_SIGNALED.store(false, Ordering::Relaxed);
+/// Rewrite a data file in the legacy (pre-version-1) layout as `dst`, in the current one, with only its
+/// non-deleted records, returning how many were carried over
+///
+/// The new file gets read back, and has to hold exactly the (intact) records read out of `src`, in the
+/// same order, with the same values, before it is renamed into place; `src` and `dst` may be the same file.
+pub(crate) fn migrate_file(src: &str, dst: &str) -> Result<usize, Error> {
+    let read_lock = open_locked(src, OFlag::O_RDONLY, FlockArg::LockExclusive)?;
 
-    // release the lock and close the fd
-    match read_lock.unlock() {
-        Ok(unlocked) => {
-            close(unlocked)?;
-            Ok(None)
+    // a file with a header has nothing to migrate (and would only look corrupt to the legacy reader)
+    let magic: &mut [u8] = &mut [0; 8];
+    let nbytes = match pread(read_lock.as_fd(), magic, 0) {
+        Ok(nbytes) => nbytes,
+        Err(e) => {
+            release(read_lock)?;
+            return Err(e.into());
         }
-        Err((_, e)) => Err(e),
+    };
+    if FileHeader::has_magic(&magic[0..nbytes]) {
+        release(read_lock)?;
+        return Err(Error::AlreadyMigrated);
     }
+
+    // (named like a compaction's, so that one interrupted by a crash gets cleared up the same way)
+    let (tmp_lock, tmp_filepath) = match create_compact_tmp(dst) {
+        Ok(created) => created,
+        Err(e) => {
+            release(read_lock)?;
+            return Err(e.into());
+        }
+    };
+
+    // the legacy layout only ever had the hashes, so there are no keys to carry over
+    let file_header = FileHeader {
+        flags: FileHeader::current().flags & !FLAG_KEYS,
+        ..FileHeader::current()
+    };
+    let copied = match write_all(&tmp_lock.as_fd(), &file_header.encode()) {
+        Ok(_) => copy_legacy_records(&read_lock.as_fd(), &tmp_lock.as_fd(), &file_header),
+        Err(e) => Err(e.into()),
+    };
+
+    // check what actually landed in the new file, checksums and all, against what was read out of
+    // the old one, counting the records which match, up to the first which does not
+    let mut found: usize = 0;
+    let result = match copied {
+        Ok(expected) => {
+            let from = FILE_HEADER_SIZE as u64;
+            let counted = record_reader(&tmp_lock.as_fd(), &file_header, from, |fd, header| {
+                let body_buf: &mut [u8] = &mut vec![0; header.body_size()];
+                _ = read(fd, &mut *body_buf)?;
+                header.verify(body_buf)?;
+                let (_, value) = header.split(body_buf);
+                match expected.get(found) {
+                    Some((hash, size, crc))
+                        if *hash == header.hash
+                            && *size == header.size
+                            && *crc == crc32fast::hash(value) =>
+                    {
+                        found += 1;
+                        Ok(None)
+                    }
+                    _ => Ok(Some(vec![])), // and stop there
+                }
+            });
+            match counted {
+                Err(Error::Sys(Errno::EKEYEXPIRED)) if found == expected.len() => Ok(found),
+                Err(Error::Sys(Errno::EKEYEXPIRED)) | Ok(_) => {
+                    Err(Error::Mismatch(expected.len(), found))
+                }
+                Err(e) => Err(e),
+            }
+        }
+        Err(e) => Err(e),
+    };
+    // on the disk before it takes the destination's place
+    let synced = result.and_then(|migrated| {
+        fsync(tmp_lock.as_fd())?;
+        Ok(migrated)
+    });
+    let migrated = match synced {
+        Ok(migrated) => migrated,
+        Err(e) => {
+            _ = unlink(tmp_filepath.as_str());
+            release(tmp_lock)?;
+            release(read_lock)?;
+            return Err(e);
+        }
+    };
+
+    // atomically replace (or create) the destination file with the tmp one, and make the rename durable
+    renameat(AT_FDCWD, tmp_filepath.as_str(), AT_FDCWD, dst)?;
+    sync_dir(dst)?;
+
+    release(tmp_lock)?;
+    release(read_lock)?;
+    Ok(migrated)
+}
+
+/* Public API
+ *
+ * One-shot versions of the `Store` operations, for callers (like the cli) which
+ * only do a single thing with the data file before exiting
+ *
+ */
+
+// a store for a one-off read, which shares its lock with any other readers
+fn open_read_only(filepath: String) -> Result<Store, Error> {
+    let options = StoreOptions {
+        read_only: true,
+        ..StoreOptions::default()
+    };
+    Store::open_with(filepath, options)
+}
+
+pub fn read_key(filepath: String, key: &str) -> Result<Option<Vec<u8>>, Error> {
+    open_read_only(filepath)?.get(key)
+}
+
+pub fn delete_key(filepath: String, key: &str) -> Result<Option<Vec<u8>>, Error> {
+    Store::open(filepath)?.delete(key)
+}
+
+pub fn write_key_val(filepath: String, key: &str, val: &[u8]) -> Result<usize, Error> {
+    Store::open(filepath)?.set(key, val)
+}
+
+/// Compare-and-swap: set `key` to `new` only if its current value is `expected`
+pub fn cas_key_val(
+    filepath: String,
+    key: &str,
+    expected: &[u8],
+    new: &[u8],
+) -> Result<bool, Error> {
+    Store::open(filepath)?.cas(key, expected, new)
+}
+
+pub fn stats(filepath: String) -> Result<Stats, Error> {
+    open_read_only(filepath)?.stats()
+}
+
+/// Check every record of the data file at `filepath` (under a shared lock), as `coat-check verify` does
+pub fn verify(filepath: String) -> Result<Report, Error> {
+    let lock = open_locked(filepath.as_str(), OFlag::O_RDONLY, FlockArg::LockShared)?;
+    let report = verify_file(&lock.as_fd());
+    release(lock)?;
+    report
+}
+
+/// Salvage what is still intact of the (damaged) data file at `src` into a new one at `dst`
+///
+/// Into a new file, readers and writers of `src` can carry on in the meantime; but repaired in
+/// place, the data file gets swapped out from under anyone appending to it, so (as for a
+/// compaction) they are kept waiting on the exclusive lock until it has been.
+pub fn repair(src: String, dst: String) -> Result<Salvage, Error> {
+    let in_place = src == dst
+        || match (stat(src.as_str()), stat(dst.as_str())) {
+            (Ok(from), Ok(to)) => from.st_dev == to.st_dev && from.st_ino == to.st_ino,
+            _ => false,
+        };
+    let arg = match in_place {
+        true => FlockArg::LockExclusive,
+        false => FlockArg::LockShared,
+    };
+    let lock = open_locked(src.as_str(), OFlag::O_RDONLY, arg)?;
+    let salvage = repair_file(&lock.as_fd(), dst.as_str());
+    release(lock)?;
+    salvage
+}
+
+/// List the records of the data file at `filepath` (under a shared lock) which pass `filter`, as
+/// far as any damage lets it read
+pub fn inspect(filepath: String, filter: Filter) -> Result<Listing, Error> {
+    let lock = open_locked(filepath.as_str(), OFlag::O_RDONLY, FlockArg::LockShared)?;
+    let listing = inspect_file(&lock.as_fd(), filter);
+    release(lock)?;
+    listing
+}
+
+/// Add `bytes` to the end of the value stored at `key` (if any), returning the value's new length
+pub fn append_key_val(filepath: String, key: &str, bytes: &[u8]) -> Result<usize, Error> {
+    Store::open(filepath)?.append(key, bytes)
+}
+
+/// Add `delta` to the integer stored at `key` (0 if there is none), returning the new count
+pub fn incr_key(filepath: String, key: &str, delta: i64) -> Result<i64, Error> {
+    Store::open(filepath)?.incr(key, delta)
+}
+
+/// Subtract `delta` from the integer stored at `key` (0 if there is none), returning the new count
+pub fn decr_key(filepath: String, key: &str, delta: i64) -> Result<i64, Error> {
+    Store::open(filepath)?.decr(key, delta)
+}
+
+pub fn compact(filepath: String) -> Result<Option<Vec<u8>>, Error> {
+    // (compaction indexes the file itself, to find the live records, so there is nothing to do first)
+    compact_file(filepath.as_str())?;
+    Ok(None)
+}
+
+pub fn migrate(src: String, dst: String) -> Result<usize, Error> {
+    migrate_file(src.as_str(), dst.as_str())
 }
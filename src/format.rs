@@ -0,0 +1,470 @@
+use crate::error::Error;
+use crate::hasher::{self, HASHER_MD5, KeyHasher, Md5Hasher};
+use crc32fast::Hasher;
+
+/* On-disk layout
+ *
+ * Every data file starts with a fixed-size header:
+ *
+ *   `[magic][format version][hash algorithm][flags][hash seed][reserved]`
+ *
+ * followed by the records, each of them:
+ *
+ *   `[(hashed) key][size of value][deleted?][checksum][size of key][expires at][key][value]`
+ *
+ * where the original key (and its size) are only there in files with the `FLAG_KEYS` flag set,
+ * the expiry time (in seconds since the epoch, or 0 for never) only with the `FLAG_EXPIRY` flag set,
+ * and the length of the hashed key depends on the hash algorithm.
+ *
+ * In files with the `FLAG_BATCHES` flag set, the deleted flag is a status byte instead, which
+ * can also mark a record as a tombstone (the key was deleted as part of a batch), or as the
+ * begin or commit marker around a batch: the records in between only count once the commit
+ * marker made it into the file too.
+ *
+ * In a segmented store, the segment written by merging the older ones has the `FLAG_MERGED`
+ * flag set: any segment numbered below it is left over from a merge which a crash interrupted.
+ *
+ * All the multi-byte numbers (format version, sizes, checksum) are little-endian,
+ * regardless of the architecture which wrote them, so data files are portable.
+ *
+ */
+
+const FILE_MAGIC: &[u8; 8] = b"COATCHK\0";
+pub const FILE_HEADER_SIZE: usize = 32;
+pub const FORMAT_VERSION: u16 = 1;
+
+/// Header flags, for the optional parts of each record
+pub const FLAG_KEYS: u8 = 0x01; // the original key is stored alongside its hash
+pub const FLAG_EXPIRY: u8 = 0x02; // each record has an (optional) expiry time
+pub const FLAG_BATCHES: u8 = 0x04; // records can be tombstones, or batch markers
+pub const FLAG_MERGED: u8 = 0x08; // a segment which supersedes all the lower-numbered ones
+const KNOWN_FLAGS: u8 = FLAG_KEYS | FLAG_EXPIRY | FLAG_BATCHES | FLAG_MERGED;
+
+/// Values of the deleted flag (status byte); all but the first two only with `FLAG_BATCHES` set
+const STATUS_LIVE: u8 = 0;
+const STATUS_DELETED: u8 = 1;
+const STATUS_TOMBSTONE: u8 = 2;
+const STATUS_BATCH_BEGIN: u8 = 3;
+const STATUS_BATCH_COMMIT: u8 = 4;
+
+const SPACER: usize = std::mem::size_of::<u64>();
+const CHECKSUM_SIZE: usize = std::mem::size_of::<u32>();
+const KEY_SPACER: usize = std::mem::size_of::<u32>();
+const EXPIRY_SIZE: usize = std::mem::size_of::<i64>();
+
+/// The header at the start of every data file
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FileHeader {
+    pub version: u16,
+    pub hasher: u8,
+    pub flags: u8,
+    pub seed: [u8; 16], // only used by the keyed hash algorithms
+}
+
+impl FileHeader {
+    /// The header for a brand-new data file, with keys hashed by MD5
+    pub fn current() -> FileHeader {
+        FileHeader {
+            version: FORMAT_VERSION,
+            hasher: HASHER_MD5,
+            flags: FLAG_KEYS | FLAG_EXPIRY | FLAG_BATCHES,
+            seed: [0; 16],
+        }
+    }
+
+    /// The header for a brand-new data file, with keys hashed by the algorithm `hasher`
+    pub fn with_hasher(hasher: u8, seed: [u8; 16]) -> FileHeader {
+        FileHeader {
+            hasher,
+            seed,
+            ..FileHeader::current()
+        }
+    }
+
+    pub fn has_keys(&self) -> bool {
+        self.flags & FLAG_KEYS != 0
+    }
+
+    pub fn has_expiry(&self) -> bool {
+        self.flags & FLAG_EXPIRY != 0
+    }
+
+    pub fn has_batches(&self) -> bool {
+        self.flags & FLAG_BATCHES != 0
+    }
+
+    pub fn is_merged(&self) -> bool {
+        self.flags & FLAG_MERGED != 0
+    }
+
+    /// Highest value the deleted flag (status byte) can have in this file
+    pub fn max_status(&self) -> u8 {
+        match self.has_batches() {
+            true => STATUS_BATCH_COMMIT,
+            false => STATUS_DELETED,
+        }
+    }
+
+    /// The hash algorithm every key in this file is indexed by
+    pub fn key_hasher(&self) -> Box<dyn KeyHasher> {
+        match hasher::key_hasher(self.hasher, self.seed) {
+            Ok(key_hasher) => key_hasher,
+            Err(_) => Box::new(Md5Hasher), // decode() has already refused any unknown id
+        }
+    }
+
+    /// Length of the hashes in this file (needed for every record read, so no hasher gets built)
+    pub fn hash_size(&self) -> usize {
+        hasher::hash_size(self.hasher).unwrap_or(32) // (md5's, as for `key_hasher()`)
+    }
+
+    // position of the deleted flag, for the record starting at `offset`
+    pub fn deleted_offset(&self, offset: u64) -> u64 {
+        offset + (self.hash_size() + SPACER) as u64
+    }
+
+    // size of the part of a record header which is in every file: up to, and including, the checksum
+    fn fixed_header_size(&self) -> usize {
+        self.deleted_offset(0) as usize + 1 + CHECKSUM_SIZE
+    }
+
+    /// Size of everything in a record before the key (if any) and value
+    pub fn record_header_size(&self) -> usize {
+        let mut head_size = self.fixed_header_size();
+        if self.has_keys() {
+            head_size += KEY_SPACER;
+        }
+        if self.has_expiry() {
+            head_size += EXPIRY_SIZE;
+        }
+        head_size
+    }
+
+    pub fn encode(&self) -> [u8; FILE_HEADER_SIZE] {
+        // produce the header (`[magic][format version][hash algorithm][flags][hash seed][reserved]` byte array),
+        // the reserved bytes all zero
+        let mut buffer = [0; FILE_HEADER_SIZE];
+        buffer[0..8].copy_from_slice(FILE_MAGIC);
+        buffer[8..10].copy_from_slice(&self.version.to_le_bytes());
+        buffer[10] = self.hasher;
+        buffer[11] = self.flags;
+        buffer[12..28].copy_from_slice(&self.seed);
+        buffer
+    }
+
+    /// Read the header out of the first bytes of a data file, refusing anything this version does not understand
+    pub fn decode(buf: &[u8]) -> Result<FileHeader, Error> {
+        if buf.len() < FILE_HEADER_SIZE || &buf[0..8] != FILE_MAGIC {
+            return Err(Error::NotDataFile);
+        }
+        let mut seed: [u8; 16] = [0; 16];
+        seed.clone_from_slice(&buf[12..28]);
+        let header = FileHeader {
+            version: u16::from_le_bytes([buf[8], buf[9]]),
+            hasher: buf[10],
+            flags: buf[11],
+            seed,
+        };
+        if header.version != FORMAT_VERSION {
+            return Err(Error::UnsupportedVersion(header.version));
+        }
+        hasher::hash_size(header.hasher)?;
+        if header.flags & !KNOWN_FLAGS != 0 {
+            return Err(Error::UnsupportedFlags(header.flags));
+        }
+        Ok(header)
+    }
+
+    /// Whether `buf` starts the way every data file does, whatever its format version
+    pub fn has_magic(buf: &[u8]) -> bool {
+        buf.starts_with(FILE_MAGIC)
+    }
+
+    /// Whether `buf` (a file too short to hold a whole header) could be the start of one, i.e.
+    /// the file was being created when a crash cut its first append short
+    pub fn is_torn(buf: &[u8]) -> bool {
+        buf.len() < FILE_HEADER_SIZE && buf.iter().zip(FILE_MAGIC).all(|(a, b)| a == b)
+    }
+}
+
+impl Default for FileHeader {
+    fn default() -> Self {
+        FileHeader::current()
+    }
+}
+
+// CRC32 of everything in a record except the deleted flag, which gets flipped in place
+// (though tombstones and batch markers, which never get flipped, have theirs covered too)
+fn checksum(parts: &[&[u8]]) -> u32 {
+    let mut hasher = Hasher::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize()
+}
+
+/// Whether the first `file_header.record_header_size()` bytes of `buf` could be the header of a record:
+/// a hex hash, followed by a deleted flag that is either 0 or 1, or another status the file can have
+/// (the sizes and checksum could be anything)
+pub fn looks_like_header(buf: &[u8], file_header: &FileHeader) -> bool {
+    let hash_size = file_header.hash_size();
+    buf[0..hash_size]
+        .iter()
+        .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(b))
+        && buf[hash_size + SPACER] <= file_header.max_status()
+}
+
+/// What a record is for, going by its status byte
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RecordKind {
+    Value,       // a key and its value, which is live unless the deleted flag is set
+    Tombstone,   // the key was deleted (as part of a batch)
+    BatchBegin,  // the records up to the next `BatchCommit` are one batch
+    BatchCommit, // the batch since the last `BatchBegin` is complete
+}
+
+impl RecordKind {
+    fn status(&self) -> u8 {
+        match self {
+            RecordKind::Value => STATUS_LIVE,
+            RecordKind::Tombstone => STATUS_TOMBSTONE,
+            RecordKind::BatchBegin => STATUS_BATCH_BEGIN,
+            RecordKind::BatchCommit => STATUS_BATCH_COMMIT,
+        }
+    }
+
+    // the part of the status byte the checksum covers, i.e. none of it for values
+    fn checksummed(&self) -> Vec<u8> {
+        match self {
+            RecordKind::Value => Vec::new(),
+            _ => vec![self.status()],
+        }
+    }
+}
+
+/// The fixed-size part of a `[(hashed) key][size of value][deleted?][checksum][size of key][expires at]` record
+pub struct RecordHeader {
+    pub offset: u64,
+    pub hash: String,
+    pub size: usize,
+    pub deleted: bool,
+    pub kind: RecordKind,
+    pub checksum: u32,
+    pub key_size: Option<usize>, // only in files which store the original keys
+    pub expires_at: Option<i64>,
+    extras: Vec<u8>, // the optional fields, as stored (and checksummed)
+}
+
+impl RecordHeader {
+    /// Read the header of the record starting at `offset` out of the first
+    /// `file_header.record_header_size()` bytes of `buf`
+    pub fn decode(offset: u64, buf: &[u8], file_header: &FileHeader) -> RecordHeader {
+        let hash_size = file_header.hash_size();
+        let fixed_size = file_header.fixed_header_size();
+        let mut sizer: [u8; SPACER] = [0; SPACER];
+        let mut summer: [u8; CHECKSUM_SIZE] = [0; CHECKSUM_SIZE];
+        sizer.clone_from_slice(&buf[hash_size..hash_size + SPACER]);
+        summer.clone_from_slice(&buf[hash_size + SPACER + 1..fixed_size]);
+
+        // then whichever optional fields this file has, in order
+        let mut pos = fixed_size;
+        let key_size = match file_header.has_keys() {
+            true => {
+                let mut key_sizer: [u8; KEY_SPACER] = [0; KEY_SPACER];
+                key_sizer.clone_from_slice(&buf[pos..pos + KEY_SPACER]);
+                pos += KEY_SPACER;
+                Some(u32::from_le_bytes(key_sizer) as usize)
+            }
+            false => None,
+        };
+        let expires_at = match file_header.has_expiry() {
+            true => {
+                let mut expirer: [u8; EXPIRY_SIZE] = [0; EXPIRY_SIZE];
+                expirer.clone_from_slice(&buf[pos..pos + EXPIRY_SIZE]);
+                pos += EXPIRY_SIZE;
+                match i64::from_le_bytes(expirer) {
+                    0 => None,
+                    t => Some(t),
+                }
+            }
+            false => None,
+        };
+
+        // any status the file cannot have gets read as a deleted value, like any other non-zero flag
+        let (kind, deleted) = match buf[hash_size + SPACER] {
+            STATUS_LIVE => (RecordKind::Value, false),
+            STATUS_TOMBSTONE if file_header.has_batches() => (RecordKind::Tombstone, false),
+            STATUS_BATCH_BEGIN if file_header.has_batches() => (RecordKind::BatchBegin, false),
+            STATUS_BATCH_COMMIT if file_header.has_batches() => (RecordKind::BatchCommit, false),
+            _ => (RecordKind::Value, true),
+        };
+
+        RecordHeader {
+            offset,
+            hash: String::from_utf8_lossy(&buf[0..hash_size]).into_owned(),
+            size: u64::from_le_bytes(sizer) as usize,
+            deleted,
+            kind,
+            checksum: u32::from_le_bytes(summer),
+            key_size,
+            expires_at,
+            extras: buf[fixed_size..pos].to_vec(),
+        }
+    }
+
+    /// Position of the key (or of the value, when there is no key), i.e. just past this header
+    pub fn body_offset(&self) -> u64 {
+        let fixed_size = self.hash.len() + SPACER + 1 + CHECKSUM_SIZE;
+        self.offset + (fixed_size + self.extras.len()) as u64
+    }
+
+    /// Size of everything after this header: the key (if any) followed by the value
+    pub fn body_size(&self) -> usize {
+        self.key_size.unwrap_or(0).saturating_add(self.size)
+    }
+
+    /// Split the `body` of the record into its key (empty, when there is none) and value
+    pub fn split<'a>(&self, body: &'a [u8]) -> (&'a [u8], &'a [u8]) {
+        body.split_at(self.key_size.unwrap_or(0))
+    }
+
+    /// Whether the record has an expiry time, and it is no later than `now`
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expires_at.is_some_and(|t| t <= now)
+    }
+
+    /// Whether the record would end past `file_len`, which no intact record can
+    pub fn overruns(&self, file_len: u64) -> bool {
+        self.body_offset()
+            .checked_add(self.body_size() as u64)
+            .is_none_or(|end| end > file_len)
+    }
+
+    pub fn next_offset(&self) -> u64 {
+        self.body_offset() + self.body_size() as u64
+    }
+
+    /// Compare the checksum stored in the record with one computed from its `body`
+    pub fn verify(&self, body: &[u8]) -> Result<(), Error> {
+        let sizer = (self.size as u64).to_le_bytes();
+        let status = self.kind.checksummed();
+        if checksum(&[&status, self.hash.as_bytes(), &sizer, &self.extras, body]) == self.checksum {
+            Ok(())
+        } else {
+            Err(Error::Corrupt(self.offset))
+        }
+    }
+}
+
+/* Legacy layout
+ *
+ * Data files written before format version 1 have no file header, and their records
+ * have no checksum, with the size in the byte order (and width) of the machine which wrote it:
+ *
+ *   `[(hashed) key][size of value][deleted?][value]`
+ *
+ */
+
+const LEGACY_SPACER: usize = std::mem::size_of::<usize>();
+
+// size of everything in a legacy record before the value
+pub fn legacy_header_size() -> usize {
+    Md5Hasher.hash_size() + LEGACY_SPACER + 1
+}
+
+impl RecordHeader {
+    /// Read the header of the legacy record starting at `offset` out of the first `legacy_header_size()`
+    /// bytes of `buf`, or `None` if those could not be one
+    ///
+    /// There is no checksum to `verify()`, nor key, and `next_offset()` does not apply either.
+    pub fn decode_legacy(offset: u64, buf: &[u8]) -> Option<RecordHeader> {
+        let hash_size = Md5Hasher.hash_size();
+        let hash = &buf[0..hash_size];
+        let deleted = buf[hash_size + LEGACY_SPACER];
+        if !hash
+            .iter()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(b))
+            || deleted > 1
+        {
+            return None;
+        }
+        let mut sizer: [u8; LEGACY_SPACER] = [0; LEGACY_SPACER];
+        sizer.clone_from_slice(&buf[hash_size..hash_size + LEGACY_SPACER]);
+        Some(RecordHeader {
+            offset,
+            hash: String::from_utf8_lossy(hash).into_owned(),
+            size: usize::from_ne_bytes(sizer),
+            deleted: deleted != 0,
+            kind: RecordKind::Value,
+            checksum: 0,
+            key_size: None,
+            expires_at: None,
+            extras: Vec::new(),
+        })
+    }
+}
+
+pub fn encode_record(
+    file_header: &FileHeader,
+    hash: &str,
+    key: &[u8],
+    val: &[u8],
+    expires_at: Option<i64>,
+) -> Vec<u8> {
+    encode_kind(file_header, RecordKind::Value, hash, key, val, expires_at)
+}
+
+/// A tombstone for `key`, i.e. a record with no value, which marks it as deleted
+pub fn encode_tombstone(file_header: &FileHeader, hash: &str, key: &[u8]) -> Vec<u8> {
+    encode_kind(file_header, RecordKind::Tombstone, hash, key, &[], None)
+}
+
+/// The marker at the start (`RecordKind::BatchBegin`) or end (`RecordKind::BatchCommit`) of a batch,
+/// which has an all-zero hash, and neither key nor value
+pub fn encode_marker(file_header: &FileHeader, kind: RecordKind) -> Vec<u8> {
+    let hash = "0".repeat(file_header.hash_size());
+    encode_kind(file_header, kind, &hash, &[], &[], None)
+}
+
+fn encode_kind(
+    file_header: &FileHeader,
+    kind: RecordKind,
+    hash: &str,
+    key: &[u8],
+    val: &[u8],
+    expires_at: Option<i64>,
+) -> Vec<u8> {
+    // produce a new record (`[(hashed) key][size of value][deleted?][checksum][size of key][expires at][key][value]`
+    // byte array), given the key and value data, leaving out whichever optional fields the file does not have
+    let mut extras: Vec<u8> = Vec::new();
+    let key = match file_header.has_keys() {
+        true => {
+            extras.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            key
+        }
+        false => &[],
+    };
+    if file_header.has_expiry() {
+        extras.extend_from_slice(&expires_at.unwrap_or(0).to_le_bytes());
+    }
+    let sizer: [u8; SPACER] = (val.len() as u64).to_le_bytes();
+    let deleted: [u8; 1] = [kind.status()];
+    let summer: [u8; CHECKSUM_SIZE] = checksum(&[
+        &kind.checksummed(),
+        hash.as_bytes(),
+        &sizer,
+        &extras,
+        key,
+        val,
+    ])
+    .to_le_bytes();
+    let mut buffer = Vec::with_capacity(file_header.record_header_size() + key.len() + val.len());
+    buffer.extend_from_slice(hash.as_bytes());
+    buffer.extend_from_slice(&sizer);
+    buffer.extend_from_slice(&deleted);
+    buffer.extend_from_slice(&summer);
+    buffer.extend_from_slice(&extras);
+    buffer.extend_from_slice(key);
+    buffer.extend_from_slice(val);
+    buffer
+}
@@ -1,8 +1,143 @@
+use crate::error::Error;
 use md5::{Digest, Md5};
+use sha2::Sha256;
+use siphasher::sip128::{Hasher128, SipHasher24};
+use std::hash::Hasher;
+use std::str::FromStr;
 
+/// Turns a key into the fixed-size (hex) hash which records are indexed by
+///
+/// Every key in a data file has to be hashed the same way, so the algorithm (and, for the
+/// keyed ones, its seed) is recorded in the file header when the file is created.
+pub trait KeyHasher {
+    /// Hash algorithm id, as recorded in the file header
+    fn id(&self) -> u8;
+    fn hash_key(&self, key: &str) -> String;
+    /// Length of every hash this produces
+    fn hash_size(&self) -> usize;
+}
+
+pub const HASHER_MD5: u8 = 1;
+pub const HASHER_SHA256: u8 = 2;
+pub const HASHER_SIPHASH: u8 = 3;
+
+/// MD5, as hex: the original (and default) algorithm
+pub struct Md5Hasher;
+
+impl KeyHasher for Md5Hasher {
+    fn id(&self) -> u8 {
+        HASHER_MD5
+    }
+
+    fn hash_key(&self, key: &str) -> String {
+        let mut hasher = Md5::new();
+        hasher.update(key.as_bytes());
+        let result = hasher.finalize();
+        format!("{:x}", result)
+    }
+
+    fn hash_size(&self) -> usize {
+        32
+    }
+}
+
+/// SHA-256, as hex
+pub struct Sha256Hasher;
+
+impl KeyHasher for Sha256Hasher {
+    fn id(&self) -> u8 {
+        HASHER_SHA256
+    }
+
+    fn hash_key(&self, key: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(key.as_bytes());
+        let result = hasher.finalize();
+        format!("{:x}", result)
+    }
+
+    fn hash_size(&self) -> usize {
+        64
+    }
+}
+
+/// SipHash-2-4 (128-bit), as hex: much faster than the cryptographic digests, and since it
+/// is keyed by a secret seed, nobody without the data file can pick keys which collide
+pub struct SipHasher {
+    pub seed: [u8; 16],
+}
+
+impl KeyHasher for SipHasher {
+    fn id(&self) -> u8 {
+        HASHER_SIPHASH
+    }
+
+    fn hash_key(&self, key: &str) -> String {
+        let mut hasher = SipHasher24::new_with_key(&self.seed);
+        hasher.write(key.as_bytes());
+        let result = hasher.finish128().as_bytes();
+        result.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    fn hash_size(&self) -> usize {
+        32
+    }
+}
+
+/// The algorithms to choose from when creating a data file
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum HasherKind {
+    #[default]
+    Md5,
+    Sha256,
+    SipHash,
+}
+
+impl HasherKind {
+    pub fn id(&self) -> u8 {
+        match self {
+            HasherKind::Md5 => HASHER_MD5,
+            HasherKind::Sha256 => HASHER_SHA256,
+            HasherKind::SipHash => HASHER_SIPHASH,
+        }
+    }
+}
+
+impl FromStr for HasherKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "md5" => Ok(HasherKind::Md5),
+            "sha256" => Ok(HasherKind::Sha256),
+            "siphash" => Ok(HasherKind::SipHash),
+            _ => Err(format!(
+                "unknown hash algorithm {s:?} (md5, sha256 or siphash)"
+            )),
+        }
+    }
+}
+
+/// The hasher for the algorithm `id` (plus `seed`, for the keyed ones) found in a file header
+pub fn key_hasher(id: u8, seed: [u8; 16]) -> Result<Box<dyn KeyHasher>, Error> {
+    match id {
+        HASHER_MD5 => Ok(Box::new(Md5Hasher)),
+        HASHER_SHA256 => Ok(Box::new(Sha256Hasher)),
+        HASHER_SIPHASH => Ok(Box::new(SipHasher { seed })),
+        _ => Err(Error::UnsupportedHasher(id)),
+    }
+}
+
+/// Length of the hashes the algorithm `id` produces, without building a hasher for it
+pub fn hash_size(id: u8) -> Result<usize, Error> {
+    match id {
+        HASHER_MD5 | HASHER_SIPHASH => Ok(32),
+        HASHER_SHA256 => Ok(64),
+        _ => Err(Error::UnsupportedHasher(id)),
+    }
+}
+
+/// MD5 hash of `key`, the way data files are hashed unless they say otherwise
 pub fn hash_key(key: &str) -> String {
-    let mut hasher = Md5::new();
-    hasher.update(key.as_bytes());
-    let result = hasher.finalize();
-    String::from(format!("{:x}", result))
+    Md5Hasher.hash_key(key)
 }
@@ -0,0 +1,165 @@
+use crate::error::Error;
+use crate::file_syscalls::{read_file_header, record_reader};
+use crate::format::{FILE_HEADER_SIZE, RecordKind};
+use nix::errno::Errno;
+use nix::unistd::read;
+use std::fmt;
+use std::os::fd::BorrowedFd;
+
+// how much of each value `inspect()` shows
+const PREVIEW_LEN: usize = 32;
+
+/// Which records `inspect()` lists
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Filter {
+    #[default]
+    All,
+    /// Only values with the deleted flag clear
+    Live,
+    /// Only values with the deleted flag set
+    Deleted,
+}
+
+/// One record of a data file, as `inspect()` lists it
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub offset: u64,
+    pub hash: String,
+    pub kind: RecordKind,
+    pub size: u64, // of the value
+    pub deleted: bool,
+    pub preview: String, // the start of the value (as text), with "..." when there is more
+}
+
+impl Entry {
+    /// The same fields, as a JSON object on a single line
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"offset\":{},\"hash\":{},\"kind\":\"{}\",\"size\":{},\"deleted\":{},\"preview\":{}}}",
+            self.offset,
+            json_string(&self.hash),
+            kind_name(self.kind),
+            self.size,
+            self.deleted,
+            json_string(&self.preview)
+        )
+    }
+}
+
+/// What `inspect()` could read of a data file: the records which pass its filter, and, should
+/// damage have stopped it short of the end, the offset it got to and what it found there
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Listing {
+    pub entries: Vec<Entry>,
+    pub stopped: Option<(u64, Error)>,
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:>10}  {}  {:<9}  {:>8}  {}  \"{}\"",
+            self.offset,
+            self.hash,
+            kind_name(self.kind),
+            self.size,
+            match self.deleted {
+                true => "deleted",
+                false => "live   ",
+            },
+            escape(&self.preview)
+        )
+    }
+}
+
+fn kind_name(kind: RecordKind) -> &'static str {
+    match kind {
+        RecordKind::Value => "value",
+        RecordKind::Tombstone => "tombstone",
+        RecordKind::BatchBegin => "begin",
+        RecordKind::BatchCommit => "commit",
+    }
+}
+
+// `s` as a JSON string, quotes and all
+fn json_string(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// `s`, with quotes, backslashes and control characters escaped, so that it fits on one line
+fn escape(s: &str) -> String {
+    let mut escaped = String::new();
+    for c in s.chars() {
+        match c {
+            '"' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c if c.is_control() => escaped.extend(c.escape_default()),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// the first `PREVIEW_LEN` bytes of `val`, as text (anything which is not UTF-8 shows up as U+FFFD)
+fn preview(val: &[u8]) -> String {
+    let shown = &val[..val.len().min(PREVIEW_LEN)];
+    let mut preview = String::from_utf8_lossy(shown).into_owned();
+    if val.len() > PREVIEW_LEN {
+        preview.push_str("...");
+    }
+    preview
+}
+
+/// List the records of the data file `fd`, in file order, which pass `filter`
+///
+/// The records are read by `record_reader`, just as when indexing or compacting, so this shows
+/// exactly what they see: a torn record at the end is left out, and damage stops the listing,
+/// which keeps everything read up to there (what matters most, when looking into the damage).
+pub(crate) fn inspect_file(fd: &BorrowedFd, filter: Filter) -> Result<Listing, Error> {
+    let Some(file_header) = read_file_header(fd)? else {
+        return Ok(Listing::default());
+    };
+    let mut entries = Vec::new();
+    let from = FILE_HEADER_SIZE as u64;
+    let mut reached = from; // the end of the last record read
+    let result = record_reader(fd, &file_header, from, |fd, header| {
+        reached = header.next_offset();
+        let shown = match filter {
+            Filter::All => true,
+            Filter::Live => header.kind == RecordKind::Value && !header.deleted,
+            Filter::Deleted => header.deleted,
+        };
+        if shown {
+            let body_buf: &mut [u8] = &mut vec![0; header.body_size()];
+            _ = read(fd, &mut *body_buf)?;
+            let (_, val) = header.split(body_buf);
+            entries.push(Entry {
+                offset: header.offset,
+                hash: header.hash.clone(),
+                kind: header.kind,
+                size: header.size as u64,
+                deleted: header.deleted,
+                preview: preview(val),
+            });
+        }
+        Ok(None)
+    });
+    let stopped = match result {
+        Err(Error::Sys(Errno::EKEYEXPIRED)) | Ok(_) => None,
+        Err(e @ Error::Corrupt(offset)) => Some((offset, e)),
+        Err(e) => Some((reached, e)),
+    };
+    Ok(Listing { entries, stopped })
+}
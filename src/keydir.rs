@@ -0,0 +1,115 @@
+use crate::format::{FILE_HEADER_SIZE, FileHeader};
+use std::collections::{BTreeMap, HashSet};
+use std::ops::Bound::{Excluded, Unbounded};
+
+/// Where the live value for a (hashed) key sits in the data file
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KeydirEntry {
+    pub offset: u64, // start of the `[(hashed) key][size of value][deleted?][checksum]...` record
+    pub size: usize, // size of the value
+    pub len: u64,    // of the whole record
+}
+
+/// Bitcask-style in-memory index: hashed key -> location of its live record
+///
+/// The entries are kept in the order of their hashes, so that a scan can carry on from
+/// wherever the one page before it stopped, without going through all the rest again.
+/// The index remembers which file it describes (`ino`) and how much of it has been
+/// read (`indexed_len`), so that records appended by anyone else, or a file swapped
+/// in by `compact()`, can be picked up without rescanning from the start every time.
+/// It also keeps the header of that file, which says how to read (and write) its records,
+/// and which keys were deleted in it, so that a segmented store knows not to look any
+/// further back for them.
+#[derive(Debug, Default)]
+pub struct Keydir {
+    entries: BTreeMap<String, KeydirEntry>,
+    deleted: HashSet<String>,
+    live_bytes: u64, // the total length of the records in `entries`
+    pub ino: u64,
+    pub indexed_len: u64,
+    pub file_header: FileHeader,
+}
+
+impl Keydir {
+    pub fn get(&self, hash: &str) -> Option<KeydirEntry> {
+        self.entries.get(hash).copied()
+    }
+
+    pub fn insert(&mut self, hash: String, entry: KeydirEntry) {
+        self.deleted.remove(&hash);
+        self.live_bytes += entry.len;
+        if let Some(old) = self.entries.insert(hash, entry) {
+            self.live_bytes -= old.len;
+        }
+    }
+
+    /// Drop the entry for `hash`, noting that the key was deleted in this file
+    pub fn remove(&mut self, hash: &str) -> Option<KeydirEntry> {
+        self.deleted.insert(String::from(hash));
+        let removed = self.entries.remove(hash);
+        if let Some(old) = removed {
+            self.live_bytes -= old.len;
+        }
+        removed
+    }
+
+    /// Drop the entry for `hash`, but only if it still points at the record at `offset`
+    pub fn remove_at(&mut self, hash: &str, offset: u64) {
+        if self.get(hash).is_some_and(|entry| entry.offset == offset) {
+            self.remove(hash);
+        }
+    }
+
+    /// Whether the last record for `hash` in this file deleted the key (rather than there being none)
+    pub fn is_deleted(&self, hash: &str) -> bool {
+        self.deleted.contains(hash)
+    }
+
+    /// Whether this file has the last word on `hash`: a live record of it, or one deleting it
+    pub fn has_record(&self, hash: &str) -> bool {
+        self.entries.contains_key(hash) || self.deleted.contains(hash)
+    }
+
+    pub fn deleted(&self) -> impl Iterator<Item = &String> {
+        self.deleted.iter()
+    }
+
+    /// How many bytes of the file the live records take up
+    pub fn live_bytes(&self) -> u64 {
+        self.live_bytes
+    }
+
+    /// How many bytes of the file (as far as it has been indexed) are taken up by anything else:
+    /// deleted and replaced records, tombstones and batch markers, i.e. what compaction would reclaim
+    pub fn dead_bytes(&self) -> u64 {
+        self.indexed_len
+            .saturating_sub(FILE_HEADER_SIZE as u64 + self.live_bytes)
+    }
+
+    /// Forget everything, e.g. when the data file has been replaced
+    pub fn reset(&mut self, ino: u64) {
+        self.entries.clear();
+        self.deleted.clear();
+        self.live_bytes = 0;
+        self.ino = ino;
+        self.indexed_len = 0;
+        self.file_header = FileHeader::default();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &KeydirEntry)> {
+        self.entries.iter()
+    }
+
+    /// The entries whose hashes come after `hash`, in order
+    pub fn after(&self, hash: &str) -> impl Iterator<Item = (&String, &KeydirEntry)> {
+        self.entries.range::<str, _>((Excluded(hash), Unbounded))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
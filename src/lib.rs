@@ -1,5 +1,14 @@
+pub mod batch;
+pub mod durability;
+pub mod error;
 pub mod file_syscalls;
-pub mod fork_syscalls;
+pub mod format;
 pub mod hasher;
+pub mod inspect;
+pub mod keydir;
+pub mod scan;
+pub mod segments;
 pub mod server;
 pub mod signal_syscalls;
+pub mod store;
+pub mod verify;
@@ -1,19 +1,104 @@
-use coat_check::file_syscalls::{compact, delete_key, read_key, write_key_val};
-use coat_check::fork_syscalls::size;
-use coat_check::server::Server;
+use coat_check::durability::Durability;
+use coat_check::error::Error;
+use coat_check::file_syscalls::{compact, inspect, migrate, repair, verify};
+use coat_check::hasher::HasherKind;
+use coat_check::inspect::Filter;
+use coat_check::server::{AutoCompact, Server};
 use coat_check::signal_syscalls::register_compaction_sig_handler;
-use log::{error, info};
+use coat_check::store::{Store, StoreOptions, Ttl};
+use log::{error, info, warn};
 use nix::errno::Errno;
 use std::env;
+use std::fs;
+use std::str::FromStr;
+use std::time::Duration;
+
+// the number in the env var `name`, if there is one (and exit if it is not a number)
+fn number_from_env<T: FromStr>(name: &str) -> Option<T> {
+    let value = std::env::var(name).ok()?;
+    match value.parse::<T>() {
+        Ok(number) => Some(number),
+        Err(_) => {
+            error!("error: {name} is not a number: {value:?}");
+            std::process::exit(1);
+        }
+    }
+}
+
+// log how many bytes the data take up on the disk (all the segments, with a directory of them)
+fn log_size(filepath: &str) {
+    let size = fs::metadata(filepath).and_then(|metadata| match metadata.is_dir() {
+        true => fs::read_dir(filepath)?
+            .map(|entry| Ok(entry?.metadata()?.len()))
+            .sum::<Result<u64, std::io::Error>>(),
+        false => Ok(metadata.len()),
+    });
+    match size {
+        Ok(bytes) => info!("{filepath:#?}: {bytes} bytes"),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => info!("{filepath:#?}: no data yet"),
+        Err(e) => info!("{filepath:#?}: {e}"),
+    }
+}
 
 fn main() {
     env_logger::init();
     let file_folder =
         std::env::var("COAT_CHECK_FILE_PATH").expect("env var 'COAT_CHECK_FILE_PATH' not defined");
 
-    // before: take the size of the date file, as a fork call to `wc`
+    // which hash algorithm to index keys by, should the data file need to be created
+    let mut options = StoreOptions::default();
+    if let Ok(name) = std::env::var("COAT_CHECK_HASHER") {
+        match name.parse::<HasherKind>() {
+            Ok(hasher) => options.hasher = hasher,
+            Err(e) => {
+                error!("error: {e}");
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // and how soon writes have to be on the disk
+    if let Ok(policy) = std::env::var("COAT_CHECK_DURABILITY") {
+        match policy.parse::<Durability>() {
+            Ok(durability) => options.durability = durability,
+            Err(e) => {
+                error!("error: {e}");
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // and whether to keep the data in a directory of segments (of up to so many bytes each) instead
+    if let Ok(size) = std::env::var("COAT_CHECK_SEGMENT_SIZE") {
+        match size.parse::<u64>() {
+            Ok(bytes) if bytes > 0 => options.segment_size = Some(bytes),
+            _ => {
+                error!("error: invalid segment size {size:?} (a number of bytes)");
+                std::process::exit(1);
+            }
+        }
+    }
+    // (the commands which only read share the lock, and make do without write permission)
+    let reading = StoreOptions {
+        read_only: true,
+        ..options
+    };
+
+    // and whether the server compacts by itself, once there is enough garbage (but no more often
+    // than every so many seconds)
+    let dead_ratio = number_from_env::<f64>("COAT_CHECK_COMPACT_RATIO");
+    let dead_bytes = number_from_env::<u64>("COAT_CHECK_COMPACT_BYTES");
+    let auto_compact = (dead_ratio.is_some() || dead_bytes.is_some()).then(|| AutoCompact {
+        dead_ratio,
+        dead_bytes,
+        min_interval: Duration::from_secs(
+            number_from_env("COAT_CHECK_COMPACT_INTERVAL").unwrap_or(60),
+        ),
+    });
+
+    // before: the size of the data file
     let f = file_folder.clone();
-    size(f.clone());
+    log_size(&f);
 
     // allow compaction to be requested by a signal (SIGUSR2)
     match register_compaction_sig_handler() {
@@ -26,6 +111,8 @@ fn main() {
         let server = Server {
             port: 5000,
             filepath: f.clone(),
+            options,
+            auto_compact,
         };
         match server.start() {
             Ok(_) => {
@@ -33,59 +120,332 @@ fn main() {
                 std::process::exit(0)
             }
             Err(e) => {
-                error!("syscall error {:#?}", e);
+                error!("error: {e}");
                 std::process::exit(1);
             }
         }
     } else if args.len() == 2 && &args[1] == "compact" {
-        match compact(file_folder.clone()) {
+        // (with segments, the store merges the older ones)
+        let compacted = match options.segment_size {
+            Some(_) => {
+                Store::open_with(file_folder.clone(), options).and_then(|store| store.compact())
+            }
+            None => compact(file_folder.clone()),
+        };
+        match compacted {
             Ok(_) => {
                 info!("compact complete");
                 std::process::exit(0)
             }
             Err(e) => match e {
-                Errno::ENOENT => {
+                Error::Sys(Errno::ENOENT) => {
                     info!("nothing to compact, no data in {:#?}", file_folder);
                     std::process::exit(0)
                 }
                 _ => {
-                    error!("compact error {:#?}", e);
+                    error!("compact error: {e}");
                     std::process::exit(1)
                 }
             },
         }
+    } else if (args.len() == 2 || (args.len() == 3 && &args[2] == "--json")) && &args[1] == "stats"
+    {
+        // count up the records, as a report, or as JSON for scripts
+        match Store::open_with(file_folder.clone(), reading).and_then(|store| store.stats()) {
+            Ok(stats) if args.len() == 3 => {
+                println!("{}", stats.to_json());
+                std::process::exit(0)
+            }
+            Ok(stats) => {
+                println!("records:       {}", stats.records);
+                println!("live:          {}", stats.live);
+                println!("deleted:       {}", stats.deleted);
+                println!("live bytes:    {}", stats.live_bytes);
+                println!("dead bytes:    {}", stats.dead_bytes);
+                println!("largest value: {}", stats.largest_value);
+                println!("file size:     {}", stats.file_size);
+                std::process::exit(0)
+            }
+            Err(e) => {
+                error!("stats error: {e}");
+                std::process::exit(1)
+            }
+        }
+    } else if args.len() == 3 && &args[1] == "verify" {
+        // check every record of a data file (or segment), exiting non-zero on any problem
+        match verify(args[2].clone()) {
+            Ok(report) => {
+                for problem in &report.problems {
+                    error!("{}: {problem}", args[2]);
+                }
+                info!(
+                    "verify complete: {} records, {} problems in {:#?}",
+                    report.records,
+                    report.problems.len(),
+                    args[2]
+                );
+                std::process::exit(if report.is_ok() { 0 } else { 1 })
+            }
+            Err(e) => {
+                error!("verify error: {e}");
+                std::process::exit(1)
+            }
+        }
+    } else if args.len() >= 3 && &args[1] == "inspect" {
+        // list every record (or just the live, or deleted, ones), as a table or as JSON lines
+        let mut filter = Filter::All;
+        let mut json = false;
+        for flag in &args[3..] {
+            match flag.as_str() {
+                "--live" => filter = Filter::Live,
+                "--deleted" => filter = Filter::Deleted,
+                "--json" => json = true,
+                _ => {
+                    error!("error: unknown inspect option {flag:?} (--live, --deleted or --json)");
+                    std::process::exit(1);
+                }
+            }
+        }
+        match inspect(args[2].clone(), filter) {
+            Ok(listing) => {
+                for entry in listing.entries {
+                    match json {
+                        true => println!("{}", entry.to_json()),
+                        false => println!("{entry}"),
+                    }
+                }
+                // (everything up to the damage, then where it is)
+                if let Some((offset, e)) = listing.stopped {
+                    error!("{}: listing stopped at offset {offset}: {e}", args[2]);
+                    std::process::exit(1)
+                }
+                std::process::exit(0)
+            }
+            Err(e) => {
+                error!("inspect error: {e}");
+                std::process::exit(1)
+            }
+        }
+    } else if args.len() == 4 && &args[1] == "repair" {
+        // copy whatever is still intact of a damaged data file to a new one
+        match repair(args[2].clone(), args[3].clone()) {
+            Ok(salvage) => {
+                for (offset, len) in &salvage.skipped {
+                    warn!(
+                        "{}: skipped {len} damaged bytes at offset {offset}",
+                        args[2]
+                    );
+                }
+                info!(
+                    "repair complete: {} records from {:#?} to {:#?} ({} dead ones dropped, {} damaged stretches skipped)",
+                    salvage.salvaged,
+                    args[2],
+                    args[3],
+                    salvage.dropped,
+                    salvage.skipped.len()
+                );
+                std::process::exit(0)
+            }
+            Err(e) => {
+                error!("repair error: {e}");
+                std::process::exit(1)
+            }
+        }
+    } else if args.len() == 4 && &args[1] == "migrate" {
+        // rewrite a data file from before format version 1 in the current layout
+        match migrate(args[2].clone(), args[3].clone()) {
+            Ok(records) => {
+                info!(
+                    "migrate complete: {records} records from {:#?} to {:#?}",
+                    args[2], args[3]
+                );
+                std::process::exit(0)
+            }
+            Err(e) => {
+                error!("migrate error: {e}");
+                std::process::exit(1)
+            }
+        }
     } else if args.len() < 3 {
         let prog = &args[0];
-        error!("Usage:\n\n{prog} <server> | compact | <(get|set) [key] [value (only with 'set')]>");
+        error!(
+            "Usage:\n\n{prog} <server> | compact | stats [--json] | verify [file] | repair [src] [dst] | inspect [file] [--live|--deleted] [--json] | migrate [src] [dst] | <(get|set|del|ttl|persist) [key] [value (only with 'set')] [--ttl seconds (only with 'set')]> | cas [key] [expected] [new] | (incr|decr) [key] [delta] | append [key] [value] | (mget|exists) [key]... | mset [key] [value]... | keys [pattern]"
+        );
         std::process::exit(0);
     }
 
-    let action = &args[1]; // "get", "set", or "del"
+    let action = &args[1]; // "get", "set", "del", or any of the other key-value commands above
     match action.as_str() {
-        "get" => match read_key(file_folder.clone(), &args[2]) {
+        "get" => match Store::open_with(file_folder.clone(), reading)
+            .and_then(|store| store.get(&args[2]))
+        {
             Ok(bytes) => match bytes {
                 Some(result) => info!("success: matched -> {:?}", String::from_utf8(result)),
                 None => info!("no match found"),
             },
             Err(e) => {
-                error!("syscall error {:#?}", e);
+                error!("error: {e}");
                 std::process::exit(1);
             }
         },
-        "set" => match write_key_val(file_folder.clone(), &args[2], &args[3].as_bytes()) {
+        "set" if args.len() == 6 && &args[4] == "--ttl" => {
+            let seconds = match args[5].parse::<u64>() {
+                Ok(seconds) => seconds,
+                Err(e) => {
+                    error!("error: invalid --ttl {:?}: {e}", args[5]);
+                    std::process::exit(1);
+                }
+            };
+            match Store::open_with(file_folder.clone(), options)
+                .and_then(|store| store.set_ex(&args[2], args[3].as_bytes(), seconds))
+            {
+                Ok(bytes) => info!("success: wrote {bytes} bytes, expiring in {seconds}s"),
+                Err(e) => {
+                    error!("error: {e}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        "set" => match Store::open_with(file_folder.clone(), options)
+            .and_then(|store| store.set(&args[2], args[3].as_bytes()))
+        {
             Ok(bytes) => info!("success: wrote {bytes} bytes"),
             Err(e) => {
-                error!("syscall error {:#?}", e);
+                error!("error: {e}");
                 std::process::exit(1);
             }
         },
-        "del" => match delete_key(file_folder.clone(), &args[2]) {
+        "del" => match Store::open_with(file_folder.clone(), options)
+            .and_then(|store| store.delete(&args[2]))
+        {
             Ok(bytes) => match bytes {
                 Some(result) => info!("success: deleted value -> {:?}", String::from_utf8(result)),
                 None => info!("no match found"),
             },
             Err(e) => {
-                error!("syscall error {:#?}", e);
+                error!("error: {e}");
+                std::process::exit(1);
+            }
+        },
+        "cas" if args.len() == 5 => {
+            match Store::open_with(file_folder.clone(), options)
+                .and_then(|store| store.cas(&args[2], args[3].as_bytes(), args[4].as_bytes()))
+            {
+                Ok(true) => info!("success: swapped"),
+                Ok(false) => {
+                    info!("mismatch: value is not {:?}", args[3]);
+                    std::process::exit(1);
+                }
+                Err(e) => {
+                    error!("error: {e}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        "ttl" => match Store::open_with(file_folder.clone(), reading)
+            .and_then(|store| store.ttl(&args[2]))
+        {
+            Ok(Ttl::Seconds(seconds)) => info!("success: expires in {seconds}s"),
+            Ok(Ttl::Forever) => info!("success: no expiry"),
+            Ok(Ttl::Missing) => info!("no match found"),
+            Err(e) => {
+                error!("error: {e}");
+                std::process::exit(1);
+            }
+        },
+        "mget" => match Store::open_with(file_folder.clone(), reading).and_then(|store| {
+            let keys: Vec<&str> = args[2..].iter().map(String::as_str).collect();
+            store.mget(&keys)
+        }) {
+            Ok(values) => {
+                for (key, value) in args[2..].iter().zip(values) {
+                    match value {
+                        Some(result) => info!("{key}: matched -> {:?}", String::from_utf8(result)),
+                        None => info!("{key}: no match found"),
+                    }
+                }
+            }
+            Err(e) => {
+                error!("error: {e}");
+                std::process::exit(1);
+            }
+        },
+        "mset" if args.len().is_multiple_of(2) => {
+            match Store::open_with(file_folder.clone(), options).and_then(|store| {
+                let pairs: Vec<(&str, &[u8])> = args[2..]
+                    .chunks(2)
+                    .map(|pair| (pair[0].as_str(), pair[1].as_bytes()))
+                    .collect();
+                store.mset(&pairs)
+            }) {
+                Ok(bytes) => info!("success: wrote {bytes} bytes"),
+                Err(e) => {
+                    error!("error: {e}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        "exists" => match Store::open_with(file_folder.clone(), reading).and_then(|store| {
+            let keys: Vec<&str> = args[2..].iter().map(String::as_str).collect();
+            store.exists(&keys)
+        }) {
+            Ok(count) => info!("{count} of {} exist", args.len() - 2),
+            Err(e) => {
+                error!("error: {e}");
+                std::process::exit(1);
+            }
+        },
+        "keys" if args.len() == 3 => match Store::open_with(file_folder.clone(), reading)
+            .and_then(|store| store.keys(&args[2]))
+        {
+            Ok(keys) => {
+                for key in keys {
+                    println!("{key}");
+                }
+            }
+            Err(e) => {
+                error!("error: {e}");
+                std::process::exit(1);
+            }
+        },
+        "append" if args.len() == 4 => match Store::open_with(file_folder.clone(), options)
+            .and_then(|store| store.append(&args[2], args[3].as_bytes()))
+        {
+            Ok(len) => info!("success: value is now {len} bytes"),
+            Err(e) => {
+                error!("error: {e}");
+                std::process::exit(1);
+            }
+        },
+        "incr" | "decr" if args.len() <= 4 => {
+            let delta = match args.get(3).map(|delta| delta.parse::<i64>()) {
+                Some(Ok(delta)) => delta,
+                Some(Err(e)) => {
+                    error!("error: invalid delta {:?}: {e}", args[3]);
+                    std::process::exit(1);
+                }
+                None => 1,
+            };
+            match Store::open_with(file_folder.clone(), options).and_then(|store| {
+                match action.as_str() {
+                    "incr" => store.incr(&args[2], delta),
+                    _ => store.decr(&args[2], delta),
+                }
+            }) {
+                Ok(count) => info!("success: {count}"),
+                Err(e) => {
+                    error!("error: {e}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        "persist" => match Store::open_with(file_folder.clone(), options)
+            .and_then(|store| store.persist(&args[2]))
+        {
+            Ok(true) => info!("success: expiry removed"),
+            Ok(false) => info!("no expiry to remove"),
+            Err(e) => {
+                error!("error: {e}");
                 std::process::exit(1);
             }
         },
@@ -95,6 +455,6 @@ fn main() {
         }
     };
 
-    // after: take the size of the date file, as a fork call to `wc`
-    size(f.clone());
+    // after: the size of the data file
+    log_size(&f);
 }
@@ -0,0 +1,139 @@
+use crate::error::Error;
+use crate::store::Store;
+use std::collections::VecDeque;
+
+/* Listing keys
+ *
+ * A scan pages through the live keys in the order of their hashes, which is the same whatever
+ * gets written (or compacted) in the meantime: each page carries on from the hash the one before
+ * it stopped at (the cursor), so a key which stays live for the whole scan turns up exactly once,
+ * and the lock is only ever held for one page at a time.
+ *
+ */
+
+/// The cursor a scan starts from, and which it hands back once there is nothing left
+pub const SCAN_START: &str = "0";
+
+/// How many records a page of a scan reads, unless asked for more (or fewer)
+pub const SCAN_COUNT: usize = 10;
+
+/// Whether `key` matches the glob `pattern`
+///
+/// `*` matches any run of characters, `?` any one character, and `[...]` any one of those in the
+/// brackets (ranges like `a-z` included, or any but those, as `[^...]` or `[!...]`); a `\` makes
+/// the next character match only itself.
+pub fn glob_match(pattern: &str, key: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let key: Vec<char> = key.chars().collect();
+    matches(&pattern, &key)
+}
+
+// one pass over the key, greedy: a `*` first matches nothing, and on a mismatch later on, the most
+// recent `*` takes one more character and the rest of the pattern is tried again from there (any
+// earlier `*` never needs to take more, since the later one can), so it's O(pattern * key) at worst
+fn matches(pattern: &[char], key: &[char]) -> bool {
+    let (mut p, mut k) = (0, 0);
+    let mut star: Option<(usize, usize)> = None; // (just past the last `*`, where in the key it took up to)
+    while k < key.len() {
+        // how much of the pattern matches the one character `key[k]`, if any of it does
+        let step = match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, k));
+                p += 1;
+                continue;
+            }
+            Some('?') => Some(1),
+            Some('[') => match in_class(&pattern[p + 1..], key[k]) {
+                Some((true, len)) => Some(1 + len),
+                Some((false, _)) => None,
+                None => (key[k] == '[').then_some(1),
+            },
+            Some('\\') if p + 1 < pattern.len() => (key[k] == pattern[p + 1]).then_some(2),
+            Some(c) => (key[k] == *c).then_some(1),
+            None => None,
+        };
+        match (step, star) {
+            (Some(len), _) => {
+                p += len;
+                k += 1;
+            }
+            (None, Some((after, took))) => {
+                star = Some((after, took + 1));
+                p = after;
+                k = took + 1;
+            }
+            (None, None) => return false,
+        }
+    }
+    // (whatever is left of the pattern has to match nothing)
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+// whether `c` is in the class at the start of `pattern` (just past its `[`), along with the length
+// of the class, up to and including its `]`; none without a `]`, as then the `[` is just a `[`
+fn in_class(pattern: &[char], c: char) -> Option<(bool, usize)> {
+    let negated = matches!(pattern.first(), Some('^' | '!'));
+    let mut i = negated as usize;
+    let start = i;
+    let mut found = false;
+    while i < pattern.len() {
+        match pattern[i] {
+            // (a `]` straight after the `[` is one of the characters, not the end)
+            ']' if i > start => return Some((found != negated, i + 1)),
+            '\\' if i + 1 < pattern.len() => {
+                found |= pattern[i + 1] == c;
+                i += 2;
+            }
+            lo if i + 2 < pattern.len() && pattern[i + 1] == '-' && pattern[i + 2] != ']' => {
+                let hi = pattern[i + 2];
+                found |= (lo.min(hi)..=lo.max(hi)).contains(&c);
+                i += 3;
+            }
+            other => {
+                found |= other == c;
+                i += 1;
+            }
+        }
+    }
+    None
+}
+
+/// The live keys of a store which match a glob pattern, a page at a time (see `Store::scan()`)
+pub struct Scan<'a> {
+    store: &'a Store,
+    pattern: String,
+    count: usize,
+    cursor: Option<String>, // none once the last page has been read
+    page: VecDeque<String>,
+}
+
+impl<'a> Scan<'a> {
+    pub(crate) fn new(store: &'a Store, pattern: &str, count: usize) -> Scan<'a> {
+        Scan {
+            store,
+            pattern: String::from(pattern),
+            count: count.max(1),
+            cursor: Some(String::from(SCAN_START)),
+            page: VecDeque::new(),
+        }
+    }
+}
+
+impl Iterator for Scan<'_> {
+    type Item = Result<String, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // (a page can well come back empty, with none of its keys matching, so keep going until one doesn't)
+        while self.page.is_empty() {
+            let cursor = self.cursor.take()?;
+            match self.store.scan_page(&cursor, &self.pattern, self.count) {
+                Ok((next, keys)) => {
+                    self.page.extend(keys);
+                    self.cursor = (next != SCAN_START).then_some(next);
+                }
+                Err(e) => return Some(Err(e)), // and nothing after it
+            }
+        }
+        self.page.pop_front().map(Ok)
+    }
+}
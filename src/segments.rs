@@ -0,0 +1,219 @@
+use crate::error::Error;
+use crate::file_syscalls::{
+    create_compact_tmp, index_records, open_locked, read_record, release, sync_dir, write_all,
+};
+use crate::format::{FLAG_MERGED, FileHeader, encode_record};
+use crate::keydir::{Keydir, KeydirEntry};
+use nix::dir::Dir;
+use nix::errno::Errno;
+use nix::fcntl::{Flock, FlockArg, OFlag, open};
+use nix::sys::stat::{Mode, fstat};
+use nix::unistd::{close, fsync, mkdir, unlink};
+use std::collections::{HashMap, HashSet};
+use std::os::fd::{AsFd, OwnedFd};
+
+/* Segmented stores
+ *
+ * Instead of one ever-growing data file, a directory of them, numbered in the order they
+ * were written: `000001.coat-check`, `000002.coat-check`, ... Only the highest-numbered
+ * (active) segment gets appended to, and once it reaches the configured size, the next
+ * write starts a new one. A key's live record is in the newest segment which has a
+ * record for it, so the older ones never change, until a merge rewrites them as one.
+ *
+ * Every segment starts with the same header as the first, so they all hash keys alike.
+ *
+ */
+
+const SEGMENT_SUFFIX: &str = ".coat-check";
+
+// serializes merges, which only take the lock on the directory for their final rename
+const MERGE_LOCK: &str = "merge.lock";
+
+pub(crate) fn segment_path(dir: &str, number: u64) -> String {
+    format!("{dir}/{number:06}{SEGMENT_SUFFIX}")
+}
+
+// the number of the segment called `name`, if it is one
+fn segment_number(name: &str) -> Option<u64> {
+    let digits = name.strip_suffix(SEGMENT_SUFFIX)?;
+    match !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()) {
+        true => digits.parse().ok(),
+        false => None,
+    }
+}
+
+/// An immutable segment, along with its own keydir
+#[derive(Debug)]
+pub(crate) struct Segment {
+    pub number: u64,
+    pub keydir: Keydir,
+}
+
+/// What a store knows of its segment directory: which segment is active, and the older ones
+#[derive(Debug, Default)]
+pub(crate) struct Segments {
+    pub active: u64,
+    pub older: Vec<Segment>, // oldest first
+}
+
+/// The segments in `dir`, oldest first, as `(number, inode)`
+pub(crate) fn list_segments(dir: &str) -> Result<Vec<(u64, u64)>, Errno> {
+    let mut dir = Dir::open(dir, OFlag::O_RDONLY | OFlag::O_DIRECTORY, Mode::empty())?;
+    let mut segments: Vec<(u64, u64)> = dir
+        .iter()
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            segment_number(&entry.file_name().to_string_lossy()).map(|number| (number, entry.ino()))
+        })
+        .collect();
+    segments.sort();
+    Ok(segments)
+}
+
+/// Open the directory `dir` and take the requested flock on it, creating it first if asked to
+///
+/// This is the lock every operation on a segmented store holds, the way a single data file is locked.
+pub(crate) fn lock_dir(dir: &str, create: bool, arg: FlockArg) -> Result<Flock<OwnedFd>, Errno> {
+    let oflag = OFlag::O_RDONLY | OFlag::O_DIRECTORY;
+    let fd: OwnedFd = match open(dir, oflag, Mode::empty()) {
+        Err(Errno::ENOENT) if create => {
+            match mkdir(dir, Mode::S_IRWXU | Mode::S_IRWXG | Mode::S_IRWXO) {
+                Ok(_) => sync_dir(dir)?,
+                Err(Errno::EEXIST) => (), // someone else got there first
+                Err(e) => return Err(e),
+            }
+            open(dir, oflag, Mode::empty())?
+        }
+        opened => opened?,
+    };
+    match Flock::lock(fd, arg) {
+        Ok(locked) => Ok(locked),
+        Err((_, e)) => Err(e),
+    }
+}
+
+/// Index all of segment `number` in `dir`
+pub(crate) fn index_segment(dir: &str, number: u64) -> Result<Segment, Error> {
+    let filepath = segment_path(dir, number);
+    let fd: OwnedFd = open(filepath.as_str(), OFlag::O_RDONLY, Mode::empty())?;
+    let mut keydir = Keydir::default();
+    let indexed = index_records(&filepath, &fd.as_fd(), &mut keydir);
+    close(fd)?;
+    indexed?;
+    Ok(Segment { number, keydir })
+}
+
+/// Take the lock which only one merge of the segments in `dir` can hold at a time
+pub(crate) fn lock_merges(dir: &str) -> Result<Flock<OwnedFd>, Errno> {
+    open_locked(
+        &format!("{dir}/{MERGE_LOCK}"),
+        OFlag::O_RDWR | OFlag::O_CREAT,
+        FlockArg::LockExclusive,
+    )
+}
+
+/// Write the live records of the (immutable) segments `numbers` into a single new one, in a temp file,
+/// leaving out those for the `newer` (hashed) keys, which have records in later segments
+///
+/// The new segment is to take the number of the newest of them, and has `FLAG_MERGED` set, since
+/// it supersedes all of them: it gets no tombstones, as there is nothing older left for them to
+/// delete. Returns the locked, fsynced temp file, its name, and the keydir for it, for the caller
+/// to rename into place (under the lock on the directory) and then remove the merged segments.
+pub(crate) fn write_merged(
+    dir: &str,
+    numbers: &[u64],
+    newer: &HashSet<String>,
+) -> Result<(Flock<OwnedFd>, String, Segment), Error> {
+    let target = *numbers.last().ok_or(Errno::EINVAL)?;
+    let segments: Vec<Segment> = numbers
+        .iter()
+        .map(|number| index_segment(dir, *number))
+        .collect::<Result<_, _>>()?;
+
+    // later segments win, just as in a lookup: what survives is the latest record for each key
+    let mut live: HashMap<&String, (usize, KeydirEntry)> = HashMap::new();
+    for (i, segment) in segments.iter().enumerate() {
+        for (hash, entry) in segment.keydir.iter() {
+            live.insert(hash, (i, *entry));
+        }
+        for hash in segment.keydir.deleted() {
+            live.remove(hash);
+        }
+    }
+    live.retain(|hash, _| !newer.contains(*hash));
+    // and copied in the order they were written
+    let mut records: Vec<(usize, KeydirEntry)> = live.into_values().collect();
+    records.sort_by_key(|(i, entry)| (*i, entry.offset));
+
+    let newest = &segments[segments.len() - 1].keydir.file_header;
+    let file_header = FileHeader {
+        flags: newest.flags | FLAG_MERGED,
+        ..*newest
+    };
+    let (tmp_lock, tmp_filepath) = create_compact_tmp(&segment_path(dir, target))?;
+    let written =
+        write_segment(dir, &segments, &records, &tmp_lock, file_header).and_then(|mut keydir| {
+            fsync(tmp_lock.as_fd())?;
+            keydir.ino = fstat(tmp_lock.as_fd())?.st_ino;
+            Ok(keydir)
+        });
+    match written {
+        Ok(keydir) => Ok((
+            tmp_lock,
+            tmp_filepath,
+            Segment {
+                number: target,
+                keydir,
+            },
+        )),
+        Err(e) => {
+            _ = unlink(tmp_filepath.as_str());
+            release(tmp_lock)?;
+            Err(e)
+        }
+    }
+}
+
+// copy `records` (of `segments`) to the temp file, after `file_header`, indexing them at their new offsets
+fn write_segment(
+    dir: &str,
+    segments: &[Segment],
+    records: &[(usize, KeydirEntry)],
+    tmp_lock: &Flock<OwnedFd>,
+    file_header: FileHeader,
+) -> Result<Keydir, Error> {
+    let mut keydir = Keydir::default();
+    keydir.file_header = file_header;
+    let mut tmp_len = write_all(&tmp_lock.as_fd(), &file_header.encode())? as u64;
+
+    let mut fds: HashMap<usize, OwnedFd> = HashMap::new();
+    for (i, entry) in records {
+        if !fds.contains_key(i) {
+            let filepath = segment_path(dir, segments[*i].number);
+            fds.insert(*i, open(filepath.as_str(), OFlag::O_RDONLY, Mode::empty())?);
+        }
+        let fd = &fds[i];
+        // (anything which has expired since the segment was indexed gets left behind too)
+        let Some((header, body)) =
+            read_record(&fd.as_fd(), &segments[*i].keydir.file_header, entry.offset)?
+        else {
+            continue;
+        };
+        let (key, val) = header.split(&body);
+        let buffer = encode_record(&file_header, &header.hash, key, val, header.expires_at);
+        keydir.insert(
+            header.hash.clone(),
+            KeydirEntry {
+                offset: tmp_len,
+                size: header.size,
+                len: buffer.len() as u64,
+            },
+        );
+        tmp_len += write_all(&tmp_lock.as_fd(), &buffer)? as u64;
+    }
+    for (_, fd) in fds {
+        close(fd)?;
+    }
+    keydir.indexed_len = tmp_len;
+    Ok(keydir)
+}
@@ -1,43 +1,67 @@
-use crate::file_syscalls::{compact, delete_key, read_key, write_key_val};
+use crate::batch::Batch;
+use crate::error::Error;
+use crate::scan::SCAN_COUNT;
 use crate::signal_syscalls::COMPACT_SIGNALED;
+use crate::store::{Garbage, Store, StoreOptions, Ttl};
+use chrono::Utc;
 use libc::{c_void, pthread_create, pthread_detach, pthread_t};
-use nix::errno::Errno;
 use nix::sys::socket::{
     AddressFamily, Backlog, MsgFlags, SockFlag, SockProtocol, SockType, SockaddrIn, accept, bind,
     listen, recv, send, socket,
 };
 use std::os::fd::{AsRawFd, RawFd};
 use std::sync::atomic::Ordering;
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
 use std::{mem, ptr};
 
 #[repr(C)]
 struct ClientThreadArgs {
     clientfd: RawFd,
-    filepath: String,
+    store: Arc<Store>,
 }
 
 extern "C" fn handle_client(arg: *mut c_void) -> *mut c_void {
     let args = unsafe { Box::from_raw(arg as *mut ClientThreadArgs) };
     println!(
         "Connected to client: {:#?} -> {:#?}",
-        args.clientfd, args.filepath
+        args.clientfd,
+        args.store.filepath()
     );
 
     let mut buf = [0u8; 1024];
     let read_err_msg = String::from("Failed to read from client");
     let write_err_msg = String::from("Failed to send to client");
-    let usage = String::from("Usage:\r\n<get> <key> | <set> <key> <value> | <del> <key>");
+    let usage = String::from(
+        "Usage:\r\n<get> <key> | <set> <key> <value> [EX <seconds>] | <del> <key> | <cas> <key> <expected> <new> | <ttl> <key> | <persist> <key> | <incr|decr> <key> [delta] | <append> <key> <value> | <mget> <key> ... | <mset> <key> <value> ... | <exists> <key> ... | <keys> <pattern> | <scan> <cursor> [MATCH <pattern>] [COUNT <n>] | <multi> ... <exec> | <discard> | <stats>",
+    );
+
+    // a client going away mid-conversation (e.g. ECONNRESET) just ends the session
+    let receive = |buf: &mut [u8]| {
+        recv(args.clientfd, buf, MsgFlags::empty()).unwrap_or_else(|e| {
+            eprintln!("{read_err_msg}: {e}");
+            0
+        })
+    };
+    let reply = |buf: &[u8]| {
+        if let Err(e) = send(args.clientfd, buf, MsgFlags::empty()) {
+            eprintln!("{write_err_msg}: {e}");
+        }
+    };
 
-    let mut nbytes = recv(args.clientfd, &mut buf, MsgFlags::empty()).expect(&read_err_msg);
+    // between MULTI and EXEC, sets and deletes queue up here, to be applied as one batch
+    let mut batch: Option<Batch> = None;
+
+    let mut nbytes = receive(&mut buf);
     while nbytes > 0 {
         let input_size = buf
-            .clone()
             .iter()
             .take_while(|c| **c != b'\n' && **c != b'\r')
             .count();
         if input_size > 0 {
             // Split the byte array on spaces
-            let raw_input = buf.clone();
+            let raw_input = buf;
             let parts: Vec<&[u8]> = raw_input[..input_size].split(|&b| b == b' ').collect();
 
             // Reset the buffer for writing back to the client
@@ -45,79 +69,321 @@ extern "C" fn handle_client(arg: *mut c_void) -> *mut c_void {
             let mut replied: bool = false;
 
             let cmd_size = parts.len();
-            if cmd_size > 1 {
+            if cmd_size == 1 {
+                let cmd = std::str::from_utf8(parts[0]).unwrap().to_ascii_lowercase();
+                let result = match (cmd.as_str(), batch.take()) {
+                    ("multi", None) => {
+                        batch = Some(Batch::new());
+                        Some(String::from("*** ok: batch started"))
+                    }
+                    ("multi", queued) => {
+                        batch = queued;
+                        Some(String::from("*** error: batch already started"))
+                    }
+                    ("exec", Some(queued)) => match args.store.apply(&queued) {
+                        Ok(bytes) => Some(format!("*** success: wrote {bytes} bytes")),
+                        Err(e) => Some(format!("*** error: {:?}", e.desc())),
+                    },
+                    ("discard", Some(queued)) => {
+                        Some(format!("*** success: discarded {} commands", queued.len()))
+                    }
+                    ("exec", None) | ("discard", None) => {
+                        Some(String::from("*** error: no batch started"))
+                    }
+                    ("stats", queued) => {
+                        batch = queued;
+                        match args.store.stats() {
+                            Ok(stats) => Some(stats.to_json()),
+                            Err(e) => Some(format!("*** error: {:?}", e.desc())),
+                        }
+                    }
+                    (_, queued) => {
+                        batch = queued;
+                        None
+                    }
+                };
+                if let Some(result) = result {
+                    let r = result.len();
+                    buf[0..r].copy_from_slice(result.as_bytes());
+                    buf[r..r + 2].copy_from_slice(b"\r\n");
+                    reply(&buf);
+                    replied = true;
+                }
+            } else if cmd_size > 1 {
                 let cmd = std::str::from_utf8(parts[0]).unwrap();
                 if cmd == "get" && cmd_size == 2 {
-                    match read_key(args.filepath.clone(), str::from_utf8(parts[1]).unwrap()) {
-                        Ok(bytes) => match bytes {
-                            Some(result) => {
-                                let r = result.len();
-                                buf[0..r].copy_from_slice(result.as_slice());
-                                buf[r..r + 2].copy_from_slice(b"\r\n")
-                            }
-                            None => {
-                                let no_match = String::from("*** no match found");
-                                let r = no_match.len();
-                                buf[0..r].copy_from_slice(no_match.as_bytes());
-                                buf[r..r + 2].copy_from_slice(b"\r\n")
-                            }
-                        },
-                        Err(e) => {
-                            let result = format!("*** error: {:?}", e.desc());
-                            let r = result.len();
-                            buf[0..r].copy_from_slice(result.as_bytes());
-                            buf[r..r + 2].copy_from_slice(b"\r\n")
-                        }
+                    let result: Vec<u8> = match args.store.get(str::from_utf8(parts[1]).unwrap()) {
+                        Ok(Some(value)) => value,
+                        Ok(None) => b"*** no match found".to_vec(),
+                        Err(e) => format!("*** error: {:?}", e.desc()).into_bytes(),
+                    };
+                    // (a value written some other way than over the wire can be too long to send)
+                    let result = match result.len() + 2 <= buf.len() {
+                        true => result,
+                        false => b"*** error: reply too long".to_vec(),
                     };
-                    send(args.clientfd, &mut buf, MsgFlags::empty()).expect(&write_err_msg);
+                    let r = result.len();
+                    buf[0..r].copy_from_slice(&result);
+                    buf[r..r + 2].copy_from_slice(b"\r\n");
+                    reply(&buf);
                     replied = true;
                 } else if cmd == "set" && cmd_size > 2 {
                     let key = str::from_utf8(parts[1]).unwrap();
                     // val as the remaining input, after the key
                     let val_start = key.len() + 5; // 5 = "set" and two spaces
-                    match write_key_val(
-                        args.filepath.clone(),
-                        key,
-                        &raw_input[val_start..input_size],
-                    ) {
-                        Ok(bytes) => {
-                            let result = format!("*** success: wrote {bytes} bytes");
-                            let r = result.len();
-                            buf[0..r].copy_from_slice(result.as_bytes());
-                            buf[r..r + 2].copy_from_slice(b"\r\n")
+                    // with a trailing `EX <seconds>`, the value stops short of it
+                    let expiry = match parts[cmd_size - 2..] {
+                        [ex, seconds] if cmd_size > 4 && ex.eq_ignore_ascii_case(b"EX") => {
+                            str::from_utf8(seconds)
+                                .ok()
+                                .and_then(|s| s.parse::<u64>().ok())
+                                .map(|secs| (secs, ex.len() + seconds.len() + 2)) // 2 = two spaces
+                        }
+                        _ => None,
+                    };
+                    let val = match expiry {
+                        Some((_, suffix)) => &raw_input[val_start..input_size - suffix],
+                        None => &raw_input[val_start..input_size],
+                    };
+                    let written = match (batch.as_mut(), expiry) {
+                        (Some(queued), Some((secs, _))) => {
+                            let expires_at = Utc::now().timestamp().saturating_add(secs as i64);
+                            queued.set_expiring(key, val, expires_at);
+                            None
                         }
-                        Err(e) => {
-                            let result = format!("*** error: {:?}", e.desc());
-                            let r = result.len();
-                            buf[0..r].copy_from_slice(result.as_bytes());
-                            buf[r..r + 2].copy_from_slice(b"\r\n")
+                        (Some(queued), None) => {
+                            queued.set(key, val);
+                            None
                         }
+                        (None, Some((secs, _))) => Some(args.store.set_ex(key, val, secs)),
+                        (None, None) => Some(args.store.set(key, val)),
+                    };
+                    let result = match written {
+                        Some(Ok(bytes)) => format!("*** success: wrote {bytes} bytes"),
+                        Some(Err(e)) => format!("*** error: {:?}", e.desc()),
+                        None => String::from("*** queued"),
+                    };
+                    let r = result.len();
+                    buf[0..r].copy_from_slice(result.as_bytes());
+                    buf[r..r + 2].copy_from_slice(b"\r\n");
+                    reply(&buf);
+                    replied = true;
+                } else if cmd == "del" && cmd_size == 2 && batch.is_some() {
+                    if let Some(queued) = batch.as_mut() {
+                        queued.delete(str::from_utf8(parts[1]).unwrap());
                     }
-                    send(args.clientfd, &mut buf, MsgFlags::empty()).expect(&write_err_msg);
+                    let result = String::from("*** queued");
+                    let r = result.len();
+                    buf[0..r].copy_from_slice(result.as_bytes());
+                    buf[r..r + 2].copy_from_slice(b"\r\n");
+                    reply(&buf);
                     replied = true;
                 } else if cmd == "del" && cmd_size == 2 {
-                    match delete_key(args.filepath.clone(), str::from_utf8(parts[1]).unwrap()) {
-                        Ok(bytes) => match bytes {
-                            Some(result) => {
-                                let r = result.len();
-                                buf[0..r].copy_from_slice(result.as_slice());
-                                buf[r..r + 2].copy_from_slice(b"\r\n")
+                    let result: Vec<u8> = match args.store.delete(str::from_utf8(parts[1]).unwrap())
+                    {
+                        Ok(Some(value)) => value,
+                        Ok(None) => b"*** no match found".to_vec(),
+                        Err(e) => format!("*** error: {:?}", e.desc()).into_bytes(),
+                    };
+                    let result = match result.len() + 2 <= buf.len() {
+                        true => result,
+                        false => b"*** error: reply too long".to_vec(),
+                    };
+                    let r = result.len();
+                    buf[0..r].copy_from_slice(&result);
+                    buf[r..r + 2].copy_from_slice(b"\r\n");
+                    reply(&buf);
+                    replied = true;
+                } else if cmd == "cas" && cmd_size == 4 {
+                    let key = str::from_utf8(parts[1]).unwrap();
+                    let result = match args.store.cas(key, parts[2], parts[3]) {
+                        Ok(true) => String::from("*** success: swapped"),
+                        Ok(false) => String::from("*** mismatch"),
+                        Err(e) => format!("*** error: {:?}", e.desc()),
+                    };
+                    let r = result.len();
+                    buf[0..r].copy_from_slice(result.as_bytes());
+                    buf[r..r + 2].copy_from_slice(b"\r\n");
+                    reply(&buf);
+                    replied = true;
+                } else if cmd == "ttl" && cmd_size == 2 {
+                    let result = match args.store.ttl(str::from_utf8(parts[1]).unwrap()) {
+                        Ok(Ttl::Seconds(secs)) => format!("{secs}"),
+                        Ok(Ttl::Forever) => String::from("*** no expiry"),
+                        Ok(Ttl::Missing) => String::from("*** no match found"),
+                        Err(e) => format!("*** error: {:?}", e.desc()),
+                    };
+                    let r = result.len();
+                    buf[0..r].copy_from_slice(result.as_bytes());
+                    buf[r..r + 2].copy_from_slice(b"\r\n");
+                    reply(&buf);
+                    replied = true;
+                } else if cmd == "append" && cmd_size > 2 {
+                    let key = str::from_utf8(parts[1]).unwrap();
+                    let bytes = &raw_input[key.len() + 8..input_size]; // 8 = "append" and two spaces
+                    // (no longer than a get can send back, with its line ending)
+                    let limit = buf.len() - 2;
+                    let result = match &batch {
+                        // (appending reads the value as it is, so it cannot wait for the batch)
+                        Some(_) => String::from("*** error: append cannot be batched"),
+                        None => match args.store.append_within(key, bytes, limit) {
+                            Ok(len) => format!("{len}"),
+                            Err(e) => format!("*** error: {:?}", e.desc()),
+                        },
+                    };
+                    let r = result.len();
+                    buf[0..r].copy_from_slice(result.as_bytes());
+                    buf[r..r + 2].copy_from_slice(b"\r\n");
+                    reply(&buf);
+                    replied = true;
+                } else if (cmd == "incr" || cmd == "decr") && (cmd_size == 2 || cmd_size == 3) {
+                    let key = str::from_utf8(parts[1]).unwrap();
+                    let delta = match cmd_size {
+                        3 => str::from_utf8(parts[2])
+                            .ok()
+                            .and_then(|d| d.parse::<i64>().ok()),
+                        _ => Some(1),
+                    };
+                    let result = match (delta, &batch) {
+                        (None, _) => String::from("*** error: invalid delta"),
+                        // (counting reads the value as it is, so it cannot wait for the batch)
+                        (Some(_), Some(_)) => format!("*** error: {cmd} cannot be batched"),
+                        (Some(delta), None) => {
+                            let counted = match cmd == "incr" {
+                                true => args.store.incr(key, delta),
+                                false => args.store.decr(key, delta),
+                            };
+                            match counted {
+                                Ok(count) => format!("{count}"),
+                                Err(e) => format!("*** error: {:?}", e.desc()),
                             }
-                            None => {
-                                let no_match = String::from("*** no match found");
-                                let r = no_match.len();
-                                buf[0..r].copy_from_slice(no_match.as_bytes());
-                                buf[r..r + 2].copy_from_slice(b"\r\n")
+                        }
+                    };
+                    let r = result.len();
+                    buf[0..r].copy_from_slice(result.as_bytes());
+                    buf[r..r + 2].copy_from_slice(b"\r\n");
+                    reply(&buf);
+                    replied = true;
+                } else if cmd == "persist" && cmd_size == 2 {
+                    let result = match args.store.persist(str::from_utf8(parts[1]).unwrap()) {
+                        Ok(true) => String::from("*** success: expiry removed"),
+                        Ok(false) => String::from("*** no expiry to remove"),
+                        Err(e) => format!("*** error: {:?}", e.desc()),
+                    };
+                    let r = result.len();
+                    buf[0..r].copy_from_slice(result.as_bytes());
+                    buf[r..r + 2].copy_from_slice(b"\r\n");
+                    reply(&buf);
+                    replied = true;
+                } else if cmd == "mget" {
+                    let keys: Vec<&str> = parts[1..]
+                        .iter()
+                        .map(|key| str::from_utf8(key).unwrap())
+                        .collect();
+                    // a line for each key, in the order asked for
+                    let result: Vec<u8> = match args.store.mget(&keys) {
+                        Ok(values) => values
+                            .into_iter()
+                            .map(|value| value.unwrap_or(b"*** no match found".to_vec()))
+                            .collect::<Vec<Vec<u8>>>()
+                            .join(&b"\r\n"[..]),
+                        Err(e) => format!("*** error: {:?}", e.desc()).into_bytes(),
+                    };
+                    let result = match result.len() + 2 <= buf.len() {
+                        true => result,
+                        false => b"*** error: reply too long".to_vec(),
+                    };
+                    let r = result.len();
+                    buf[0..r].copy_from_slice(&result);
+                    buf[r..r + 2].copy_from_slice(b"\r\n");
+                    reply(&buf);
+                    replied = true;
+                } else if cmd == "mset" && cmd_size % 2 == 1 {
+                    let pairs: Vec<(&str, &[u8])> = parts[1..]
+                        .chunks(2)
+                        .map(|pair| (str::from_utf8(pair[0]).unwrap(), pair[1]))
+                        .collect();
+                    let result = match batch.as_mut() {
+                        Some(queued) => {
+                            for (key, val) in pairs {
+                                queued.set(key, val);
                             }
+                            String::from("*** queued")
+                        }
+                        None => match args.store.mset(&pairs) {
+                            Ok(bytes) => format!("*** success: wrote {bytes} bytes"),
+                            Err(e) => format!("*** error: {:?}", e.desc()),
                         },
-                        Err(e) => {
-                            let result = format!("*** error: {:?}", e.desc());
-                            let r = result.len();
-                            buf[0..r].copy_from_slice(result.as_bytes());
-                            buf[r..r + 2].copy_from_slice(b"\r\n")
+                    };
+                    let r = result.len();
+                    buf[0..r].copy_from_slice(result.as_bytes());
+                    buf[r..r + 2].copy_from_slice(b"\r\n");
+                    reply(&buf);
+                    replied = true;
+                } else if cmd == "keys" && cmd_size == 2 {
+                    let pattern = str::from_utf8(parts[1]).unwrap();
+                    // a line for each key, in order
+                    let result: Vec<u8> = match args.store.keys(pattern) {
+                        Ok(keys) if keys.is_empty() => b"*** no match found".to_vec(),
+                        Ok(keys) => keys.join("\r\n").into_bytes(),
+                        Err(e) => format!("*** error: {:?}", e.desc()).into_bytes(),
+                    };
+                    let result = match result.len() + 2 <= buf.len() {
+                        true => result,
+                        false => b"*** error: reply too long (scan instead)".to_vec(),
+                    };
+                    let r = result.len();
+                    buf[0..r].copy_from_slice(&result);
+                    buf[r..r + 2].copy_from_slice(b"\r\n");
+                    reply(&buf);
+                    replied = true;
+                } else if cmd == "scan" && cmd_size.is_multiple_of(2) {
+                    let cursor = str::from_utf8(parts[1]).unwrap();
+                    // followed by any of `MATCH <glob>` and `COUNT <n>`, in either order
+                    let mut pattern = "*";
+                    let mut count: Option<usize> = Some(SCAN_COUNT);
+                    for option in parts[2..].chunks(2) {
+                        let value = str::from_utf8(option[1]).unwrap();
+                        if option[0].eq_ignore_ascii_case(b"MATCH") {
+                            pattern = value;
+                        } else if option[0].eq_ignore_ascii_case(b"COUNT") {
+                            count = value.parse::<usize>().ok().filter(|n| *n > 0);
+                        } else {
+                            count = None;
                         }
+                    }
+                    // the cursor to carry on from, then a line for each key
+                    let result: Vec<u8> = match count {
+                        None => b"*** error: invalid scan options".to_vec(),
+                        Some(count) => match args.store.scan_page(cursor, pattern, count) {
+                            Ok((next, keys)) => {
+                                [vec![next], keys].concat().join("\r\n").into_bytes()
+                            }
+                            Err(e) => format!("*** error: {:?}", e.desc()).into_bytes(),
+                        },
+                    };
+                    let result = match result.len() + 2 <= buf.len() {
+                        true => result,
+                        false => b"*** error: reply too long (a smaller count, then)".to_vec(),
+                    };
+                    let r = result.len();
+                    buf[0..r].copy_from_slice(&result);
+                    buf[r..r + 2].copy_from_slice(b"\r\n");
+                    reply(&buf);
+                    replied = true;
+                } else if cmd == "exists" {
+                    let keys: Vec<&str> = parts[1..]
+                        .iter()
+                        .map(|key| str::from_utf8(key).unwrap())
+                        .collect();
+                    let result = match args.store.exists(&keys) {
+                        Ok(count) => format!("{count}"),
+                        Err(e) => format!("*** error: {:?}", e.desc()),
                     };
-                    send(args.clientfd, &mut buf, MsgFlags::empty()).expect(&write_err_msg);
+                    let r = result.len();
+                    buf[0..r].copy_from_slice(result.as_bytes());
+                    buf[r..r + 2].copy_from_slice(b"\r\n");
+                    reply(&buf);
                     replied = true;
                 }
             };
@@ -126,27 +392,50 @@ extern "C" fn handle_client(arg: *mut c_void) -> *mut c_void {
                 let r = result.len();
                 buf[0..r].copy_from_slice(result.as_bytes());
                 buf[r..r + 2].copy_from_slice(b"\r\n");
-                send(args.clientfd, &mut buf, MsgFlags::empty()).expect(&write_err_msg);
+                reply(&buf);
             }
         }
-        nbytes = recv(args.clientfd, &mut buf, MsgFlags::empty()).expect(&read_err_msg);
+        nbytes = receive(&mut buf);
     }
 
     println!(
         "Disconnected from client: {:#?} -> {:#?}",
-        args.clientfd, args.filepath
+        args.clientfd,
+        args.store.filepath()
     );
     ptr::null_mut()
 }
 
+/// When the server compacts the data file of its own accord, in a background thread
+///
+/// A run starts as soon as the garbage exceeds either threshold (of those which are set),
+/// but never sooner than `min_interval` after the previous run started.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AutoCompact {
+    pub dead_ratio: Option<f64>, // dead bytes, as a fraction of all of them
+    pub dead_bytes: Option<u64>,
+    pub min_interval: Duration,
+}
+
+impl AutoCompact {
+    pub fn is_due(&self, garbage: &Garbage) -> bool {
+        self.dead_ratio.is_some_and(|ratio| garbage.ratio() > ratio)
+            || self
+                .dead_bytes
+                .is_some_and(|bytes| garbage.dead_bytes > bytes)
+    }
+}
+
 #[derive(Debug)]
 pub struct Server {
     pub port: u16,
     pub filepath: String,
+    pub options: StoreOptions,
+    pub auto_compact: Option<AutoCompact>,
 }
 
 impl Server {
-    pub fn start(&self) -> Result<(), Errno> {
+    pub fn start(&self) -> Result<(), Error> {
         // Create the server socket
         let fd = socket(
             AddressFamily::Inet,
@@ -164,56 +453,101 @@ impl Server {
         listen(&fd, Backlog::MAXALLOWABLE)?;
         println!("Server listening on {:#?} -> {:#?}", self.port, sockfd);
 
+        // Index the data file once, up front, and share it with every client thread
+        let store = Arc::new(Store::open_with(self.filepath.clone(), self.options)?);
+        spawn_compactor(Arc::downgrade(&store), self.auto_compact);
+
         // Accept and handle incoming connections
-        self.handle(sockfd);
+        self.handle(sockfd, store);
 
         Ok(())
     }
 
-    fn handle(&self, sockfd: RawFd) {
+    fn handle(&self, sockfd: RawFd, store: Arc<Store>) {
+        // (compactions are the compactor thread's business, so nobody waits on them to get served)
         let mut connection = accept(sockfd);
-        while connection.is_ok() {
-            // Handle any pending compaction requests first
-            if COMPACT_SIGNALED.load(Ordering::Relaxed) {
-                println!("Compacting {:#?} -- please wait", self.filepath.clone());
-                match compact(self.filepath.clone()) {
-                    Ok(_) => println!("Compacting {:#?} -- completed", self.filepath.clone()),
-                    Err(e) => {
-                        println!("Compacting {:#?} -- error {:#?}", self.filepath.clone(), e)
-                    }
-                }
-            } else {
-                // Create a new pthread for each successful client connection
-                let args = ClientThreadArgs {
-                    clientfd: connection.unwrap(),
-                    filepath: self.filepath.clone(),
-                };
+        while let Ok(clientfd) = connection {
+            // Create a new pthread for each successful client connection
+            let args = ClientThreadArgs {
+                clientfd,
+                store: Arc::clone(&store),
+            };
 
-                // Box the arguments to the client thread, so they do not go out of scope
-                let arg_ptr = Box::into_raw(Box::new(args));
-
-                let mut thread_id: pthread_t = unsafe { mem::zeroed() };
-                let create_result = unsafe {
-                    pthread_create(
-                        &mut thread_id,
-                        ptr::null(),
-                        handle_client,
-                        arg_ptr as *mut c_void,
-                    )
-                };
+            // Box the arguments to the client thread, so they do not go out of scope
+            let arg_ptr = Box::into_raw(Box::new(args));
 
-                if create_result != 0 {
-                    eprintln!("Error creating thread: {}", create_result);
-                }
+            let mut thread_id: pthread_t = unsafe { mem::zeroed() };
+            let create_result = unsafe {
+                pthread_create(
+                    &mut thread_id,
+                    ptr::null(),
+                    handle_client,
+                    arg_ptr as *mut c_void,
+                )
+            };
 
-                // Let the newly-created thread run to completion
-                unsafe {
-                    pthread_detach(thread_id);
-                }
+            if create_result != 0 {
+                eprintln!("Error creating thread: {}", create_result);
+            }
 
-                // Accept any new client connections
-                connection = accept(sockfd);
+            // Let the newly-created thread run to completion
+            unsafe {
+                pthread_detach(thread_id);
             }
+
+            // Accept any new client connections
+            connection = accept(sockfd);
         }
     }
 }
+
+// how often the compactor checks for a compaction request (SIGUSR2)
+const COMPACTOR_TICK: Duration = Duration::from_millis(50);
+
+// the compactor thread: compact whenever SIGUSR2 asks for it, and (with `AutoCompact`) whenever
+// the garbage says it is due, for as long as the store is around
+fn spawn_compactor(store: Weak<Store>, policy: Option<AutoCompact>) {
+    thread::spawn(move || {
+        let mut last_run: Option<Instant> = None;
+        let mut last_check = Instant::now();
+        loop {
+            thread::sleep(COMPACTOR_TICK);
+            let Some(store) = store.upgrade() else {
+                break;
+            };
+            // (taking the request, so that one which arrives mid-compaction gets a run of its own)
+            if COMPACT_SIGNALED.swap(false, Ordering::Relaxed) {
+                println!("Compacting {:#?} -- please wait", store.filepath());
+            } else {
+                let Some(policy) = policy else {
+                    continue;
+                };
+                let poll = policy
+                    .min_interval
+                    .clamp(Duration::from_millis(10), Duration::from_secs(1));
+                if last_check.elapsed() < poll
+                    || last_run.is_some_and(|started| started.elapsed() < policy.min_interval)
+                {
+                    continue;
+                }
+                last_check = Instant::now();
+                let garbage = store.garbage();
+                if !policy.is_due(&garbage) {
+                    continue;
+                }
+                println!(
+                    "Compacting {:#?} -- {} of {} bytes are garbage",
+                    store.filepath(),
+                    garbage.dead_bytes,
+                    garbage.live_bytes + garbage.dead_bytes
+                );
+            }
+
+            last_run = Some(Instant::now());
+            match store.compact() {
+                Ok(_) => println!("Compacting {:#?} -- completed", store.filepath()),
+                Err(e) => println!("Compacting {:#?} -- error {:#?}", store.filepath(), e),
+            }
+        }
+    });
+}
@@ -0,0 +1,1330 @@
+use crate::batch::{Batch, BatchOp};
+use crate::durability::{Durability, GroupCommit, WriteQueue};
+use crate::error::Error;
+use crate::file_syscalls::random_seed;
+use crate::file_syscalls::{
+    append_new_key_val, append_records, compact_file, delete, file_mode, file_stats, find_record,
+    index_records, mark_deleted, open_locked, read_record, release, remove_orphans,
+    remove_orphans_in, sync_dir, truncate_torn_tail, unlock,
+};
+use crate::format::{
+    FileHeader, RecordHeader, RecordKind, encode_marker, encode_record, encode_tombstone,
+};
+use crate::hasher::HasherKind;
+use crate::keydir::{Keydir, KeydirEntry};
+use crate::scan::{SCAN_START, Scan, glob_match};
+use crate::segments::{
+    Segment, Segments, index_segment, list_segments, lock_dir, lock_merges, segment_path,
+    write_merged,
+};
+use chrono::Utc;
+use log::warn;
+use nix::errno::Errno;
+use nix::fcntl::{AT_FDCWD, Flock, FlockArg, OFlag, open, renameat};
+use nix::sys::stat::{Mode, fstat};
+use nix::unistd::{close, fsync, unlink};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::os::fd::{AsFd, BorrowedFd, OwnedFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::thread;
+use std::time::Duration;
+
+/// A data file, plus the in-memory keydir index of where each live key is stored in it
+///
+/// The keydir is built once, when the store is opened, and then kept current on every
+/// append, delete and compaction, so lookups `pread` the value directly instead of
+/// scanning the whole file. Share a single `Store` between threads (e.g. in an `Arc`).
+///
+/// With a segment size set, `filepath` is a directory of segments instead (see `segments.rs`),
+/// with a keydir for each of them: lookups try the active one first, then the older ones,
+/// newest first, until one of them has a record of the key.
+#[derive(Debug)]
+pub struct Store {
+    filepath: String,
+    keydir: Mutex<Keydir>, // for the data file, or the active segment
+    segments: Mutex<Segments>,
+    segment_size: Option<u64>,
+    new_file_header: FileHeader,
+    durability: Durability,
+    commit: Arc<GroupCommit>,
+    puts: WriteQueue<Put>,
+    dir_synced: AtomicBool, // whether the directory entry is known to be on the disk
+}
+
+// a set, queued up to be written out together with any others made at the same time
+#[derive(Debug)]
+struct Put {
+    key: String,
+    val: Vec<u8>,
+    expires_at: Option<i64>,
+}
+
+/// How long a key has left to live, as reported by `Store::ttl()`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Ttl {
+    Missing, // no such key (or it has already expired)
+    Forever, // the key has no expiry time
+    Seconds(u64),
+}
+
+/// Settings for a `Store`
+///
+/// The hash algorithm only applies when the store is the one to create the data file:
+/// an existing file keeps whatever its header says.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StoreOptions {
+    pub hasher: HasherKind,
+    pub durability: Durability,
+    /// Keep the data in a directory of segments, starting a new one when the active one reaches this many bytes
+    pub segment_size: Option<u64>,
+    /// Only open the store to read from it: with a shared lock, so that readers in other processes
+    /// need not take turns, and without needing write permission on the data file (which leaves
+    /// clearing up after a crash to the next writer)
+    pub read_only: bool,
+}
+
+/// How many bytes of the data on disk belong to live records, and how many are garbage, for `Store::compact()` to reclaim
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Garbage {
+    pub live_bytes: u64,
+    pub dead_bytes: u64,
+}
+
+impl Garbage {
+    /// The dead bytes, as a fraction of all of them (0 with no data at all)
+    pub fn ratio(&self) -> f64 {
+        match self.live_bytes + self.dead_bytes {
+            0 => 0.0,
+            total => self.dead_bytes as f64 / total as f64,
+        }
+    }
+}
+
+/// What is in the data file (or all the segments), record by record, as counted by `Store::stats()`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Stats {
+    pub records: u64,       // all of them, tombstones and batch markers included
+    pub live: u64,          // those holding the current value of a key
+    pub deleted: u64,       // values with the deleted flag set, and tombstones
+    pub live_bytes: u64,    // in the live records
+    pub dead_bytes: u64,    // in all the others
+    pub largest_value: u64, // of the live records
+    pub file_size: u64,     // on the disk, file headers included
+}
+
+impl Stats {
+    /// The same numbers, as a JSON object on a single line
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"records\":{},\"live\":{},\"deleted\":{},\"live_bytes\":{},\"dead_bytes\":{},\"largest_value\":{},\"file_size\":{}}}",
+            self.records,
+            self.live,
+            self.deleted,
+            self.live_bytes,
+            self.dead_bytes,
+            self.largest_value,
+            self.file_size
+        )
+    }
+
+    fn add(&mut self, other: &Stats) {
+        self.records += other.records;
+        self.live += other.live;
+        self.deleted += other.deleted;
+        self.live_bytes += other.live_bytes;
+        self.dead_bytes += other.dead_bytes;
+        self.largest_value = self.largest_value.max(other.largest_value);
+        self.file_size += other.file_size;
+    }
+}
+
+// the count a value holds, for `incr()`
+fn parse_count(value: &[u8]) -> Result<i64, Error> {
+    std::str::from_utf8(value)
+        .ok()
+        .and_then(|count| count.parse::<i64>().ok())
+        .ok_or(Error::NotInteger)
+}
+
+// what an operation holds for as long as it runs: the flock, and the file to read and append to
+struct Locked {
+    lock: Flock<OwnedFd>,    // on the data file, or on the directory of segments
+    active: Option<OwnedFd>, // the active segment, if there are segments
+    path: String,            // of the data file, or the active segment
+}
+
+impl Locked {
+    fn fd(&self) -> BorrowedFd<'_> {
+        match &self.active {
+            Some(fd) => fd.as_fd(),
+            None => self.lock.as_fd(),
+        }
+    }
+
+    fn release(self) -> Result<(), Errno> {
+        if let Some(fd) = self.active {
+            close(fd)?;
+        }
+        release(self.lock)
+    }
+
+    // let go of the lock, but keep the file open
+    fn unlock(self) -> Result<(OwnedFd, String), Errno> {
+        match self.active {
+            Some(fd) => {
+                release(self.lock)?;
+                Ok((fd, self.path))
+            }
+            None => Ok((unlock(self.lock)?, self.path)),
+        }
+    }
+}
+
+impl Store {
+    pub fn open(filepath: String) -> Result<Store, Error> {
+        Store::open_with(filepath, StoreOptions::default())
+    }
+
+    pub fn open_with(filepath: String, options: StoreOptions) -> Result<Store, Error> {
+        // the seed is only used by the keyed hash algorithms, but pick one regardless
+        let new_file_header = FileHeader::with_hasher(options.hasher.id(), random_seed()?);
+        let store = Store {
+            filepath,
+            keydir: Mutex::new(Keydir::default()),
+            segments: Mutex::new(Segments::default()),
+            segment_size: options.segment_size,
+            new_file_header,
+            durability: options.durability,
+            commit: Arc::new(GroupCommit::default()),
+            puts: WriteQueue::default(),
+            dir_synced: AtomicBool::new(false),
+        };
+        if let Durability::Interval(millis) = store.durability {
+            spawn_flusher(&store, millis);
+        }
+
+        // a compaction interrupted by a crash leaves its temp file behind, for the next open to clear up
+        let removed = match (store.segment_size, options.read_only) {
+            (_, true) => Ok(0), // (left for a writer)
+            (Some(_), false) => remove_orphans_in(&store.filepath, |_| true),
+            (None, false) => remove_orphans(&store.filepath),
+        };
+        match removed {
+            Ok(_) | Err(Errno::ENOENT) => (),
+            Err(e) => warn!(
+                "{}: failed to remove orphaned temp files: {e}",
+                store.filepath
+            ),
+        }
+
+        // build the keydir now (unless there is no data file yet), and while at it, unless only
+        // reading, clean up after any append which a crash left incomplete at the end of the file
+        let (oflag, arg) = match options.read_only {
+            true => (OFlag::O_RDONLY, FlockArg::LockShared),
+            false => (OFlag::O_RDWR, FlockArg::LockExclusive),
+        };
+        match store.lock(oflag, arg) {
+            Ok(locked) => {
+                {
+                    let mut keydir = store.keydir();
+                    index_records(&locked.path, &locked.fd(), &mut keydir)?;
+                    if !options.read_only {
+                        truncate_torn_tail(&locked.path, &locked.fd(), &keydir)?;
+                    }
+                }
+                locked.release()?;
+            }
+            Err(Error::Sys(Errno::ENOENT)) => (),
+            Err(e) => return Err(e),
+        }
+
+        Ok(store)
+    }
+
+    pub fn filepath(&self) -> &str {
+        &self.filepath
+    }
+
+    fn keydir(&self) -> MutexGuard<'_, Keydir> {
+        self.keydir.lock().unwrap()
+    }
+
+    // (always taken before the keydir, when both are needed at once)
+    fn segments(&self) -> MutexGuard<'_, Segments> {
+        self.segments.lock().unwrap()
+    }
+
+    // take the lock for an operation: on the data file, or on the directory of segments, then opening the active one
+    fn lock(&self, oflag: OFlag, arg: FlockArg) -> Result<Locked, Error> {
+        let Some(segment_size) = self.segment_size else {
+            let lock = open_locked(&self.filepath, oflag, arg)?;
+            return Ok(Locked {
+                lock,
+                active: None,
+                path: self.filepath.clone(),
+            });
+        };
+        let lock = lock_dir(&self.filepath, oflag.contains(OFlag::O_CREAT), arg)?;
+        let writing = matches!(arg, FlockArg::LockExclusive);
+        match self.open_active(oflag, writing, segment_size) {
+            Ok((fd, path)) => Ok(Locked {
+                lock,
+                active: Some(fd),
+                path,
+            }),
+            Err(e) => {
+                release(lock)?;
+                Err(e)
+            }
+        }
+    }
+
+    // (under the lock on the directory) catch up on whatever anyone else rolled over or merged, start
+    // a new segment if this is a write and the active one is full, then open the active one
+    fn open_active(
+        &self,
+        oflag: OFlag,
+        writing: bool,
+        segment_size: u64,
+    ) -> Result<(OwnedFd, String), Error> {
+        let mut segments = self.segments();
+        self.catch_up(&mut segments)?;
+        let filepath = segment_path(&self.filepath, segments.active);
+        let fd: OwnedFd = open(filepath.as_str(), oflag, file_mode())?;
+        if !writing || (fstat(fd.as_fd())?.st_size as u64) < segment_size {
+            return Ok((fd, filepath));
+        }
+
+        // the full segment becomes immutable: complete (less any torn tail), and on the disk, which
+        // also covers any write to it still waiting on a group commit
+        let mut keydir = self.keydir();
+        let finished = index_records(&filepath, &fd.as_fd(), &mut keydir)
+            .and_then(|_| truncate_torn_tail(&filepath, &fd.as_fd(), &keydir))
+            .and_then(|_| Ok(fsync(fd.as_fd())?));
+        close(fd)?;
+        finished?;
+
+        let number = segments.active + 1;
+        let next = segment_path(&self.filepath, number);
+        let next_fd: OwnedFd = open(next.as_str(), oflag | OFlag::O_CREAT, file_mode())?;
+        let full = Segment {
+            number: segments.active,
+            keydir: std::mem::take(&mut *keydir),
+        };
+        segments.older.push(full);
+        segments.active = number;
+        // a new file, whose directory entry has to be synced along with the first write to it
+        self.dir_synced.store(false, Ordering::Relaxed);
+        Ok((next_fd, next))
+    }
+
+    // bring `segments` in line with the directory, should anyone else have rolled over or merged any
+    fn catch_up(&self, segments: &mut Segments) -> Result<(), Error> {
+        let listed = list_segments(&self.filepath)?;
+        let Some(&(active, _)) = listed.last() else {
+            // nothing written yet: the first write creates the first segment
+            segments.active = 1;
+            segments.older.clear();
+            return Ok(());
+        };
+        let older = &listed[..listed.len() - 1];
+
+        // (leaving out anything below the merged segment, which a crash kept a merge from removing)
+        let floor = match segments.older.first() {
+            Some(segment) if segment.keydir.file_header.is_merged() => segment.number,
+            _ => 0,
+        };
+        let known: Vec<(u64, u64)> = segments
+            .older
+            .iter()
+            .map(|segment| (segment.number, segment.keydir.ino))
+            .collect();
+        let current: Vec<(u64, u64)> = older.iter().filter(|(n, _)| *n >= floor).copied().collect();
+        if active == segments.active && current == known {
+            return Ok(());
+        }
+
+        let mut indexed: HashMap<u64, Segment> = segments
+            .older
+            .drain(..)
+            .map(|segment| (segment.number, segment))
+            .collect();
+        for &(number, ino) in older.iter().rev() {
+            let segment = match indexed.remove(&number) {
+                Some(segment) if segment.keydir.ino == ino => segment,
+                _ => index_segment(&self.filepath, number)?,
+            };
+            let merged = segment.keydir.file_header.is_merged();
+            segments.older.insert(0, segment);
+            if merged {
+                break; // anything below was merged into this one
+            }
+        }
+        segments.active = active;
+        Ok(())
+    }
+
+    // catch up on anything written to the file since the last call, then find the key
+    fn lookup(
+        &self,
+        locked: &Locked,
+        key: &str,
+    ) -> Result<(String, Option<KeydirEntry>, FileHeader), Error> {
+        let file_header = self.catch_up_index(locked)?;
+        let hash = file_header.key_hasher().hash_key(key);
+        let entry = self.keydir().get(&hash);
+        Ok((hash, entry, file_header))
+    }
+
+    // index anything written to the file since the last call, returning the header keys are hashed by
+    fn catch_up_index(&self, locked: &Locked) -> Result<FileHeader, Error> {
+        // (a new segment carries on with the header of the one before it)
+        let inherited = self
+            .segments()
+            .older
+            .last()
+            .map(|segment| segment.keydir.file_header);
+        let mut keydir = self.keydir();
+        index_records(&locked.path, &locked.fd(), &mut keydir)?;
+        if keydir.indexed_len == 0 {
+            // no data yet, so the next append creates the file, with this store's settings
+            keydir.file_header = inherited.unwrap_or(self.new_file_header);
+        }
+        Ok(keydir.file_header)
+    }
+
+    // for a key with no record in the data file (or active segment): its live record in the newest older
+    // segment with any record of it, if there are segments
+    fn find_older(&self, hash: &str, key: &str) -> Result<Option<(RecordHeader, Vec<u8>)>, Error> {
+        if self.keydir().is_deleted(hash) {
+            return Ok(None);
+        }
+        let found =
+            self.segments()
+                .older
+                .iter()
+                .rev()
+                .find_map(|segment| match segment.keydir.get(hash) {
+                    Some(entry) => Some(Some((segment.number, entry, segment.keydir.file_header))),
+                    None if segment.keydir.is_deleted(hash) => Some(None),
+                    None => None,
+                });
+        let Some(Some((number, entry, file_header))) = found else {
+            return Ok(None);
+        };
+
+        // the lock on the directory keeps any merge from swapping the segment out in the meantime
+        let filepath = segment_path(&self.filepath, number);
+        let fd: OwnedFd = open(filepath.as_str(), OFlag::O_RDONLY, Mode::empty())?;
+        let found = find_record(&fd.as_fd(), &file_header, &entry, key);
+        close(fd)?;
+        found
+    }
+
+    // let go of the exclusive lock after a write (if any), then wait until the policy says it is durable
+    fn release_written(&self, locked: Locked, wrote: bool) -> Result<(), Error> {
+        if !wrote || self.durability == Durability::Never {
+            return Ok(locked.release()?);
+        }
+        let ticket = self.commit.written();
+        // unlocked first, so that other writers can go ahead (and share the fsync) in the meantime
+        let (fd, filepath) = locked.unlock()?;
+        let synced = match self.durability {
+            Durability::Always => self.sync(&filepath, &fd.as_fd(), ticket),
+            _ => Ok(()), // up to the flusher thread
+        };
+        close(fd)?;
+        synced
+    }
+
+    fn sync(&self, filepath: &str, fd: &BorrowedFd, ticket: u64) -> Result<(), Error> {
+        // the write may well have created the file, so its directory entry needs to be on the disk too
+        if !self.dir_synced.load(Ordering::Relaxed) {
+            sync_dir(filepath)?;
+            self.dir_synced.store(true, Ordering::Relaxed);
+        }
+        Ok(self.commit.sync(fd, ticket)?)
+    }
+
+    /// How many times the data file has been fsynced, which (thanks to group commit) can be
+    /// fewer than the number of writes
+    pub fn syncs(&self) -> u64 {
+        self.commit.syncs()
+    }
+
+    /// Look `key` up, returning its value if there is a live record for it
+    ///
+    /// Fails with `Error::KeyCollision` if the record found is for another key of the same hash
+    /// (which only gets noticed in data files which store the original keys).
+    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        Ok(self.get_record(key)?.map(|(_, value)| value))
+    }
+
+    // the header of the live record for `key`, as well as its value
+    fn get_record(&self, key: &str) -> Result<Option<(RecordHeader, Vec<u8>)>, Error> {
+        let locked = self.lock(OFlag::O_RDONLY, FlockArg::LockShared)?;
+
+        let result = self
+            .lookup(&locked, key)
+            .and_then(|(hash, entry, file_header)| match entry {
+                Some(entry) => {
+                    let found = find_record(&locked.fd(), &file_header, &entry, key);
+                    if let Ok(None) = found {
+                        // deleted in place (by another process) since it was indexed, or expired
+                        self.keydir().remove_at(&hash, entry.offset);
+                    }
+                    found
+                }
+                None => self.find_older(&hash, key),
+            });
+
+        locked.release()?;
+        result
+    }
+
+    /// Look up all of `keys` at once, returning their values in the same order
+    ///
+    /// Everything happens under one lock, with one catch-up of the keydir: the records it points
+    /// to are then read in the order they sit in the file, in a single pass from front to back.
+    pub fn mget(&self, keys: &[&str]) -> Result<Vec<Option<Vec<u8>>>, Error> {
+        let locked = self.lock(OFlag::O_RDONLY, FlockArg::LockShared)?;
+        let result = self.mget_locked(&locked, keys);
+        locked.release()?;
+        result
+    }
+
+    fn mget_locked(&self, locked: &Locked, keys: &[&str]) -> Result<Vec<Option<Vec<u8>>>, Error> {
+        let file_header = self.catch_up_index(locked)?;
+        let mut values: Vec<Option<Vec<u8>>> = vec![None; keys.len()];
+        let mut entries: Vec<(usize, String, KeydirEntry)> = Vec::new();
+        let key_hasher = file_header.key_hasher();
+        for (i, key) in keys.iter().enumerate() {
+            let hash = key_hasher.hash_key(key);
+            let entry = self.keydir().get(&hash);
+            match entry {
+                Some(entry) => entries.push((i, hash, entry)),
+                None => values[i] = self.find_older(&hash, key)?.map(|(_, value)| value),
+            }
+        }
+
+        entries.sort_by_key(|(_, _, entry)| entry.offset);
+        for (i, hash, entry) in entries {
+            match find_record(&locked.fd(), &file_header, &entry, keys[i])? {
+                Some((_, value)) => values[i] = Some(value),
+                // deleted in place (by another process) since it was indexed, or expired
+                None => self.keydir().remove_at(&hash, entry.offset),
+            }
+        }
+        Ok(values)
+    }
+
+    /// How many of `keys` have a live value (a key given twice counts twice)
+    pub fn exists(&self, keys: &[&str]) -> Result<usize, Error> {
+        Ok(self
+            .mget(keys)?
+            .iter()
+            .filter(|value| value.is_some())
+            .count())
+    }
+
+    /// Set every key in `pairs` to its value, all or none of them, as one batch (see `apply()`)
+    pub fn mset(&self, pairs: &[(&str, &[u8])]) -> Result<usize, Error> {
+        let mut batch = Batch::new();
+        for (key, val) in pairs {
+            batch.set(key, val);
+        }
+        self.apply(&batch)
+    }
+
+    /// Every live key which matches the glob `pattern`, in order
+    ///
+    /// This reads all of them under one lock, which is fine for a small store; for a big one,
+    /// `scan()` takes the lock a page at a time instead.
+    pub fn keys(&self, pattern: &str) -> Result<Vec<String>, Error> {
+        let (_, mut keys) = self.scan_page(SCAN_START, pattern, usize::MAX)?;
+        keys.sort();
+        Ok(keys)
+    }
+
+    /// Iterate over the live keys which match the glob `pattern`, reading `count` records a page
+    ///
+    /// Each page takes the lock only while it reads, so writes carry on in between. A key which
+    /// stays live for the whole scan is returned exactly once, but one written or deleted in the
+    /// meantime may or may not be (see `scan.rs`).
+    pub fn scan(&self, pattern: &str, count: usize) -> Scan<'_> {
+        Scan::new(self, pattern, count)
+    }
+
+    /// One page of a scan: the keys which match `pattern`, out of the next `count` live records
+    /// after `cursor`, along with the cursor to carry on from (`"0"` to start, and once done)
+    pub fn scan_page(
+        &self,
+        cursor: &str,
+        pattern: &str,
+        count: usize,
+    ) -> Result<(String, Vec<String>), Error> {
+        let count = count.max(1);
+        let locked = match self.lock(OFlag::O_RDONLY, FlockArg::LockShared) {
+            Ok(locked) => locked,
+            Err(Error::Sys(Errno::ENOENT)) => return Ok((String::from(SCAN_START), vec![])), // no data yet
+            Err(e) => return Err(e),
+        };
+        let result = self.scan_locked(&locked, cursor, pattern, count);
+        locked.release()?;
+        result
+    }
+
+    fn scan_locked(
+        &self,
+        locked: &Locked,
+        cursor: &str,
+        pattern: &str,
+        count: usize,
+    ) -> Result<(String, Vec<String>), Error> {
+        let file_header = self.catch_up_index(locked)?;
+        if !file_header.has_keys() {
+            return Err(Error::NoKeys);
+        }
+
+        // the first live records after the cursor, in the order of their hashes: no more than one past
+        // a page from each keydir (the data file's, or active segment's, then the older segments',
+        // newest first), leaving out those a newer file has the last word on, so that the first of
+        // them all are the first overall, and the one past the page tells whether there are more
+        let mut live: BTreeMap<String, (Option<u64>, KeydirEntry, FileHeader)> = BTreeMap::new();
+        {
+            let segments = self.segments();
+            let keydir = self.keydir();
+            for (hash, entry) in keydir.after(cursor).take(count.saturating_add(1)) {
+                live.insert(hash.clone(), (None, *entry, file_header));
+            }
+            for (i, segment) in segments.older.iter().enumerate().rev() {
+                let newer = &segments.older[i + 1..];
+                let found = segment.keydir.after(cursor).filter(|(hash, _)| {
+                    !keydir.has_record(hash) && !newer.iter().any(|s| s.keydir.has_record(hash))
+                });
+                for (hash, entry) in found.take(count.saturating_add(1)) {
+                    let found = (Some(segment.number), *entry, segment.keydir.file_header);
+                    live.insert(hash.clone(), found);
+                }
+            }
+        }
+        let next = match live.len() > count {
+            true => live.keys().nth(count - 1).cloned().unwrap_or_default(),
+            false => String::from(SCAN_START),
+        };
+
+        let mut keys: Vec<String> = Vec::new();
+        let mut fds: HashMap<u64, OwnedFd> = HashMap::new();
+        for (hash, (number, entry, file_header)) in live.into_iter().take(count) {
+            let record = match number {
+                None => read_record(&locked.fd(), &file_header, entry.offset),
+                Some(number) => {
+                    let fd = match fds.entry(number) {
+                        Entry::Occupied(opened) => opened.into_mut(),
+                        Entry::Vacant(vacant) => {
+                            let filepath = segment_path(&self.filepath, number);
+                            vacant.insert(open(filepath.as_str(), OFlag::O_RDONLY, Mode::empty())?)
+                        }
+                    };
+                    read_record(&fd.as_fd(), &file_header, entry.offset)
+                }
+            };
+            match record? {
+                Some((header, body)) => {
+                    let key = String::from_utf8_lossy(header.split(&body).0);
+                    if glob_match(pattern, &key) {
+                        keys.push(key.into_owned());
+                    }
+                }
+                // deleted in place (by another process) since it was indexed, or expired
+                None if number.is_none() => self.keydir().remove_at(&hash, entry.offset),
+                None => (),
+            }
+        }
+        for (_, fd) in fds {
+            close(fd)?;
+        }
+        Ok((next, keys))
+    }
+
+    pub fn delete(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        let locked = self.lock(OFlag::O_RDWR, FlockArg::LockExclusive)?;
+        let result = self.delete_locked(&locked, key);
+        self.release_written(locked, matches!(result, Ok(Some(_))))?;
+        result
+    }
+
+    fn delete_locked(&self, locked: &Locked, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        let fd = locked.fd();
+        let (hash, entry, file_header) = self.lookup(locked, key)?;
+        if self.segment_size.is_none() {
+            return match entry {
+                Some(entry) => {
+                    let deleted = delete(&fd, &file_header, &entry, key);
+                    if deleted.is_ok() {
+                        self.keydir().remove_at(&hash, entry.offset);
+                    }
+                    deleted
+                }
+                None => Ok(None),
+            };
+        }
+
+        // the older segments never change, so deleting from them means appending a tombstone
+        let current = match entry {
+            Some(entry) => find_record(&fd, &file_header, &entry, key)?,
+            None => self.find_older(&hash, key)?,
+        };
+        let Some((_, value)) = current else {
+            return Ok(None);
+        };
+        if !file_header.has_batches() {
+            return Err(Error::NoBatches);
+        }
+        truncate_torn_tail(&locked.path, &fd, &self.keydir())?;
+        let tombstone = encode_tombstone(&file_header, &hash, key.as_bytes());
+        let (offset, nbytes) = append_records(&fd, &file_header, &tombstone)?;
+        let mut keydir = self.keydir();
+        keydir.remove(&hash);
+        keydir.indexed_len = offset + nbytes as u64;
+        Ok(Some(value))
+    }
+
+    pub fn set(&self, key: &str, val: &[u8]) -> Result<usize, Error> {
+        self.put(key, val, None)
+    }
+
+    /// Set `key` to `val`, to expire (and read as missing) `seconds` from now
+    pub fn set_ex(&self, key: &str, val: &[u8], seconds: u64) -> Result<usize, Error> {
+        let expires_at = Utc::now().timestamp().saturating_add(seconds as i64);
+        self.put(key, val, Some(expires_at))
+    }
+
+    fn put(&self, key: &str, val: &[u8], expires_at: Option<i64>) -> Result<usize, Error> {
+        if self.durability == Durability::Always {
+            // sets made at the same time (e.g. by the server's clients) go out in one write, and
+            // then one fsync, rather than each waiting its turn for the lock and the disk
+            let put = Put {
+                key: String::from(key),
+                val: val.to_vec(),
+                expires_at,
+            };
+            return self.puts.submit(put, |puts| self.put_all(puts));
+        }
+        self.update(key, expires_at.is_some(), |current| match current {
+            // key already exists, so only upsert if the value (or expiry time) is different
+            Some((header, value)) if value == val && header.expires_at == expires_at => Ok(None),
+            _ => Ok(Some((val.to_vec(), expires_at))),
+        })
+    }
+
+    // write out all of `puts` under one lock, returning the number of bytes each appended
+    fn put_all(&self, puts: &[Put]) -> Vec<Result<usize, Error>> {
+        let locked = match self.lock(OFlag::O_RDWR | OFlag::O_CREAT, FlockArg::LockExclusive) {
+            Ok(locked) => locked,
+            Err(e) => return puts.iter().map(|_| Err(e)).collect(),
+        };
+        let results = self.put_all_locked(&locked, puts);
+        let wrote = results
+            .iter()
+            .any(|result| matches!(result, Ok(n) if *n > 0));
+        match self.release_written(locked, wrote) {
+            Ok(()) => results,
+            Err(e) => results
+                .into_iter()
+                .map(|result| result.and(Err(e)))
+                .collect(),
+        }
+    }
+
+    // as `update_locked()` for each of `puts`, but with all their records appended in one write
+    fn put_all_locked(&self, locked: &Locked, puts: &[Put]) -> Vec<Result<usize, Error>> {
+        let fd = &locked.fd();
+        // a crash in some other process may have left an incomplete record at the end
+        let file_header = match self
+            .catch_up_index(locked)
+            .and_then(|header| truncate_torn_tail(&locked.path, fd, &self.keydir()).map(|_| header))
+        {
+            Ok(file_header) => file_header,
+            Err(e) => return puts.iter().map(|_| Err(e)).collect(),
+        };
+
+        let mut results: Vec<Result<usize, Error>> = Vec::new();
+        let mut records: Vec<u8> = Vec::new();
+        let mut replaced: Vec<u64> = Vec::new(); // offsets of the records to flag as deleted afterwards
+        let mut superseded: Vec<u64> = Vec::new(); // the same, for records earlier on in `records`
+        let mut changes: Vec<(String, KeydirEntry)> = Vec::new(); // with offsets into `records`
+        let mut earlier: HashMap<String, (usize, u64)> = HashMap::new(); // hash -> (put, offset into `records`)
+        let key_hasher = file_header.key_hasher();
+        for (i, put) in puts.iter().enumerate() {
+            if put.expires_at.is_some() && !file_header.has_expiry() {
+                results.push(Err(Error::NoExpiry));
+                continue;
+            }
+            let hash = key_hasher.hash_key(&put.key);
+
+            // key already exists (in the file, or earlier on in this write), so only upsert if the
+            // value (or expiry time) is different
+            match earlier.get(&hash) {
+                Some(&(j, _)) if puts[j].key != put.key && file_header.has_keys() => {
+                    results.push(Err(Error::KeyCollision));
+                    continue;
+                }
+                Some(&(j, _)) if puts[j].val == put.val && puts[j].expires_at == put.expires_at => {
+                    results.push(Ok(0));
+                    continue;
+                }
+                Some(&(_, at)) => superseded.push(at),
+                None => {
+                    let entry = self.keydir().get(&hash);
+                    let current = match entry {
+                        Some(entry) => find_record(fd, &file_header, &entry, &put.key),
+                        None => self.find_older(&hash, &put.key),
+                    };
+                    match (current, entry) {
+                        (Err(e), _) => {
+                            results.push(Err(e));
+                            continue;
+                        }
+                        (Ok(Some((header, value))), _)
+                            if value == put.val && header.expires_at == put.expires_at =>
+                        {
+                            results.push(Ok(0));
+                            continue;
+                        }
+                        // (a record in an older segment stays as it is, since the new one supersedes it anyway)
+                        (Ok(Some(_)), Some(entry)) => replaced.push(entry.offset),
+                        _ => (),
+                    }
+                }
+            }
+
+            let record = encode_record(
+                &file_header,
+                &hash,
+                put.key.as_bytes(),
+                &put.val,
+                put.expires_at,
+            );
+            let entry = KeydirEntry {
+                offset: records.len() as u64,
+                size: put.val.len(),
+                len: record.len() as u64,
+            };
+            earlier.insert(hash.clone(), (i, entry.offset));
+            changes.push((hash, entry));
+            results.push(Ok(record.len()));
+            records.extend(record);
+        }
+        if records.is_empty() {
+            return results;
+        }
+
+        let appended = append_records(fd, &file_header, &records).and_then(|(offset, nbytes)| {
+            for old in replaced {
+                mark_deleted(fd, &file_header, old)?;
+            }
+            for old in superseded {
+                mark_deleted(fd, &file_header, offset + old)?;
+            }
+            Ok((offset, nbytes))
+        });
+        let (offset, nbytes) = match appended {
+            Ok(appended) => appended,
+            Err(e) => {
+                // (as for a single set, whatever did get written is picked up by the next lookup)
+                return results
+                    .into_iter()
+                    .map(|result| match result {
+                        Ok(n) if n > 0 => Err(e.into()),
+                        other => other,
+                    })
+                    .collect();
+            }
+        };
+        let mut keydir = self.keydir();
+        for (hash, entry) in changes {
+            keydir.insert(
+                hash,
+                KeydirEntry {
+                    offset: offset + entry.offset,
+                    ..entry
+                },
+            );
+        }
+        keydir.indexed_len = offset + nbytes as u64;
+        results
+    }
+
+    /// Set `key` to `new`, but only if its live value is still `expected`, returning whether it was
+    ///
+    /// A missing (or expired) key never matches. As with `set()`, the new value has no expiry time.
+    pub fn cas(&self, key: &str, expected: &[u8], new: &[u8]) -> Result<bool, Error> {
+        let mut matched = false;
+        self.update(key, false, |current| match current {
+            Some((header, value)) if value == expected => {
+                matched = true;
+                match value == new && header.expires_at.is_none() {
+                    true => Ok(None),
+                    false => Ok(Some((new.to_vec(), None))),
+                }
+            }
+            _ => Ok(None),
+        })?;
+        Ok(matched)
+    }
+
+    /// Add `delta` to the (signed, decimal) integer stored at `key`, returning the new count
+    ///
+    /// A missing (or expired) key counts from 0, and a key's expiry time (if any) is kept. Reading
+    /// and writing happen under the same lock, so concurrent increments never lose one another.
+    pub fn incr(&self, key: &str, delta: i64) -> Result<i64, Error> {
+        let mut count: i64 = 0;
+        self.update(key, false, |current| {
+            let (counted, expires_at) = match current {
+                Some((header, value)) => (parse_count(value)?, header.expires_at),
+                None => (0, None),
+            };
+            count = counted.checked_add(delta).ok_or(Error::NotInteger)?;
+            match current.is_some() && delta == 0 {
+                true => Ok(None),
+                false => Ok(Some((count.to_string().into_bytes(), expires_at))),
+            }
+        })?;
+        Ok(count)
+    }
+
+    /// Add `bytes` to the end of the value stored at `key`, returning the length of the whole value
+    ///
+    /// A missing (or expired) key starts out empty, and a key's expiry time (if any) is kept. The
+    /// new record is written in full, as for any other upsert, but without the caller reading first.
+    pub fn append(&self, key: &str, bytes: &[u8]) -> Result<usize, Error> {
+        self.append_within(key, bytes, usize::MAX)
+    }
+
+    /// As `append()`, but refusing (with `Error::TooLong`) to make the value longer than `limit`
+    /// bytes, checked under the same lock as the write
+    pub fn append_within(&self, key: &str, bytes: &[u8], limit: usize) -> Result<usize, Error> {
+        let mut len: usize = 0;
+        self.update(key, false, |current| {
+            let (mut value, expires_at) = match current {
+                Some((header, value)) => (value.clone(), header.expires_at),
+                None => (Vec::new(), None),
+            };
+            if value.len().saturating_add(bytes.len()) > limit {
+                return Err(Error::TooLong(limit));
+            }
+            value.extend_from_slice(bytes);
+            len = value.len();
+            match current.is_some() && bytes.is_empty() {
+                true => Ok(None),
+                false => Ok(Some((value, expires_at))),
+            }
+        })?;
+        Ok(len)
+    }
+
+    /// Subtract `delta` from the integer stored at `key`, as `incr()`
+    pub fn decr(&self, key: &str, delta: i64) -> Result<i64, Error> {
+        self.incr(key, delta.checked_neg().ok_or(Error::NotInteger)?)
+    }
+
+    /// Read, compare and write `key`, all under one exclusive lock on one fd
+    ///
+    /// `change` gets the live record for `key` (if any), and returns the value and expiry time
+    /// to replace it with, or `None` to leave it be. The new record is appended before the old
+    /// one is flagged as deleted, so a crash in between leaves the key with its old value, or
+    /// its new one (indexing lets later records win), but never without one. Returns the
+    /// number of bytes appended, i.e. 0 when nothing changed.
+    fn update<F>(&self, key: &str, with_expiry: bool, change: F) -> Result<usize, Error>
+    where
+        F: FnOnce(
+            Option<&(RecordHeader, Vec<u8>)>,
+        ) -> Result<Option<(Vec<u8>, Option<i64>)>, Error>,
+    {
+        // no O_APPEND, since the deleted flag gets written in place: appends seek to the end instead
+        let locked = self.lock(OFlag::O_RDWR | OFlag::O_CREAT, FlockArg::LockExclusive)?;
+        let result = self.update_locked(&locked, key, with_expiry, change);
+        self.release_written(locked, matches!(result, Ok(n) if n > 0))?;
+        result
+    }
+
+    fn update_locked<F>(
+        &self,
+        locked: &Locked,
+        key: &str,
+        with_expiry: bool,
+        change: F,
+    ) -> Result<usize, Error>
+    where
+        F: FnOnce(
+            Option<&(RecordHeader, Vec<u8>)>,
+        ) -> Result<Option<(Vec<u8>, Option<i64>)>, Error>,
+    {
+        let fd = &locked.fd();
+        let (hash, entry, file_header) = self.lookup(locked, key)?;
+        if with_expiry && !file_header.has_expiry() {
+            return Err(Error::NoExpiry);
+        }
+        // a crash in some other process may have left an incomplete record at the end
+        truncate_torn_tail(&locked.path, fd, &self.keydir())?;
+
+        let current = match entry {
+            Some(entry) => find_record(fd, &file_header, &entry, key)?,
+            None => self.find_older(&hash, key)?,
+        };
+        let (val, expires_at) = match change(current.as_ref())? {
+            Some(replacement) => replacement,
+            None => return Ok(0),
+        };
+
+        let (offset, nbytes) = append_new_key_val(fd, &file_header, &hash, key, &val, expires_at)?;
+        // (a record in an older segment stays as it is, since the new one supersedes it anyway)
+        if let (Some(entry), Some(_)) = (entry, current) {
+            mark_deleted(fd, &file_header, entry.offset)?;
+        }
+        let mut keydir = self.keydir();
+        keydir.insert(
+            hash,
+            KeydirEntry {
+                offset,
+                size: val.len(),
+                len: nbytes as u64,
+            },
+        );
+        keydir.indexed_len = offset + nbytes as u64;
+        Ok(nbytes)
+    }
+
+    /// Make all the changes in `batch`, or none of them, returning the number of bytes appended
+    ///
+    /// The whole batch goes out in one write, between a begin and a commit marker, with a
+    /// tombstone for each key it deletes. Only after that are the records it replaces flagged
+    /// as deleted, which a crash could prevent, but then they are superseded by the batch anyway.
+    pub fn apply(&self, batch: &Batch) -> Result<usize, Error> {
+        if batch.is_empty() {
+            return Ok(0);
+        }
+        let locked = self.lock(OFlag::O_RDWR | OFlag::O_CREAT, FlockArg::LockExclusive)?;
+        let result = self.apply_locked(&locked, batch);
+        self.release_written(locked, matches!(result, Ok(n) if n > 0))?;
+        result
+    }
+
+    fn apply_locked(&self, locked: &Locked, batch: &Batch) -> Result<usize, Error> {
+        let fd = &locked.fd();
+        let mut file_header = self.keydir().file_header;
+        let mut records: Vec<u8> = Vec::new();
+        let mut replaced: Vec<u64> = Vec::new(); // offsets of the records to flag as deleted afterwards
+        let mut changes: Vec<(String, Option<KeydirEntry>)> = Vec::new(); // with offsets into `records`
+        let mut live: HashMap<String, bool> = HashMap::new(); // for the keys the batch has touched so far
+
+        for op in batch.ops() {
+            let key = match op {
+                BatchOp::Set(key, _, _) | BatchOp::Delete(key) => key,
+            };
+            let (hash, entry, header) = self.lookup(locked, key)?;
+            if records.is_empty() {
+                if !header.has_batches() {
+                    return Err(Error::NoBatches);
+                }
+                // a crash in some other process may have left an incomplete record at the end
+                truncate_torn_tail(&locked.path, fd, &self.keydir())?;
+                file_header = header;
+                records.extend(encode_marker(&file_header, RecordKind::BatchBegin));
+            }
+
+            // whether there is a value to replace, either in the file, or earlier in the batch
+            let was_live = match live.get(&hash) {
+                Some(was_live) => *was_live,
+                None => match entry {
+                    Some(entry) => match find_record(fd, &file_header, &entry, key)? {
+                        Some(_) => {
+                            replaced.push(entry.offset);
+                            true
+                        }
+                        None => false,
+                    },
+                    None => self.find_older(&hash, key)?.is_some(),
+                },
+            };
+
+            match op {
+                BatchOp::Set(_, val, expires_at) => {
+                    if expires_at.is_some() && !file_header.has_expiry() {
+                        return Err(Error::NoExpiry);
+                    }
+                    let record =
+                        encode_record(&file_header, &hash, key.as_bytes(), val, *expires_at);
+                    let entry = KeydirEntry {
+                        offset: records.len() as u64,
+                        size: val.len(),
+                        len: record.len() as u64,
+                    };
+                    changes.push((hash.clone(), Some(entry)));
+                    records.extend(record);
+                    live.insert(hash, true);
+                }
+                BatchOp::Delete(_) => {
+                    if was_live {
+                        changes.push((hash.clone(), None));
+                        records.extend(encode_tombstone(&file_header, &hash, key.as_bytes()));
+                    }
+                    live.insert(hash, false);
+                }
+            }
+        }
+        if changes.is_empty() {
+            return Ok(0); // nothing but deletes of keys which were not there
+        }
+        records.extend(encode_marker(&file_header, RecordKind::BatchCommit));
+
+        let (offset, nbytes) = append_records(fd, &file_header, &records)?;
+        for old in replaced {
+            mark_deleted(fd, &file_header, old)?;
+        }
+        let mut keydir = self.keydir();
+        for (hash, change) in changes {
+            match change {
+                Some(entry) => keydir.insert(
+                    hash,
+                    KeydirEntry {
+                        offset: offset + entry.offset,
+                        ..entry
+                    },
+                ),
+                None => _ = keydir.remove(&hash),
+            }
+        }
+        keydir.indexed_len = offset + nbytes as u64;
+        Ok(nbytes)
+    }
+
+    /// How long `key` has left before it expires
+    pub fn ttl(&self, key: &str) -> Result<Ttl, Error> {
+        let now = Utc::now().timestamp();
+        match self.get_record(key) {
+            Ok(Some((header, _))) => match header.expires_at {
+                Some(t) => Ok(Ttl::Seconds(t.saturating_sub(now).max(0) as u64)),
+                None => Ok(Ttl::Forever),
+            },
+            Ok(None) | Err(Error::Sys(Errno::ENOENT)) => Ok(Ttl::Missing),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Remove the expiry time of `key`, returning false if it had none (or does not exist)
+    ///
+    /// The checksum covers the expiry time, so rather than being edited in place, the
+    /// record is upserted without one.
+    pub fn persist(&self, key: &str) -> Result<bool, Error> {
+        let nbytes = self.update(key, false, |current| match current {
+            Some((header, value)) if header.expires_at.is_some() => Ok(Some((value.clone(), None))),
+            _ => Ok(None),
+        })?;
+        Ok(nbytes > 0)
+    }
+
+    /// How much garbage there is, as of the last operation (which picked up whatever anyone else appended)
+    ///
+    /// With segments, only the older ones count, since they are all a merge rewrites, and their records
+    /// are also dead once the key has been replaced (or deleted) in a newer segment.
+    pub fn garbage(&self) -> Garbage {
+        let segments = self.segments();
+        let keydir = self.keydir();
+        if self.segment_size.is_none() {
+            return Garbage {
+                live_bytes: keydir.live_bytes(),
+                dead_bytes: keydir.dead_bytes(),
+            };
+        }
+
+        let mut newer: HashSet<&String> = keydir.iter().map(|(hash, _)| hash).collect();
+        newer.extend(keydir.deleted());
+        let mut garbage = Garbage::default();
+        for segment in segments.older.iter().rev() {
+            let live: u64 = segment
+                .keydir
+                .iter()
+                .filter(|(hash, _)| !newer.contains(hash))
+                .map(|(_, entry)| entry.len)
+                .sum();
+            garbage.live_bytes += live;
+            garbage.dead_bytes += segment.keydir.dead_bytes() + segment.keydir.live_bytes() - live;
+            newer.extend(segment.keydir.iter().map(|(hash, _)| hash));
+            newer.extend(segment.keydir.deleted());
+        }
+        garbage
+    }
+
+    /// Count up the records on the disk, reading every one of them (under a shared lock)
+    ///
+    /// Unlike `garbage()`, this goes by the files alone, and covers the active segment too.
+    pub fn stats(&self) -> Result<Stats, Error> {
+        let locked = match self.lock(OFlag::O_RDONLY, FlockArg::LockShared) {
+            Ok(locked) => locked,
+            Err(Error::Sys(Errno::ENOENT)) => return Ok(Stats::default()), // no data yet
+            Err(e) => return Err(e),
+        };
+        let stats = match self.segment_size {
+            Some(_) => self.segment_stats(&locked),
+            None => file_stats(&locked.path, &locked.fd(), &HashSet::new()).map(|(stats, _)| stats),
+        };
+        locked.release()?;
+        stats
+    }
+
+    // (under the lock on the directory) the stats of every segment, newest first, where a record
+    // only counts as live if no newer segment has a record of the same key
+    fn segment_stats(&self, locked: &Locked) -> Result<Stats, Error> {
+        let numbers: Vec<u64> = self.segments().older.iter().map(|s| s.number).collect();
+        let (mut stats, keydir) = file_stats(&locked.path, &locked.fd(), &HashSet::new())?;
+        let mut newer: HashSet<String> = keydir.iter().map(|(hash, _)| hash.clone()).collect();
+        newer.extend(keydir.deleted().cloned());
+        for number in numbers.into_iter().rev() {
+            let filepath = segment_path(&self.filepath, number);
+            let fd: OwnedFd = open(filepath.as_str(), OFlag::O_RDONLY, Mode::empty())?;
+            let counted = file_stats(&filepath, &fd.as_fd(), &newer);
+            close(fd)?;
+            let (segment, keydir) = counted?;
+            stats.add(&segment);
+            newer.extend(keydir.iter().map(|(hash, _)| hash.clone()));
+            newer.extend(keydir.deleted().cloned());
+        }
+        Ok(stats)
+    }
+
+    /// Rewrite the data file without its deleted, replaced and expired records
+    ///
+    /// With segments, it is the older segments which get merged into one, while the active
+    /// one goes on taking writes: only the final rename holds anyone else up.
+    pub fn compact(&self) -> Result<Option<Vec<u8>>, Error> {
+        if self.segment_size.is_some() {
+            let merge_lock = lock_merges(&self.filepath)?;
+            let merged = self.merge_segments();
+            release(merge_lock)?;
+            merged?;
+            return Ok(None);
+        }
+        let keydir = compact_file(&self.filepath)?;
+        *self.keydir() = keydir;
+        Ok(None)
+    }
+
+    fn merge_segments(&self) -> Result<(), Error> {
+        // which segments there are to merge, as of now, and which keys the active one has records of,
+        // which supersede any in the merged segments (the active one only grows, so that stays true)
+        let locked = self.lock(OFlag::O_RDONLY, FlockArg::LockShared)?;
+        let snapshot = {
+            let segments = self.segments();
+            let mut keydir = self.keydir();
+            index_records(&locked.path, &locked.fd(), &mut keydir).map(|_| {
+                let numbers: Vec<u64> = segments.older.iter().map(|s| s.number).collect();
+                let mut newer: HashSet<String> =
+                    keydir.iter().map(|(hash, _)| hash.clone()).collect();
+                newer.extend(keydir.deleted().cloned());
+                (numbers, newer)
+            })
+        };
+        locked.release()?;
+        let (numbers, newer) = snapshot?;
+        if numbers.is_empty() {
+            return Ok(());
+        }
+
+        let (tmp_lock, tmp_filepath, merged) = write_merged(&self.filepath, &numbers, &newer)?;
+        // (only the directory: the active segment can be as full as it likes, this is no write to it)
+        let dir_lock = match lock_dir(&self.filepath, false, FlockArg::LockExclusive) {
+            Ok(lock) => lock,
+            Err(e) => {
+                _ = unlink(tmp_filepath.as_str());
+                release(tmp_lock)?;
+                return Err(e.into());
+            }
+        };
+
+        // the merged segment takes the place of the newest of the ones it merged, and only once
+        // that is on the disk can the others go: until then, its flag says they are superseded
+        let mut segments = self.segments();
+        let filepath = segment_path(&self.filepath, merged.number);
+        let swapped = self.catch_up(&mut segments).and_then(|_| {
+            renameat(AT_FDCWD, tmp_filepath.as_str(), AT_FDCWD, filepath.as_str())?;
+            Ok(sync_dir(&filepath)?)
+        });
+        match swapped {
+            Ok(_) => {
+                for (number, _) in list_segments(&self.filepath)? {
+                    if number < merged.number
+                        && let Err(e) = unlink(segment_path(&self.filepath, number).as_str())
+                    {
+                        warn!(
+                            "{}: failed to remove merged segment {number}: {e}",
+                            self.filepath
+                        );
+                    }
+                }
+                segments
+                    .older
+                    .retain(|segment| segment.number > merged.number);
+                segments.older.insert(0, merged);
+            }
+            Err(_) => _ = unlink(tmp_filepath.as_str()),
+        }
+        drop(segments);
+
+        release(tmp_lock)?;
+        release(dir_lock)?;
+        swapped
+    }
+}
+
+impl Drop for Store {
+    fn drop(&mut self) {
+        // whatever the flusher thread has not got to yet
+        if self.durability != Durability::Never
+            && self.commit.is_dirty()
+            && let Err(e) = flush(&self.filepath, self.segment_size.is_some(), &self.commit)
+        {
+            warn!("{}: failed to flush on close: {e}", self.filepath);
+        }
+    }
+}
+
+// fsync everything written to the data file so far (with segments, to the active one: the others
+// were synced as they filled up)
+fn flush(filepath: &str, segmented: bool, commit: &GroupCommit) -> Result<(), Error> {
+    let filepath = match segmented {
+        true => match list_segments(filepath)?.last() {
+            Some((number, _)) => segment_path(filepath, *number),
+            None => return Ok(()),
+        },
+        false => String::from(filepath),
+    };
+    let fd: OwnedFd = open(filepath.as_str(), OFlag::O_RDONLY, Mode::empty())?;
+    sync_dir(&filepath)?;
+    let synced = commit.sync_all(&fd.as_fd());
+    close(fd)?;
+    Ok(synced?)
+}
+
+// for `Durability::Interval`: flush every so often, for as long as the store is around
+fn spawn_flusher(store: &Store, millis: u64) {
+    let filepath = store.filepath.clone();
+    let segmented = store.segment_size.is_some();
+    let commit: Weak<GroupCommit> = Arc::downgrade(&store.commit);
+    thread::spawn(move || {
+        loop {
+            thread::sleep(Duration::from_millis(millis));
+            let Some(commit) = commit.upgrade() else {
+                break;
+            };
+            if commit.is_dirty()
+                && let Err(e) = flush(&filepath, segmented, &commit)
+            {
+                warn!("{filepath}: failed to flush: {e}");
+            }
+        }
+    });
+}
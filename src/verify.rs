@@ -0,0 +1,359 @@
+use crate::error::Error;
+use crate::file_syscalls::{
+    create_compact_tmp, next_valid_record, read_file_header, read_record, release, sync_dir,
+    write_all,
+};
+use crate::format::{
+    FILE_HEADER_SIZE, FileHeader, RecordHeader, RecordKind, encode_record, looks_like_header,
+};
+use chrono::Utc;
+use nix::fcntl::{AT_FDCWD, renameat};
+use nix::sys::stat::fstat;
+use nix::sys::uio::pread;
+use nix::unistd::{fsync, unlink};
+use std::collections::HashMap;
+use std::fmt;
+use std::os::fd::{AsFd, BorrowedFd};
+
+/* Verifying (and repairing) data files
+ *
+ * An fsck for data files: every record gets read the way `record_reader` reads them, from one
+ * header to the next, and checked for anything the format does not allow, rather than stopping
+ * at the first corrupt one. Where a size is impossible, the walk carries on from the next intact
+ * record, the way `next_valid_record` finds it, so one bad record does not hide the rest.
+ *
+ * Repairing takes the same walk, but skips every damaged record (and whatever follows it, up to
+ * the next intact one), and copies the live records among the rest to a new file.
+ *
+ */
+
+/// Something wrong with a data file, at the byte offset of the record concerned
+#[derive(Clone, Debug, PartialEq)]
+pub enum Problem {
+    /// The file ends part-way through the record (e.g. a crash cut its append short)
+    Truncated { offset: u64 },
+    /// The record's size runs past the end of the file, yet there are intact records after it
+    ImpossibleSize { offset: u64, size: u64, next: u64 },
+    /// The deleted flag (status byte) is none of the values the file can have
+    InvalidStatus { offset: u64, status: u8 },
+    /// The record fails its checksum
+    ChecksumMismatch { offset: u64 },
+    /// A second live record of the same key, which should have flagged the first one deleted
+    DuplicateLiveKey {
+        offset: u64,
+        hash: String,
+        first: u64,
+    },
+    /// A batch begins here, but never gets committed
+    Uncommitted { offset: u64 },
+}
+
+impl Problem {
+    pub fn offset(&self) -> u64 {
+        match self {
+            Problem::Truncated { offset }
+            | Problem::ImpossibleSize { offset, .. }
+            | Problem::InvalidStatus { offset, .. }
+            | Problem::ChecksumMismatch { offset }
+            | Problem::DuplicateLiveKey { offset, .. }
+            | Problem::Uncommitted { offset } => *offset,
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::Truncated { offset } => {
+                write!(f, "offset {offset}: record cut off by the end of the file")
+            }
+            Problem::ImpossibleSize { offset, size, next } => write!(
+                f,
+                "offset {offset}: impossible size {size} (past the end of the file), next intact record at offset {next}"
+            ),
+            Problem::InvalidStatus { offset, status } => {
+                write!(f, "offset {offset}: invalid deleted flag {status}")
+            }
+            Problem::ChecksumMismatch { offset } => write!(f, "offset {offset}: checksum mismatch"),
+            Problem::DuplicateLiveKey {
+                offset,
+                hash,
+                first,
+            } => write!(
+                f,
+                "offset {offset}: another live record of key {hash}, as well as the one at offset {first}"
+            ),
+            Problem::Uncommitted { offset } => {
+                write!(f, "offset {offset}: batch never committed")
+            }
+        }
+    }
+}
+
+/// What `verify()` found: how many records it read, and what is wrong with them, in file order
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Report {
+    pub records: u64,
+    pub problems: Vec<Problem>,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Check every record of the data file `fd`, which the caller holds (at least) a shared lock on
+pub(crate) fn verify_file(fd: &BorrowedFd) -> Result<Report, Error> {
+    let mut report = Report::default();
+    let file_len = fstat(fd)?.st_size as u64;
+    let file_header = match read_file_header(fd)? {
+        Some(file_header) => file_header,
+        None if file_len > 0 => {
+            report.problems.push(Problem::Truncated { offset: 0 }); // not even the whole file header
+            return Ok(report);
+        }
+        None => return Ok(report),
+    };
+
+    let head_buf: &mut [u8] = &mut vec![0; file_header.record_header_size()];
+    let status_offset = file_header.deleted_offset(0) as usize;
+    let now = Utc::now().timestamp();
+    // the offset of each key's live record, as of the last committed change, and any batch's since
+    let mut live: HashMap<String, u64> = HashMap::new();
+    let mut batch: Option<u64> = None; // where the open batch (if any) begins
+    let mut pending: Vec<(String, Option<u64>)> = Vec::new();
+
+    let mut offset = FILE_HEADER_SIZE as u64;
+    while offset < file_len {
+        if pread(fd, head_buf, offset as i64)? < head_buf.len() {
+            report.problems.push(Problem::Truncated { offset });
+            break;
+        }
+        let header = RecordHeader::decode(offset, head_buf, &file_header);
+        if header.overruns(file_len) {
+            match next_valid_record(fd, &file_header, offset + 1)? {
+                Some(next) => {
+                    report.problems.push(Problem::ImpossibleSize {
+                        offset,
+                        size: header.size as u64,
+                        next,
+                    });
+                    offset = next;
+                    continue;
+                }
+                None => {
+                    report.problems.push(Problem::Truncated { offset });
+                    break;
+                }
+            }
+        }
+        report.records += 1;
+
+        let status = head_buf[status_offset];
+        if status > file_header.max_status() {
+            report
+                .problems
+                .push(Problem::InvalidStatus { offset, status });
+        }
+        let mut body = vec![0; header.body_size()];
+        _ = pread(fd, &mut body, header.body_offset() as i64)?;
+        if header.verify(&body).is_err() {
+            report.problems.push(Problem::ChecksumMismatch { offset });
+        }
+
+        let change = match header.kind {
+            RecordKind::Value if !header.deleted && !header.is_expired(now) => {
+                Some((header.hash.clone(), Some(offset)))
+            }
+            RecordKind::Value => None,
+            RecordKind::Tombstone => Some((header.hash.clone(), None)),
+            RecordKind::BatchBegin => {
+                if let Some(begun) = batch.replace(offset) {
+                    report.problems.push(Problem::Uncommitted { offset: begun });
+                }
+                pending.clear();
+                None
+            }
+            RecordKind::BatchCommit => {
+                if batch.take().is_some() {
+                    for (hash, change) in pending.drain(..) {
+                        track_live(&mut live, &mut report, hash, change);
+                    }
+                }
+                None
+            }
+        };
+        match (&mut batch, change) {
+            (Some(_), Some(change)) => pending.push(change),
+            (None, Some((hash, change))) => track_live(&mut live, &mut report, hash, change),
+            _ => (),
+        }
+        offset = header.next_offset();
+    }
+    if let Some(begun) = batch {
+        report.problems.push(Problem::Uncommitted { offset: begun });
+    }
+
+    report.problems.sort_by_key(|problem| problem.offset());
+    Ok(report)
+}
+
+// note the live record of `hash` (if any), complaining if there already was one
+fn track_live(
+    live: &mut HashMap<String, u64>,
+    report: &mut Report,
+    hash: String,
+    change: Option<u64>,
+) {
+    match change {
+        Some(offset) => {
+            if let Some(first) = live.insert(hash.clone(), offset) {
+                report.problems.push(Problem::DuplicateLiveKey {
+                    offset,
+                    hash,
+                    first,
+                });
+            }
+        }
+        None => _ = live.remove(&hash),
+    }
+}
+
+/// What `repair()` salvaged from a damaged data file, and what it had to leave behind
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Salvage {
+    pub salvaged: u64,            // live records, copied to the new file
+    pub dropped: u64, // intact records which were dead anyway (deleted, replaced, expired, ...)
+    pub skipped: Vec<(u64, u64)>, // damaged stretches of the file, as (offset, length)
+}
+
+/// Copy the live records which are still intact in the data file `fd` to a new one at `dst`,
+/// going round any damage, as far as the next intact record
+///
+/// Records count as live just as when indexing (so a batch whose commit marker was lost goes too),
+/// and the new file is written in full, and fsynced, before it gets renamed into place.
+pub(crate) fn repair_file(fd: &BorrowedFd, dst: &str) -> Result<Salvage, Error> {
+    let file_header = read_file_header(fd)?.ok_or(Error::NotDataFile)?;
+    let file_len = fstat(fd)?.st_size as u64;
+    let head_buf: &mut [u8] = &mut vec![0; file_header.record_header_size()];
+    let mut salvage = Salvage::default();
+    let mut intact: u64 = 0;
+    let mut live: HashMap<String, u64> = HashMap::new();
+    let mut batch: Option<Vec<(String, Option<u64>)>> = None;
+
+    let mut offset = FILE_HEADER_SIZE as u64;
+    while offset < file_len {
+        let header = match intact_record(fd, &file_header, head_buf, offset, file_len)? {
+            Some(header) => header,
+            None => {
+                // resynchronise at the next record which looks right, and adds up
+                let next = next_valid_record(fd, &file_header, offset + 1)?.unwrap_or(file_len);
+                salvage.skipped.push((offset, next - offset));
+                offset = next;
+                continue;
+            }
+        };
+        intact += 1;
+        let change = match header.kind {
+            RecordKind::Value if !header.deleted => Some((header.hash.clone(), Some(offset))),
+            RecordKind::Value => None,
+            RecordKind::Tombstone => Some((header.hash.clone(), None)),
+            RecordKind::BatchBegin => {
+                batch = Some(Vec::new());
+                None
+            }
+            RecordKind::BatchCommit => {
+                for (hash, change) in batch.take().unwrap_or_default() {
+                    salvage_change(&mut live, hash, change);
+                }
+                None
+            }
+        };
+        match (&mut batch, change) {
+            (Some(pending), Some(change)) => pending.push(change),
+            (None, Some((hash, change))) => salvage_change(&mut live, hash, change),
+            _ => (),
+        }
+        offset = header.next_offset();
+    }
+
+    let mut records: Vec<u64> = live.into_values().collect();
+    records.sort();
+    let (tmp_lock, tmp_filepath) = create_compact_tmp(dst)?;
+    let written =
+        write_salvaged(fd, &tmp_lock.as_fd(), &file_header, &records).and_then(|salvaged| {
+            fsync(tmp_lock.as_fd())?;
+            renameat(AT_FDCWD, tmp_filepath.as_str(), AT_FDCWD, dst)?;
+            Ok(salvaged)
+        });
+    match written {
+        Ok(salvaged) => salvage.salvaged = salvaged,
+        Err(e) => {
+            _ = unlink(tmp_filepath.as_str());
+            release(tmp_lock)?;
+            return Err(e);
+        }
+    }
+    let synced = sync_dir(dst);
+    release(tmp_lock)?;
+    synced?;
+    salvage.dropped = intact - salvage.salvaged;
+    Ok(salvage)
+}
+
+// the header of the record at `offset`, if it is an intact one: plausible, within the file, and matching its checksum
+fn intact_record(
+    fd: &BorrowedFd,
+    file_header: &FileHeader,
+    head_buf: &mut [u8],
+    offset: u64,
+    file_len: u64,
+) -> Result<Option<RecordHeader>, Error> {
+    if pread(fd, head_buf, offset as i64)? < head_buf.len()
+        || !looks_like_header(head_buf, file_header)
+    {
+        return Ok(None);
+    }
+    let header = RecordHeader::decode(offset, head_buf, file_header);
+    if header.overruns(file_len) {
+        return Ok(None);
+    }
+    let mut body = vec![0; header.body_size()];
+    _ = pread(fd, &mut body, header.body_offset() as i64)?;
+    match header.verify(&body) {
+        Ok(_) => Ok(Some(header)),
+        Err(_) => Ok(None),
+    }
+}
+
+// later records win, as when indexing
+fn salvage_change(live: &mut HashMap<String, u64>, hash: String, change: Option<u64>) {
+    match change {
+        Some(offset) => _ = live.insert(hash, offset),
+        None => _ = live.remove(&hash),
+    }
+}
+
+// write the file header, then the `records` at those offsets of `fd` which have not expired, returning how many
+fn write_salvaged(
+    fd: &BorrowedFd,
+    tmp_fd: &BorrowedFd,
+    file_header: &FileHeader,
+    records: &[u64],
+) -> Result<u64, Error> {
+    write_all(tmp_fd, &file_header.encode())?;
+    let mut salvaged = 0;
+    for offset in records {
+        let Some((header, body)) = read_record(fd, file_header, *offset)? else {
+            continue;
+        };
+        let (key, val) = header.split(&body);
+        write_all(
+            tmp_fd,
+            &encode_record(file_header, &header.hash, key, val, header.expires_at),
+        )?;
+        salvaged += 1;
+    }
+    Ok(salvaged)
+}
@@ -0,0 +1,93 @@
+use coat_check::batch::Batch;
+use coat_check::file_syscalls::{compact, read_key};
+use coat_check::store::Store;
+
+mod common;
+
+// a record with a 1-byte key: header, hash, and the size, deleted, checksum, key size and expiry fields
+const RECORD_OVERHEAD: u64 = 32 + 8 + 1 + 4 + 4 + 8 + 1;
+// the begin or commit marker around a batch, which has neither key nor value
+const MARKER_SIZE: u64 = RECORD_OVERHEAD - 1;
+
+#[test]
+fn batch_sets_and_deletes_together() {
+    let file_folder = common::generate_test_file(500);
+    let store = Store::open(file_folder.clone()).unwrap();
+    assert!(store.set("a", b"alpha").is_ok());
+    assert!(store.set("b", b"bravo").is_ok());
+
+    let mut batch = Batch::new();
+    batch
+        .set("a", b"apple")
+        .delete("b")
+        .set("c", b"cherry")
+        .delete("d") // not there, so no tombstone
+        .set("e", b"elder")
+        .delete("e");
+    match store.apply(&batch) {
+        Ok(bytes) => assert_eq!(
+            bytes as u64,
+            2 * MARKER_SIZE
+                + (RECORD_OVERHEAD + 5) * 2
+                + (RECORD_OVERHEAD + 6)
+                + RECORD_OVERHEAD * 2
+        ),
+        Err(e) => panic!("apply failed: {e}"),
+    }
+
+    // both through the store which applied it, and through one which reads it from the file
+    let reopened = Store::open(file_folder.clone()).unwrap();
+    for store in [&store, &reopened] {
+        assert_eq!(store.get("a"), Ok(Some(b"apple".to_vec())));
+        assert_eq!(store.get("b"), Ok(None));
+        assert_eq!(store.get("c"), Ok(Some(b"cherry".to_vec())));
+        assert_eq!(store.get("d"), Ok(None));
+        assert_eq!(store.get("e"), Ok(None));
+    }
+
+    // and compaction leaves nothing but the live records behind
+    assert!(compact(file_folder.clone()).is_ok());
+    assert_eq!(
+        std::fs::metadata(&file_folder).unwrap().len(),
+        32 + (RECORD_OVERHEAD + 5) + (RECORD_OVERHEAD + 6)
+    );
+    assert_eq!(
+        read_key(file_folder.clone(), "c"),
+        Ok(Some(b"cherry".to_vec()))
+    );
+    assert_eq!(read_key(file_folder.clone(), "b"), Ok(None));
+}
+
+#[test]
+fn batch_without_its_commit_marker_is_discarded() {
+    let file_folder = common::generate_test_file(501);
+    let store = Store::open(file_folder.clone()).unwrap();
+    assert!(store.set("a", b"alpha").is_ok());
+    assert!(store.set("b", b"bravo").is_ok());
+    let before = std::fs::read(&file_folder).unwrap();
+
+    let mut batch = Batch::new();
+    batch.set("a", b"apple").delete("b").set("c", b"cherry");
+    assert!(store.apply(&batch).is_ok());
+    let after = std::fs::read(&file_folder).unwrap();
+
+    // a crash part-way through the write of the batch, before its commit marker (and before
+    // the records it replaces got flagged as deleted)
+    let mut torn = before.clone();
+    torn.extend_from_slice(&after[before.len()..after.len() - MARKER_SIZE as usize]);
+    std::fs::write(&file_folder, &torn).unwrap();
+
+    let store = Store::open(file_folder.clone()).unwrap();
+    assert_eq!(store.get("a"), Ok(Some(b"alpha".to_vec())));
+    assert_eq!(store.get("b"), Ok(Some(b"bravo".to_vec())));
+    assert_eq!(store.get("c"), Ok(None));
+    assert_eq!(
+        std::fs::metadata(&file_folder).unwrap().len(),
+        before.len() as u64
+    );
+
+    // and the next batch goes in where the discarded one was
+    assert!(store.apply(&batch).is_ok());
+    assert_eq!(std::fs::read(&file_folder).unwrap().len(), after.len());
+    assert_eq!(store.get("c"), Ok(Some(b"cherry".to_vec())));
+}
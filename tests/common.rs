@@ -2,7 +2,8 @@ use chrono::Utc;
 
 pub fn generate_test_file(n: i32) -> String {
     format!(
-        "/tmp/test-{}-{n}.coat-check",
-        Utc::now().timestamp().to_string()
+        "/tmp/test-{}-{}-{n}.coat-check",
+        Utc::now().timestamp(),
+        std::process::id()
     )
 }
@@ -0,0 +1,164 @@
+use coat_check::file_syscalls::{compact, delete_key, read_key, write_key_val};
+use coat_check::store::Store;
+use std::fs::File;
+use std::path::Path;
+
+mod common;
+
+// the compaction temp files there are for the data file at `filepath`
+fn temp_files(filepath: &str) -> Vec<String> {
+    let path = Path::new(filepath);
+    let prefix = format!("{}.compact-", path.file_name().unwrap().to_str().unwrap());
+    std::fs::read_dir(path.parent().unwrap())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.starts_with(&prefix))
+        .collect()
+}
+
+fn write_records(filepath: &str) {
+    assert!(write_key_val(filepath.to_string(), "a", b"alpha").is_ok());
+    assert!(write_key_val(filepath.to_string(), "b", b"bravo").is_ok());
+    assert!(write_key_val(filepath.to_string(), "a", b"apple").is_ok());
+    assert!(delete_key(filepath.to_string(), "b").is_ok());
+}
+
+fn assert_records(filepath: &str) {
+    assert_eq!(
+        read_key(filepath.to_string(), "a"),
+        Ok(Some(b"apple".to_vec()))
+    );
+    assert_eq!(read_key(filepath.to_string(), "b"), Ok(None));
+}
+
+#[test]
+fn back_to_back_compactions_leave_no_temp_files() {
+    let file_folder = common::generate_test_file(700);
+    write_records(&file_folder);
+
+    // (well within the same second, which used to mean the same temp file name)
+    for _ in 0..3 {
+        assert!(compact(file_folder.clone()).is_ok());
+        assert!(write_key_val(file_folder.clone(), "c", b"charlie").is_ok());
+        assert!(delete_key(file_folder.clone(), "c").is_ok());
+    }
+    assert_records(&file_folder);
+    assert!(temp_files(&file_folder).is_empty());
+}
+
+#[test]
+fn interrupted_before_the_rename_keeps_the_original() {
+    let file_folder = common::generate_test_file(701);
+    write_records(&file_folder);
+    let original = std::fs::read(&file_folder).unwrap();
+
+    // a crash part-way through writing the temp file, and one after it was written (and synced),
+    // but before it got renamed into place
+    let partial = format!("{file_folder}.compact-00112233aabbccdd");
+    std::fs::write(&partial, &original[0..40]).unwrap();
+    let complete = format!("{file_folder}.compact-8899aabbccddeeff");
+    std::fs::write(&complete, &original).unwrap();
+    assert_eq!(temp_files(&file_folder).len(), 2);
+
+    // the next open finds the original as it was, and clears the orphans away
+    let store = Store::open(file_folder.clone()).unwrap();
+    assert!(temp_files(&file_folder).is_empty());
+    assert_eq!(std::fs::read(&file_folder).unwrap(), original);
+    assert_records(&file_folder);
+
+    // and compacting it again goes ahead as usual
+    assert!(store.compact().is_ok());
+    assert_records(&file_folder);
+}
+
+#[test]
+fn temp_file_of_a_compaction_in_progress_is_left_alone() {
+    let file_folder = common::generate_test_file(702);
+    write_records(&file_folder);
+
+    // another process, still writing its temp file (and so holding the lock on it)
+    let in_progress = format!("{file_folder}.compact-0123456789abcdef");
+    let file = File::create(&in_progress).unwrap();
+    file.lock().unwrap();
+
+    assert!(Store::open(file_folder.clone()).is_ok());
+    assert_eq!(temp_files(&file_folder).len(), 1);
+
+    // until it lets go without having renamed it, e.g. because it died
+    drop(file);
+    assert!(Store::open(file_folder.clone()).is_ok());
+    assert!(temp_files(&file_folder).is_empty());
+}
+
+#[test]
+fn interrupted_after_the_rename_keeps_the_new_file() {
+    let file_folder = common::generate_test_file(703);
+    let hint_file = format!("{file_folder}.hint");
+    write_records(&file_folder);
+    assert!(compact(file_folder.clone()).is_ok());
+    let old_hint = std::fs::read(&hint_file).unwrap();
+
+    // a crash after the rename, before the (new) hint file was written: the old one is still there,
+    // describing the file as it was before this compaction
+    assert!(write_key_val(file_folder.clone(), "c", b"charlie").is_ok());
+    assert!(delete_key(file_folder.clone(), "a").is_ok());
+    assert!(compact(file_folder.clone()).is_ok());
+    std::fs::write(&hint_file, &old_hint).unwrap();
+
+    let store = Store::open(file_folder.clone()).unwrap();
+    assert_eq!(store.get("a"), Ok(None));
+    assert_eq!(store.get("c"), Ok(Some(b"charlie".to_vec())));
+
+    // or no hint file at all
+    std::fs::remove_file(&hint_file).unwrap();
+    let store = Store::open(file_folder.clone()).unwrap();
+    assert_eq!(store.get("a"), Ok(None));
+    assert_eq!(store.get("c"), Ok(Some(b"charlie".to_vec())));
+    assert!(temp_files(&file_folder).is_empty());
+
+    // the file is complete, so there is nothing to truncate either
+    assert_eq!(
+        std::fs::metadata(&file_folder).unwrap().len(),
+        32 + 32 + 8 + 1 + 4 + 4 + 8 + 1 + 7
+    );
+}
+
+#[test]
+fn writes_made_during_a_compaction_are_kept() {
+    let file_folder = common::generate_test_file(704);
+    let writer = Store::open(file_folder.clone()).unwrap();
+    for i in 0..100 {
+        assert!(writer.set(&format!("key{i}"), b"first").is_ok());
+    }
+
+    // compacted by another store on the same file (as another process would), over and over,
+    // while the writer replaces, deletes and adds keys
+    let handle = {
+        let file_folder = file_folder.clone();
+        std::thread::spawn(move || {
+            let compactor = Store::open(file_folder).unwrap();
+            for _ in 0..20 {
+                if let Err(e) = compactor.compact() {
+                    panic!("compact failed: {e}");
+                }
+            }
+        })
+    };
+    for i in 0..200 {
+        assert!(writer.set(&format!("key{i}"), b"second").is_ok());
+        if i % 3 == 0 {
+            assert!(writer.delete(&format!("key{}", i / 2)).is_ok());
+        }
+    }
+    handle.join().unwrap();
+    assert!(temp_files(&file_folder).is_empty());
+
+    // whatever the interleaving, every write made it into the file
+    for store in [writer, Store::open(file_folder.clone()).unwrap()] {
+        for i in 0..200 {
+            let deleted = (0..200).any(|j| j % 3 == 0 && j / 2 == i && j >= i);
+            let expected = (!deleted).then(|| b"second".to_vec());
+            assert_eq!(store.get(&format!("key{i}")), Ok(expected), "key{i}");
+        }
+    }
+}
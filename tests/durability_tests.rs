@@ -0,0 +1,129 @@
+use coat_check::durability::Durability;
+use coat_check::file_syscalls::{inspect, verify};
+use coat_check::hasher::hash_key;
+use coat_check::inspect::Filter;
+use coat_check::store::{Store, StoreOptions};
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::Duration;
+
+mod common;
+
+fn open(n: i32, durability: Durability) -> Store {
+    let options = StoreOptions {
+        durability,
+        ..StoreOptions::default()
+    };
+    Store::open_with(common::generate_test_file(n), options).unwrap()
+}
+
+#[test]
+fn durability_parses_from_settings() {
+    assert_eq!("always".parse::<Durability>(), Ok(Durability::Always));
+    assert_eq!("never".parse::<Durability>(), Ok(Durability::Never));
+    assert_eq!("250ms".parse::<Durability>(), Ok(Durability::Interval(250)));
+    assert_eq!("250".parse::<Durability>(), Ok(Durability::Interval(250)));
+    assert!("0ms".parse::<Durability>().is_err());
+    assert!("sometimes".parse::<Durability>().is_err());
+}
+
+#[test]
+fn concurrent_writes_share_fsyncs() {
+    let store = Arc::new(open(600, Durability::Always));
+
+    // (all the writers start at once, so that they really do overlap)
+    let start = Arc::new(Barrier::new(16));
+    let mut handles = Vec::new();
+    for t in 0..16 {
+        let store = Arc::clone(&store);
+        let start = Arc::clone(&start);
+        handles.push(thread::spawn(move || {
+            start.wait();
+            for i in 0..20 {
+                let key = format!("key-{t}-{i}");
+                assert!(store.set(&key, key.as_bytes()).is_ok());
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    // every write waited on a sync, and some of them shared one
+    let syncs = store.syncs();
+    assert!(syncs > 0 && syncs < 16 * 20, "{syncs} syncs");
+    for t in 0..16 {
+        let key = format!("key-{t}-19");
+        assert_eq!(store.get(&key), Ok(Some(key.into_bytes())));
+    }
+
+    // and nothing written, nothing synced
+    assert!(store.set("key-0-0", b"key-0-0").is_ok());
+    assert_eq!(store.syncs(), syncs);
+}
+
+#[test]
+fn concurrent_sets_of_the_same_keys_are_written_together() {
+    let file_folder = common::generate_test_file(603);
+    let store = Arc::new(Store::open(file_folder.clone()).unwrap());
+
+    // (so that sets of the one key often end up in the same write)
+    let start = Arc::new(Barrier::new(16));
+    let mut handles = Vec::new();
+    for t in 0..16 {
+        let store = Arc::clone(&store);
+        let start = Arc::clone(&start);
+        handles.push(thread::spawn(move || {
+            start.wait();
+            for i in 0..20 {
+                let val = format!("{t:02}-{i:02}");
+                assert_eq!(
+                    store.set(&format!("key-{}", i % 4), val.as_bytes()),
+                    Ok(63 + 4)
+                );
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    // every set got a record of its own, and all but the last of each key were flagged as deleted
+    let all = inspect(file_folder.clone(), Filter::All)
+        .unwrap_or_else(|e| panic!("inspect failed: {e}"))
+        .entries;
+    assert_eq!(all.len(), 16 * 20);
+    let live = inspect(file_folder.clone(), Filter::Live)
+        .unwrap_or_else(|e| panic!("inspect failed: {e}"))
+        .entries;
+    assert_eq!(live.len(), 4);
+    for entry in live {
+        let key = (0..4)
+            .map(|i| format!("key-{i}"))
+            .find(|key| hash_key(key) == entry.hash)
+            .unwrap();
+        assert_eq!(store.get(&key), Ok(Some(entry.preview.into_bytes())));
+    }
+    match verify(file_folder) {
+        Ok(report) => assert_eq!(report.problems, vec![]),
+        Err(e) => panic!("verify failed: {e}"),
+    }
+}
+
+#[test]
+fn interval_and_never_leave_writes_to_later() {
+    let never = open(601, Durability::Never);
+    assert!(never.set("a", b"alpha").is_ok());
+    assert_eq!(never.syncs(), 0);
+
+    // writes are acknowledged straight away, then flushed in the background
+    let interval = open(602, Durability::Interval(10));
+    assert!(interval.set("a", b"alpha").is_ok());
+    assert!(interval.set("b", b"bravo").is_ok());
+    let mut waited = 0;
+    while interval.syncs() == 0 && waited < 100 {
+        thread::sleep(Duration::from_millis(10));
+        waited += 1;
+    }
+    assert!(interval.syncs() >= 1);
+}
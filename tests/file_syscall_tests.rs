@@ -1,4 +1,12 @@
+#![allow(
+    clippy::assertions_on_constants,
+    clippy::get_first,
+    clippy::useless_vec
+)]
+
+use coat_check::error::Error;
 use coat_check::file_syscalls::{compact, delete_key, read_key, write_key_val};
+use coat_check::store::Store;
 use nix::errno::Errno;
 use std::thread;
 use std::time::Duration;
@@ -12,7 +20,7 @@ fn first_read_key_fails() {
     let result = read_key(file_folder, "meh");
     // file does not exist, so read() should fail
     assert!(result.is_err());
-    assert_eq!(result, Err(Errno::ENOENT))
+    assert_eq!(result, Err(Error::Sys(Errno::ENOENT)))
 }
 
 #[test]
@@ -259,3 +267,149 @@ fn compaction_works() {
         }
     }
 }
+
+// overwrite a single byte of the data file, as a bad disk might
+fn damage(file_folder: &str, offset: u64, byte: u8) {
+    use std::os::unix::fs::FileExt;
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(file_folder)
+        .unwrap();
+    file.write_at(&[byte], offset).unwrap();
+}
+
+#[test]
+fn damaged_value_is_reported_as_corrupt() {
+    let file_folder = common::generate_test_file(7);
+
+    // after the 32-byte file header,
+    // `[(hashed) key][size of value][deleted?][checksum][size of key][expiry][key][value]`
+    // = 32 + 8 + 1 + 4 + 4 + 8 + 1 + 5 bytes each
+    assert!(write_key_val(file_folder.clone(), "a", b"alpha").is_ok());
+    assert!(write_key_val(file_folder.clone(), "b", b"bravo").is_ok());
+    damage(&file_folder, 32 + 63 + 58 + 1, b'R');
+
+    // the other record is still fine
+    assert_eq!(
+        read_key(file_folder.clone(), "a"),
+        Ok(Some(b"alpha".to_vec()))
+    );
+    assert_eq!(
+        read_key(file_folder.clone(), "b"),
+        Err(Error::Corrupt(32 + 63))
+    );
+
+    // and compaction refuses to carry on past it
+    assert_eq!(compact(file_folder.clone()), Err(Error::Corrupt(32 + 63)));
+    assert_eq!(
+        read_key(file_folder.clone(), "a"),
+        Ok(Some(b"alpha".to_vec()))
+    );
+}
+
+#[test]
+fn damaged_size_is_reported_as_corrupt() {
+    let file_folder = common::generate_test_file(8);
+
+    assert!(write_key_val(file_folder.clone(), "a", b"alpha").is_ok());
+    assert!(write_key_val(file_folder.clone(), "b", b"bravo").is_ok());
+    assert!(write_key_val(file_folder.clone(), "c", b"charlie").is_ok());
+    damage(&file_folder, 32 + 63 + 32 + 3, 0x7f);
+
+    // the size now points far past the end of the file, instead of at the next record,
+    // which is still intact, so this is not just an incomplete append to be cut off
+    assert_eq!(
+        read_key(file_folder.clone(), "a"),
+        Err(Error::Corrupt(32 + 63))
+    );
+    assert_eq!(
+        std::fs::metadata(&file_folder).unwrap().len(),
+        32 + 63 + 63 + 65
+    );
+}
+
+#[test]
+fn incomplete_last_record_is_truncated_on_open() {
+    let file_folder = common::generate_test_file(9);
+
+    assert!(write_key_val(file_folder.clone(), "a", b"alpha").is_ok());
+    assert!(write_key_val(file_folder.clone(), "b", b"bravo").is_ok());
+
+    // a crash part-way through the append of "b" (its value, then its header, got cut short)
+    for torn_len in [32 + 63 + 60, 32 + 63 + 20] {
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&file_folder)
+            .unwrap();
+        file.set_len(torn_len).unwrap();
+
+        assert_eq!(
+            read_key(file_folder.clone(), "a"),
+            Ok(Some(b"alpha".to_vec()))
+        );
+        assert_eq!(read_key(file_folder.clone(), "b"), Ok(None));
+        // (which only reads, so leaves the torn record for the next writer to truncate)
+        assert_eq!(std::fs::metadata(&file_folder).unwrap().len(), torn_len);
+        assert!(Store::open(file_folder.clone()).is_ok());
+        assert_eq!(std::fs::metadata(&file_folder).unwrap().len(), 32 + 63);
+
+        // and appends carry on from the end of the last complete record
+        assert!(write_key_val(file_folder.clone(), "b", b"bravo").is_ok());
+        assert_eq!(
+            read_key(file_folder.clone(), "b"),
+            Ok(Some(b"bravo".to_vec()))
+        );
+    }
+}
+
+#[test]
+fn file_without_a_known_header_is_refused() {
+    let file_folder = common::generate_test_file(10);
+
+    assert!(write_key_val(file_folder.clone(), "a", b"alpha").is_ok());
+
+    // a format version from the future
+    damage(&file_folder, 8, 2);
+    assert_eq!(
+        read_key(file_folder.clone(), "a"),
+        Err(Error::UnsupportedVersion(2))
+    );
+    assert_eq!(
+        write_key_val(file_folder.clone(), "b", b"bravo"),
+        Err(Error::UnsupportedVersion(2))
+    );
+    assert_eq!(
+        compact(file_folder.clone()),
+        Err(Error::UnsupportedVersion(2))
+    );
+
+    // a file in the old headerless layout, which starts right in with the first record
+    damage(&file_folder, 0, b'0');
+    assert_eq!(read_key(file_folder.clone(), "a"), Err(Error::NotDataFile));
+    assert_eq!(std::fs::metadata(&file_folder).unwrap().len(), 32 + 63);
+}
+
+#[test]
+fn incomplete_file_header_is_truncated_on_open() {
+    let file_folder = common::generate_test_file(11);
+
+    assert!(write_key_val(file_folder.clone(), "a", b"alpha").is_ok());
+
+    // a crash part-way through the very first append
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(&file_folder)
+        .unwrap();
+    file.set_len(5).unwrap();
+
+    assert_eq!(read_key(file_folder.clone(), "a"), Ok(None));
+    assert_eq!(std::fs::metadata(&file_folder).unwrap().len(), 5);
+    assert!(Store::open(file_folder.clone()).is_ok());
+    assert_eq!(std::fs::metadata(&file_folder).unwrap().len(), 0);
+    assert!(write_key_val(file_folder.clone(), "a", b"alpha").is_ok());
+    assert_eq!(
+        read_key(file_folder.clone(), "a"),
+        Ok(Some(b"alpha".to_vec()))
+    );
+    assert_eq!(std::fs::metadata(&file_folder).unwrap().len(), 32 + 63);
+}
@@ -1,4 +1,11 @@
-use coat_check::hasher::hash_key;
+use coat_check::error::Error;
+use coat_check::file_syscalls::{compact, read_key, write_key_val};
+use coat_check::hasher::{
+    HasherKind, KeyHasher, Md5Hasher, Sha256Hasher, SipHasher, hash_key, hash_size,
+};
+use coat_check::store::{Store, StoreOptions};
+
+mod common;
 
 #[test]
 fn key_hashing_is_deterministic() {
@@ -12,3 +19,81 @@ fn key_hashing_is_case_sensitive() {
     let hashed_two = hash_key("Coat CHECK");
     assert_ne!(hashed_one, hashed_two);
 }
+
+#[test]
+fn hashers_produce_fixed_size_hashes() {
+    let hashers: Vec<Box<dyn KeyHasher>> = vec![
+        Box::new(Md5Hasher),
+        Box::new(Sha256Hasher),
+        Box::new(SipHasher { seed: [7; 16] }),
+    ];
+    for hasher in hashers {
+        for key in ["", "a", "a much longer key than the others"] {
+            assert_eq!(hasher.hash_key(key).len(), hasher.hash_size());
+        }
+        assert_eq!(hash_size(hasher.id()), Ok(hasher.hash_size()));
+    }
+
+    // the seed changes every hash
+    let seeded = SipHasher { seed: [7; 16] };
+    let reseeded = SipHasher { seed: [8; 16] };
+    assert_eq!(seeded.hash_key("foo"), seeded.hash_key("foo"));
+    assert_ne!(seeded.hash_key("foo"), reseeded.hash_key("foo"));
+}
+
+#[test]
+fn data_file_keeps_the_hasher_it_was_created_with() {
+    for (n, hasher, hash_size) in [
+        (300, HasherKind::Sha256, 64),
+        (301, HasherKind::SipHash, 32),
+    ] {
+        let file_folder = common::generate_test_file(n);
+
+        let store = Store::open_with(
+            file_folder.clone(),
+            StoreOptions {
+                hasher,
+                ..StoreOptions::default()
+            },
+        )
+        .unwrap();
+        assert!(store.set("a", b"alpha").is_ok());
+        assert!(store.set("b", b"bravo").is_ok());
+        assert!(store.delete("a").is_ok());
+        let record_size = hash_size + 8 + 1 + 4 + 4 + 8 + 1 + 5;
+        assert_eq!(
+            std::fs::metadata(&file_folder).unwrap().len(),
+            32 + 2 * record_size as u64
+        );
+
+        // opened again, with the default (md5) settings, the header still says how to hash
+        match read_key(file_folder.clone(), "b") {
+            Ok(bytes) => assert_eq!(bytes, Some(b"bravo".to_vec())),
+            Err(e) => panic!("read failed: {e}"),
+        }
+        assert!(write_key_val(file_folder.clone(), "c", b"charlie").is_ok());
+        assert_eq!(compact(file_folder.clone()), Ok(None));
+        assert_eq!(read_key(file_folder.clone(), "a"), Ok(None));
+        assert_eq!(
+            read_key(file_folder.clone(), "c"),
+            Ok(Some(b"charlie".to_vec()))
+        );
+    }
+}
+
+#[test]
+fn unknown_hasher_is_refused() {
+    let file_folder = common::generate_test_file(302);
+
+    assert!(write_key_val(file_folder.clone(), "a", b"alpha").is_ok());
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(&file_folder)
+        .unwrap();
+    std::os::unix::fs::FileExt::write_at(&file, &[42], 10).unwrap();
+
+    assert_eq!(
+        read_key(file_folder.clone(), "a"),
+        Err(Error::UnsupportedHasher(42))
+    );
+}
@@ -0,0 +1,123 @@
+use coat_check::batch::Batch;
+use coat_check::error::Error;
+use coat_check::file_syscalls::{delete_key, inspect, write_key_val};
+use coat_check::format::RecordKind;
+use coat_check::hasher::hash_key;
+use coat_check::inspect::{Entry, Filter};
+use coat_check::store::Store;
+use std::os::unix::fs::FileExt;
+
+mod common;
+
+// an upsert of "a", a delete of "b", then a batch which deletes "a"
+fn write_records(filepath: &str) {
+    assert!(write_key_val(filepath.to_string(), "a", b"alpha").is_ok());
+    assert!(write_key_val(filepath.to_string(), "b", b"bravo").is_ok());
+    assert!(write_key_val(filepath.to_string(), "a", b"apple").is_ok());
+    assert!(delete_key(filepath.to_string(), "b").is_ok());
+    let mut batch = Batch::new();
+    batch.delete("a");
+    assert!(
+        Store::open(filepath.to_string())
+            .unwrap()
+            .apply(&batch)
+            .is_ok()
+    );
+}
+
+fn kinds(entries: &[Entry]) -> Vec<(u64, RecordKind, bool)> {
+    entries
+        .iter()
+        .map(|entry| (entry.offset, entry.kind, entry.deleted))
+        .collect()
+}
+
+#[test]
+fn inspect_lists_every_record_in_file_order() {
+    let file_folder = common::generate_test_file(1000);
+    write_records(&file_folder);
+
+    let listing =
+        inspect(file_folder.clone(), Filter::All).unwrap_or_else(|e| panic!("inspect failed: {e}"));
+    assert_eq!(listing.stopped, None);
+    let entries = listing.entries;
+    // 63 bytes a value, 57 a marker, and 58 for the tombstone
+    assert_eq!(
+        kinds(&entries),
+        vec![
+            (32, RecordKind::Value, true),
+            (95, RecordKind::Value, true),
+            (158, RecordKind::Value, true),
+            (221, RecordKind::BatchBegin, false),
+            (278, RecordKind::Tombstone, false),
+            (336, RecordKind::BatchCommit, false),
+        ]
+    );
+    assert_eq!(entries[0].hash, hash_key("a"));
+    assert_eq!(entries[2].size, 5);
+    assert_eq!(entries[2].preview, "apple");
+    assert_eq!(
+        entries[4].to_json(),
+        format!(
+            r#"{{"offset":278,"hash":"{}","kind":"tombstone","size":0,"deleted":false,"preview":""}}"#,
+            hash_key("a")
+        )
+    );
+
+    // and just the ones with the deleted flag set, or clear
+    let deleted = inspect(file_folder.clone(), Filter::Deleted)
+        .unwrap()
+        .entries;
+    assert_eq!(deleted.len(), 3);
+    assert!(
+        inspect(file_folder, Filter::Live)
+            .unwrap()
+            .entries
+            .is_empty()
+    );
+}
+
+#[test]
+fn inspect_previews_the_start_of_each_value() {
+    let file_folder = common::generate_test_file(1001);
+    let long = "a \"quoted\" value, which goes on for quite a while";
+    assert!(write_key_val(file_folder.clone(), "long", long.as_bytes()).is_ok());
+    assert!(write_key_val(file_folder.clone(), "tab", b"a\tb").is_ok());
+
+    let entries = inspect(file_folder, Filter::Live).unwrap().entries;
+    assert_eq!(entries[0].preview, "a \"quoted\" value, which goes on ...");
+    assert!(
+        entries[0]
+            .to_string()
+            .ends_with(r#"live     "a \"quoted\" value, which goes on ...""#)
+    );
+    assert!(
+        entries[0]
+            .to_json()
+            .ends_with(r#""preview":"a \"quoted\" value, which goes on ..."}"#)
+    );
+    assert!(entries[1].to_string().ends_with(r#""a\tb""#));
+    assert!(entries[1].to_json().ends_with(r#""preview":"a\u0009b"}"#));
+}
+
+#[test]
+fn inspect_keeps_what_it_read_before_the_damage() {
+    let file_folder = common::generate_test_file(1002);
+    assert!(write_key_val(file_folder.clone(), "a", b"alpha").is_ok());
+    assert!(write_key_val(file_folder.clone(), "b", b"bravo").is_ok());
+    assert!(write_key_val(file_folder.clone(), "c", b"charlie").is_ok());
+    // a size for "b" which runs past the end of the file, with "c" still intact after it
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(&file_folder)
+        .unwrap();
+    file.write_at(&1000u64.to_le_bytes(), 95 + 32).unwrap();
+
+    let listing =
+        inspect(file_folder, Filter::All).unwrap_or_else(|e| panic!("inspect failed: {e}"));
+    assert_eq!(
+        kinds(&listing.entries),
+        vec![(32, RecordKind::Value, false)]
+    );
+    assert_eq!(listing.stopped, Some((95, Error::Corrupt(95))));
+}
@@ -0,0 +1,138 @@
+use coat_check::error::Error;
+use coat_check::file_syscalls::{migrate, read_key, write_key_val};
+use coat_check::hasher::hash_key;
+
+mod common;
+
+// a data file in the legacy layout, `[(hashed) key][size of value][deleted?][value]` with a native-endian size
+fn write_legacy_file(filepath: &str, records: &[(&str, &str, bool)]) {
+    let mut bytes: Vec<u8> = Vec::new();
+    for (key, val, deleted) in records {
+        bytes.extend_from_slice(hash_key(key).as_bytes());
+        bytes.extend_from_slice(&val.len().to_ne_bytes());
+        bytes.push(*deleted as u8);
+        bytes.extend_from_slice(val.as_bytes());
+    }
+    std::fs::write(filepath, bytes).unwrap();
+}
+
+#[test]
+fn migrate_carries_over_live_records() {
+    let src = common::generate_test_file(200);
+    let dst = common::generate_test_file(201);
+
+    write_legacy_file(
+        &src,
+        &[
+            ("a", "alpha", false),
+            ("b", "beta", true),
+            ("c", "gamma", false),
+        ],
+    );
+
+    // the legacy file cannot be read as it is
+    assert_eq!(read_key(src.clone(), "a"), Err(Error::NotDataFile));
+
+    match migrate(src.clone(), dst.clone()) {
+        Ok(records) => assert_eq!(records, 2),
+        Err(e) => panic!("migrate failed: {e}"),
+    }
+    assert_eq!(read_key(dst.clone(), "a"), Ok(Some(b"alpha".to_vec())));
+    assert_eq!(read_key(dst.clone(), "b"), Ok(None));
+    assert_eq!(read_key(dst.clone(), "c"), Ok(Some(b"gamma".to_vec())));
+    assert_eq!(std::fs::metadata(&dst).unwrap().len(), 32 + 58 + 58);
+
+    // the new file is writable as usual
+    assert!(write_key_val(dst.clone(), "b", b"bravo").is_ok());
+    assert_eq!(read_key(dst.clone(), "b"), Ok(Some(b"bravo".to_vec())));
+
+    // and migrating in place works too
+    write_legacy_file(&src, &[("d", "delta", false)]);
+    assert_eq!(migrate(src.clone(), src.clone()), Ok(1));
+    assert_eq!(read_key(src.clone(), "d"), Ok(Some(b"delta".to_vec())));
+}
+
+#[test]
+fn migrate_refuses_damaged_legacy_file() {
+    let src = common::generate_test_file(202);
+    let dst = common::generate_test_file(203);
+
+    write_legacy_file(&src, &[("a", "alpha", false), ("b", "bravo", false)]);
+
+    // the last record runs past the end of the file
+    let file = std::fs::OpenOptions::new().write(true).open(&src).unwrap();
+    file.set_len(46 + 44).unwrap();
+
+    assert_eq!(migrate(src.clone(), dst.clone()), Err(Error::Corrupt(46)));
+    assert!(!std::path::Path::new(&dst).exists());
+}
+
+#[test]
+fn migrate_refuses_a_file_in_the_current_format() {
+    let src = common::generate_test_file(212);
+    let dst = common::generate_test_file(213);
+
+    assert!(write_key_val(src.clone(), "a", b"alpha").is_ok());
+    assert_eq!(
+        migrate(src.clone(), dst.clone()),
+        Err(Error::AlreadyMigrated)
+    );
+    assert_eq!(
+        Error::AlreadyMigrated.to_string(),
+        "data file is already in the current format"
+    );
+    assert!(!std::path::Path::new(&dst).exists());
+    assert_eq!(read_key(src.clone(), "a"), Ok(Some(b"alpha".to_vec())));
+    // (in place too, which leaves it just as it was)
+    assert_eq!(
+        migrate(src.clone(), src.clone()),
+        Err(Error::AlreadyMigrated)
+    );
+    assert_eq!(read_key(src, "a"), Ok(Some(b"alpha".to_vec())));
+}
+
+#[test]
+fn concurrent_migrations_keep_to_their_own_temp_files() {
+    // into the same folder, in the same second
+    let files: Vec<(String, String, String)> = (0..4)
+        .map(|i| {
+            let src = common::generate_test_file(204 + 2 * i);
+            let dst = common::generate_test_file(205 + 2 * i);
+            let val = format!("value-{i}");
+            write_legacy_file(&src, &[("a", &val, false)]);
+            (src, dst, val)
+        })
+        .collect();
+    let handles: Vec<_> = files
+        .iter()
+        .map(|(src, dst, _)| {
+            let (src, dst) = (src.clone(), dst.clone());
+            std::thread::spawn(move || migrate(src, dst))
+        })
+        .collect();
+    for handle in handles {
+        assert_eq!(handle.join().unwrap(), Ok(1));
+    }
+
+    for (_, dst, val) in &files {
+        assert_eq!(
+            read_key(dst.clone(), "a"),
+            Ok(Some(val.clone().into_bytes()))
+        );
+        // and nothing left behind
+        let name = std::path::Path::new(dst)
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .into_owned();
+        let leftovers = std::fs::read_dir("/tmp")
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| {
+                let other = entry.file_name().to_string_lossy().into_owned();
+                other != name && other.starts_with(&name)
+            })
+            .count();
+        assert_eq!(leftovers, 0);
+    }
+}
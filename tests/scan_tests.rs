@@ -0,0 +1,134 @@
+use coat_check::file_syscalls::compact;
+use coat_check::scan::{SCAN_START, glob_match};
+use coat_check::store::{Store, StoreOptions};
+use std::collections::HashMap;
+
+mod common;
+
+#[test]
+fn glob_patterns_match_like_the_shell() {
+    let cases = [
+        ("*", "", true),
+        ("user:*", "user:42", true),
+        ("user:*", "users", false),
+        ("*:*:name", "user:42:name", true),
+        ("h?llo", "hello", true),
+        ("h?llo", "hllo", false),
+        ("h[ae]llo", "hallo", true),
+        ("h[ae]llo", "hillo", false),
+        ("h[^e]llo", "hallo", true),
+        ("h[!e]llo", "hello", false),
+        ("key[0-9]", "key7", true),
+        ("key[0-9]", "keyx", false),
+        ("a[]]b", "a]b", true),
+        ("a\\*b", "a*b", true),
+        ("a\\*b", "axb", false),
+        ("a[b", "a[b", true),
+        ("日本*", "日本語", true),
+    ];
+    for (pattern, key, expected) in cases {
+        assert_eq!(glob_match(pattern, key), expected, "{pattern} {key}");
+    }
+}
+
+#[test]
+fn glob_patterns_with_many_stars_match_in_good_time() {
+    // each `*` retrying every split of the rest of the key would take about 60^12 steps
+    let pattern = format!("{}b", "a*".repeat(12));
+    let key = "a".repeat(60);
+    assert!(!glob_match(&pattern, &key));
+    assert!(glob_match(&pattern, &format!("{key}b")));
+    assert!(glob_match("*a*b*c", "xxaxxbxxbxxc"));
+    assert!(!glob_match("*a*b*c", "xxaxxbxxbxxcx"));
+}
+
+#[test]
+fn keys_lists_the_live_keys_which_match() {
+    let file_folder = common::generate_test_file(1100);
+    let store = Store::open(file_folder.clone()).unwrap();
+    assert_eq!(store.keys("*"), Ok(vec![]));
+
+    assert!(store.set("user:2", b"bob").is_ok());
+    assert!(store.set("user:1", b"alice").is_ok());
+    assert!(store.set("user:3", b"carol").is_ok());
+    assert!(store.set("admin", b"root").is_ok());
+    assert!(store.set("user:1", b"alicia").is_ok());
+    assert!(store.delete("user:3").is_ok());
+    assert!(store.set_ex("user:4", b"gone", 0).is_ok());
+
+    assert_eq!(
+        store.keys("user:*"),
+        Ok(vec![String::from("user:1"), String::from("user:2")])
+    );
+    assert_eq!(store.keys("*").unwrap().len(), 3);
+    assert_eq!(store.keys("nobody"), Ok(vec![]));
+
+    // and just the same once compacted
+    assert!(compact(file_folder).is_ok());
+    assert_eq!(store.keys("*").unwrap().len(), 3);
+}
+
+#[test]
+fn scan_returns_every_key_live_throughout_exactly_once() {
+    let file_folder = common::generate_test_file(1101);
+    let store = Store::open(file_folder.clone()).unwrap();
+    for i in 0..50 {
+        assert!(store.set(&format!("key{i}"), b"value").is_ok());
+    }
+
+    // with writes, deletes and a compaction between the pages
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    let mut cursor = String::from(SCAN_START);
+    let mut pages = 0;
+    loop {
+        let (next, keys) = store
+            .scan_page(&cursor, "key*", 7)
+            .unwrap_or_else(|e| panic!("scan failed: {e}"));
+        assert!(keys.len() <= 7);
+        for key in keys {
+            *seen.entry(key).or_default() += 1;
+        }
+        pages += 1;
+        assert!(store.set(&format!("new{pages}"), b"value").is_ok());
+        assert!(store.set(&format!("key{pages}"), b"updated").is_ok());
+        assert!(store.delete(&format!("key{}", 49 - pages)).is_ok());
+        if pages == 3 {
+            assert!(store.compact().is_ok());
+        }
+        if next == SCAN_START {
+            break;
+        }
+        cursor = next;
+    }
+    assert!(pages > 1);
+    assert!(seen.values().all(|n| *n == 1));
+    assert!(seen.keys().all(|key| key.starts_with("key")));
+    // every key which was never deleted, updated or not
+    for i in 0..49 - pages {
+        assert!(seen.contains_key(&format!("key{i}")), "key{i}");
+    }
+}
+
+#[test]
+fn scan_iterates_over_every_segment() {
+    let dir = common::generate_test_file(1102);
+    let options = StoreOptions {
+        segment_size: Some(200),
+        ..StoreOptions::default()
+    };
+    let store = Store::open_with(dir, options).unwrap();
+    for i in 0..20 {
+        assert!(store.set(&format!("key{i}"), b"value").is_ok());
+    }
+    assert!(store.set("key0", b"replaced").is_ok());
+    assert!(store.delete("key1").is_ok());
+
+    let mut scanned = store
+        .scan("*", 3)
+        .collect::<Result<Vec<String>, _>>()
+        .unwrap_or_else(|e| panic!("scan failed: {e}"));
+    scanned.sort();
+    assert_eq!(scanned.len(), 19);
+    assert_eq!(store.keys("*"), Ok(scanned));
+    assert_eq!(store.scan("key1?", 1).count(), 10);
+}
@@ -0,0 +1,233 @@
+use coat_check::store::{Stats, Store, StoreOptions};
+use std::sync::Arc;
+use std::thread;
+
+mod common;
+
+// small enough that every few records start a new segment
+fn options() -> StoreOptions {
+    StoreOptions {
+        segment_size: Some(200),
+        ..StoreOptions::default()
+    }
+}
+
+// the segment files in the directory `dir`, oldest first
+fn segment_files(dir: &str) -> Vec<String> {
+    let mut names: Vec<String> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.ends_with(".coat-check"))
+        .collect();
+    names.sort();
+    names
+}
+
+// ten keys over several segments, then an upsert and a delete of keys in the first one
+fn write_records(store: &Store) {
+    for i in 0..10 {
+        assert!(
+            store
+                .set(&format!("key{i}"), format!("value-{i}").as_bytes())
+                .is_ok()
+        );
+    }
+    assert!(store.set("key0", b"replaced").is_ok());
+    assert_eq!(store.delete("key1"), Ok(Some(b"value-1".to_vec())));
+}
+
+fn assert_records(store: &Store) {
+    assert_eq!(store.get("key0"), Ok(Some(b"replaced".to_vec())));
+    assert_eq!(store.get("key1"), Ok(None));
+    for i in 2..10 {
+        assert_eq!(
+            store.get(&format!("key{i}")),
+            Ok(Some(format!("value-{i}").into_bytes()))
+        );
+    }
+}
+
+#[test]
+fn rolls_over_and_reads_newest_first() {
+    let dir = common::generate_test_file(800);
+    let store = Store::open_with(dir.clone(), options()).unwrap();
+    write_records(&store);
+
+    assert!(segment_files(&dir).len() > 2);
+    assert_records(&store);
+    // the delete went in as a tombstone, since the original is in an older segment
+    assert_eq!(store.delete("key1"), Ok(None));
+
+    // and the same again, from the files alone
+    drop(store);
+    assert_records(&Store::open_with(dir, options()).unwrap());
+}
+
+#[test]
+fn compact_merges_the_older_segments() {
+    let dir = common::generate_test_file(801);
+    let store = Store::open_with(dir.clone(), options()).unwrap();
+    write_records(&store);
+    let before = segment_files(&dir);
+
+    assert!(store.compact().is_ok());
+    let after = segment_files(&dir);
+    // the merged segment (numbered as the newest it replaced), plus the active one, untouched
+    assert_eq!(after.len(), 2);
+    assert_eq!(after[0], before[before.len() - 2]);
+    assert_eq!(after[1], before[before.len() - 1]);
+    let merged = std::fs::read(format!("{dir}/{}", after[0])).unwrap();
+    assert!(merged[11] & 0x08 != 0);
+
+    assert_records(&store);
+    assert!(store.set("key10", b"value-10").is_ok());
+    drop(store);
+    let reopened = Store::open_with(dir, options()).unwrap();
+    assert_records(&reopened);
+    assert_eq!(reopened.get("key10"), Ok(Some(b"value-10".to_vec())));
+}
+
+#[test]
+fn writes_carry_on_while_a_merge_runs() {
+    let dir = common::generate_test_file(802);
+    let writer = Store::open_with(dir.clone(), options()).unwrap();
+    assert!(writer.set("seed", b"seed").is_ok());
+
+    // merged by another store on the same directory (as another process would)
+    let merger = Arc::new(Store::open_with(dir.clone(), options()).unwrap());
+    let handle = {
+        let merger = Arc::clone(&merger);
+        thread::spawn(move || {
+            for _ in 0..20 {
+                if let Err(e) = merger.compact() {
+                    panic!("compact failed: {e}");
+                }
+            }
+        })
+    };
+    for i in 0..100 {
+        assert!(
+            writer
+                .set(&format!("key{i}"), format!("value-{i}").as_bytes())
+                .is_ok()
+        );
+        if i % 3 == 0 {
+            assert!(writer.delete(&format!("key{}", i / 2)).is_ok());
+        }
+    }
+    handle.join().unwrap();
+
+    // whatever the interleaving, both of them see every write
+    for store in [&writer, merger.as_ref()] {
+        for i in 0..100 {
+            let deleted = (0..100).any(|j| j % 3 == 0 && j / 2 == i && j >= i);
+            let expected = (!deleted).then(|| format!("value-{i}").into_bytes());
+            assert_eq!(store.get(&format!("key{i}")), Ok(expected), "key{i}");
+        }
+    }
+}
+
+#[test]
+fn leftovers_from_an_interrupted_merge_are_ignored() {
+    let dir = common::generate_test_file(803);
+    let store = Store::open_with(dir.clone(), options()).unwrap();
+    write_records(&store);
+    // more writes, so that the tombstone for key1 ends up in an older segment too
+    for i in 10..16 {
+        assert!(store.set(&format!("key{i}"), b"more").is_ok());
+    }
+    let before: Vec<(String, Vec<u8>)> = segment_files(&dir)
+        .into_iter()
+        .map(|name| {
+            let bytes = std::fs::read(format!("{dir}/{name}")).unwrap();
+            (name, bytes)
+        })
+        .collect();
+
+    assert!(store.compact().is_ok());
+    drop(store);
+
+    // a crash after the merged segment was renamed into place, but before the others were
+    // removed: say the first one survived, with key1 in it, but not the tombstone which deleted it
+    let (name, bytes) = &before[0];
+    assert!(name < &segment_files(&dir)[0]);
+    std::fs::write(format!("{dir}/{name}"), bytes).unwrap();
+
+    let store = Store::open_with(dir.clone(), options()).unwrap();
+    assert_records(&store);
+    // and the next merge clears them away
+    assert!(store.compact().is_ok());
+    assert_eq!(segment_files(&dir).len(), 2);
+    assert_records(&store);
+}
+
+#[test]
+fn garbage_includes_records_replaced_in_newer_segments() {
+    let dir = common::generate_test_file(804);
+    let store = Store::open_with(dir.clone(), options()).unwrap();
+    assert!(store.set("a", b"alpha").is_ok());
+    assert!(store.set("b", b"bravo").is_ok());
+    assert!(store.set("c", b"charlie").is_ok());
+    // (the first segment is full, so these start the second)
+    assert!(store.set("a", b"apple").is_ok());
+    assert!(store.delete("b").is_ok());
+    assert_eq!(segment_files(&dir).len(), 2);
+
+    // only the first segment counts, where just "c" (65 bytes) is still live
+    let garbage = store.garbage();
+    assert_eq!(garbage.live_bytes, 65);
+    assert_eq!(garbage.dead_bytes, 2 * 63);
+
+    assert!(store.compact().is_ok());
+    assert_eq!(store.garbage().dead_bytes, 0);
+    assert_eq!(store.garbage().live_bytes, 65);
+    assert_eq!(store.get("a"), Ok(Some(b"apple".to_vec())));
+    assert_eq!(store.get("b"), Ok(None));
+}
+
+#[test]
+fn stats_cover_every_segment() {
+    let dir = common::generate_test_file(805);
+    let store = Store::open_with(dir.clone(), options()).unwrap();
+    assert!(store.set("a", b"alpha").is_ok());
+    assert!(store.set("b", b"bravo").is_ok());
+    assert!(store.set("c", b"charlie").is_ok());
+    // (the first segment is full, so these start the second)
+    assert!(store.set("a", b"apple").is_ok());
+    assert!(store.delete("b").is_ok());
+    assert_eq!(segment_files(&dir).len(), 2);
+
+    // the tombstone for "b" (58 bytes) counts as deleted, but not the record it deleted
+    let stats = store.stats().unwrap();
+    assert_eq!(
+        stats,
+        Stats {
+            records: 5,
+            live: 2,
+            deleted: 1,
+            live_bytes: 65 + 63,
+            dead_bytes: 2 * 63 + 58,
+            largest_value: 7,
+            file_size: 2 * 32 + 3 * 63 + 65 + 58,
+        }
+    );
+}
+
+#[test]
+fn mget_reads_every_segment() {
+    let dir = common::generate_test_file(806);
+    let store = Store::open_with(dir.clone(), options()).unwrap();
+    write_records(&store);
+    assert!(segment_files(&dir).len() > 2);
+
+    let keys: Vec<String> = (0..11).map(|i| format!("key{i}")).collect();
+    let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+    let mut expected: Vec<Option<Vec<u8>>> = (0..10)
+        .map(|i| Some(format!("value-{i}").into_bytes()))
+        .collect();
+    expected[0] = Some(b"replaced".to_vec());
+    expected[1] = None;
+    expected.push(None);
+    assert_eq!(store.mget(&keys), Ok(expected));
+    assert_eq!(store.exists(&keys), Ok(9));
+}
@@ -1,14 +1,26 @@
-use coat_check::server::Server;
+use coat_check::server::{AutoCompact, Server};
+use coat_check::signal_syscalls::COMPACT_SIGNALED;
+use coat_check::store::{Store, StoreOptions};
 use std::io::{Read, Write};
 use std::net::TcpStream;
+use std::sync::atomic::Ordering;
 use std::{thread, time};
 
 mod common;
 
 fn test_harness(n: i32, actions: Vec<String>, expectations: Vec<String>) {
+    start_server(n, None);
+    converse(n, actions, expectations);
+}
+
+// returns the path of the data file
+fn start_server(n: i32, auto_compact: Option<AutoCompact>) -> String {
+    let filepath = common::generate_test_file(n);
     let server = Server {
         port: 5000 + n as u16,
-        filepath: common::generate_test_file(n),
+        filepath: filepath.clone(),
+        options: StoreOptions::default(),
+        auto_compact,
     };
     // server start() never returns, so spin it up in the background
     thread::spawn(move || {
@@ -17,7 +29,10 @@ fn test_harness(n: i32, actions: Vec<String>, expectations: Vec<String>) {
 
     // pause long enough to have the server start accepting connections
     thread::sleep(time::Duration::from_millis(100));
+    filepath
+}
 
+fn converse(n: i32, actions: Vec<String>, expectations: Vec<String>) {
     // run the tests as the client
     let mut stream = TcpStream::connect(format!("127.0.0.1:{}", 5000 + n)).unwrap();
 
@@ -33,7 +48,7 @@ fn test_harness(n: i32, actions: Vec<String>, expectations: Vec<String>) {
         stream.write_all(&buf).unwrap();
 
         // read the server reply
-        stream.read(&mut buf).unwrap();
+        _ = stream.read(&mut buf).unwrap();
         read_size = buf
             .clone()
             .iter()
@@ -53,7 +68,7 @@ fn test_harness(n: i32, actions: Vec<String>, expectations: Vec<String>) {
 #[test]
 fn server_write_then_read_key_works() {
     let actions = ["set foo my value", "get foo"];
-    let expectations = ["*** success: wrote 49 bytes", "my value"];
+    let expectations = ["*** success: wrote 68 bytes", "my value"];
 
     test_harness(
         1,
@@ -71,8 +86,8 @@ fn server_duplicate_key_writes_upsert() {
         "set foo 한국어 키보드",
     ];
     let expectations = [
-        "*** success: wrote 49 bytes",
-        "*** success: wrote 60 bytes",
+        "*** success: wrote 68 bytes",
+        "*** success: wrote 79 bytes",
         "한국어 키보드",
         "*** success: wrote 0 bytes",
     ];
@@ -87,7 +102,7 @@ fn server_duplicate_key_writes_upsert() {
 #[test]
 fn server_unknown_key_no_match() {
     let actions = ["set foo my value", "get foobar"];
-    let expectations = ["*** success: wrote 49 bytes", "*** no match found"];
+    let expectations = ["*** success: wrote 68 bytes", "*** no match found"];
 
     test_harness(
         3,
@@ -100,7 +115,7 @@ fn server_unknown_key_no_match() {
 fn server_delete_key_works() {
     let actions = ["set foo my value", "get foo", "del foo", "get foo"];
     let expectations = [
-        "*** success: wrote 49 bytes",
+        "*** success: wrote 68 bytes",
         "my value",
         "my value",
         "*** no match found",
@@ -124,3 +139,326 @@ fn server_invalid_command_warning() {
         expectations.iter().map(|&s| s.into()).collect(),
     );
 }
+
+#[test]
+fn server_set_with_expiry() {
+    let actions = [
+        "set foo my value EX 100",
+        "get foo",
+        "persist foo",
+        "ttl foo",
+        "set bar EX 0",
+        "ttl bar",
+        "set baz b EX 0",
+        "get baz",
+    ];
+    let expectations = [
+        "*** success: wrote 68 bytes",
+        "my value",
+        "*** success: expiry removed",
+        "*** no expiry",
+        "*** success: wrote 64 bytes",
+        "*** no expiry",
+        "*** success: wrote 61 bytes",
+        "*** no match found",
+    ];
+
+    test_harness(
+        6,
+        actions.iter().map(|&s| s.into()).collect(),
+        expectations.iter().map(|&s| s.into()).collect(),
+    );
+}
+
+#[test]
+fn server_compare_and_swap() {
+    let actions = [
+        "set foo one",
+        "cas foo two three",
+        "get foo",
+        "cas foo one two",
+        "get foo",
+        "cas bar one two",
+    ];
+    let expectations = [
+        "*** success: wrote 63 bytes",
+        "*** mismatch",
+        "one",
+        "*** success: swapped",
+        "two",
+        "*** mismatch",
+    ];
+
+    test_harness(
+        7,
+        actions.iter().map(|&s| s.into()).collect(),
+        expectations.iter().map(|&s| s.into()).collect(),
+    );
+}
+
+#[test]
+fn server_multi_exec_batch() {
+    let actions = [
+        "set foo one",
+        "multi",
+        "set foo two",
+        "set bar three",
+        "del foo",
+        "get bar",
+        "exec",
+        "get foo",
+        "get bar",
+        "multi",
+        "set baz four",
+        "discard",
+        "get baz",
+        "exec",
+    ];
+    let expectations = [
+        "*** success: wrote 63 bytes",
+        "*** ok: batch started",
+        "*** queued",
+        "*** queued",
+        "*** queued",
+        "*** no match found",
+        "*** success: wrote 302 bytes",
+        "*** no match found",
+        "three",
+        "*** ok: batch started",
+        "*** queued",
+        "*** success: discarded 1 commands",
+        "*** no match found",
+        "*** error: no batch started",
+    ];
+
+    test_harness(
+        8,
+        actions.iter().map(|&s| s.into()).collect(),
+        expectations.iter().map(|&s| s.into()).collect(),
+    );
+}
+
+#[test]
+fn server_compacts_by_itself_once_there_is_enough_garbage() {
+    let n = 9;
+    let filepath = start_server(
+        n,
+        Some(AutoCompact {
+            dead_ratio: Some(0.5),
+            dead_bytes: None,
+            min_interval: time::Duration::from_millis(20),
+        }),
+    );
+
+    // one live record of 64 bytes, and nine dead ones
+    let actions: Vec<String> = (0..10).map(|i| format!("set k value{i}")).collect();
+    let expectations = vec![String::from("*** success: wrote 64 bytes"); 10];
+    converse(n, actions, expectations);
+
+    thread::sleep(time::Duration::from_millis(300));
+    assert_eq!(std::fs::metadata(&filepath).unwrap().len(), 32 + 64);
+}
+
+#[test]
+fn server_keeps_serving_when_asked_to_compact() {
+    let n = 10;
+    start_server(n, None);
+
+    // as SIGUSR2 would: before there is even a data file to compact, so the compaction fails
+    COMPACT_SIGNALED.store(true, Ordering::Relaxed);
+    let actions = vec![String::from("set foo bar"), String::from("get foo")];
+    let expectations = vec![
+        String::from("*** success: wrote 63 bytes"),
+        String::from("bar"),
+    ];
+    converse(n, actions, expectations);
+
+    // and the request was taken by the compactor thread (of one server or another)
+    thread::sleep(time::Duration::from_millis(300));
+    assert!(!COMPACT_SIGNALED.load(Ordering::Relaxed));
+    let actions = vec![String::from("set foo baz"), String::from("get foo")];
+    let expectations = vec![
+        String::from("*** success: wrote 63 bytes"),
+        String::from("baz"),
+    ];
+    converse(n, actions, expectations);
+}
+
+#[test]
+fn server_reports_stats() {
+    let actions = ["set foo bar", "set foo baz", "stats"];
+    let expectations = [
+        "*** success: wrote 63 bytes",
+        "*** success: wrote 63 bytes",
+        r#"{"records":2,"live":1,"deleted":1,"live_bytes":63,"dead_bytes":63,"largest_value":3,"file_size":158}"#,
+    ];
+    test_harness(
+        11,
+        actions.iter().map(|&s| s.into()).collect(),
+        expectations.iter().map(|&s| s.into()).collect(),
+    );
+}
+
+#[test]
+fn server_counts_with_incr_and_decr() {
+    let actions = [
+        "incr hits",
+        "incr hits 10",
+        "decr hits 3",
+        "decr hits",
+        "incr hits lots",
+        "set name coat",
+        "incr name",
+        "get hits",
+    ];
+    let expectations = [
+        "1",
+        "11",
+        "8",
+        "7",
+        "*** error: invalid delta",
+        "*** success: wrote 65 bytes",
+        "*** error: \"value is not an integer, or out of range\"",
+        "7",
+    ];
+    test_harness(
+        12,
+        actions.iter().map(|&s| s.into()).collect(),
+        expectations.iter().map(|&s| s.into()).collect(),
+    );
+}
+
+#[test]
+fn server_appends_to_a_value() {
+    let actions = [
+        "append log started",
+        "append log  and then stopped",
+        "get log",
+        "multi",
+        "append log again",
+        "discard",
+    ];
+    let expectations = [
+        "7",
+        "24",
+        "started and then stopped",
+        "*** ok: batch started",
+        "*** error: append cannot be batched",
+        "*** success: discarded 0 commands",
+    ];
+    test_harness(
+        13,
+        actions.iter().map(|&s| s.into()).collect(),
+        expectations.iter().map(|&s| s.into()).collect(),
+    );
+}
+
+// send `action`, returning the whole reply, however many lines it runs to
+fn ask(stream: &mut TcpStream, action: &str) -> String {
+    let mut buf = [0u8; 1024];
+    let l = action.len();
+    buf[0..l].copy_from_slice(action.as_bytes());
+    buf[l..l + 2].copy_from_slice(b"\r\n");
+    stream.write_all(&buf).unwrap();
+
+    buf.fill(0);
+    _ = stream.read(&mut buf).unwrap();
+    let read_size = buf.iter().take_while(|c| **c != 0).count();
+    String::from_utf8(buf[0..read_size].to_vec()).unwrap()
+}
+
+#[test]
+fn server_handles_many_keys_at_once() {
+    start_server(14, None);
+    let mut stream = TcpStream::connect("127.0.0.1:5014").unwrap();
+
+    assert_eq!(
+        ask(&mut stream, "mset a alpha b bravo"),
+        "*** success: wrote 240 bytes\r\n"
+    );
+    assert_eq!(
+        ask(&mut stream, "mget b missing a"),
+        "bravo\r\n*** no match found\r\nalpha\r\n"
+    );
+    assert_eq!(ask(&mut stream, "exists a b missing"), "2\r\n");
+    // a key without its value
+    assert!(ask(&mut stream, "mset a").starts_with("*** invalid command"));
+
+    // and queued up, like any other set
+    assert_eq!(ask(&mut stream, "multi"), "*** ok: batch started\r\n");
+    assert_eq!(ask(&mut stream, "mset a apple c charlie"), "*** queued\r\n");
+    assert_eq!(ask(&mut stream, "exec"), "*** success: wrote 242 bytes\r\n");
+    assert_eq!(
+        ask(&mut stream, "mget a b c"),
+        "apple\r\nbravo\r\ncharlie\r\n"
+    );
+}
+
+#[test]
+fn server_lists_keys() {
+    start_server(15, None);
+    let mut stream = TcpStream::connect("127.0.0.1:5015").unwrap();
+
+    assert_eq!(ask(&mut stream, "keys *"), "*** no match found\r\n");
+    assert!(ask(&mut stream, "mset user:1 alice user:2 bob admin root").starts_with("*** success"));
+    assert_eq!(ask(&mut stream, "keys user:*"), "user:1\r\nuser:2\r\n");
+
+    // page by page, the cursor first
+    let mut cursor = String::from("0");
+    let mut keys: Vec<String> = Vec::new();
+    loop {
+        let reply = ask(&mut stream, &format!("scan {cursor} MATCH * COUNT 1"));
+        let mut lines: Vec<String> = reply.lines().map(String::from).collect();
+        cursor = lines.remove(0);
+        assert!(lines.len() <= 1);
+        keys.extend(lines);
+        if cursor == "0" {
+            break;
+        }
+    }
+    keys.sort();
+    assert_eq!(keys, vec!["admin", "user:1", "user:2"]);
+
+    assert_eq!(
+        ask(&mut stream, "scan 0 count 10 match a*"),
+        "0\r\nadmin\r\n"
+    );
+    assert_eq!(
+        ask(&mut stream, "scan 0 COUNT none"),
+        "*** error: invalid scan options\r\n"
+    );
+}
+
+#[test]
+fn server_refuses_values_too_long_to_send_back() {
+    let filepath = start_server(16, None);
+    let mut stream = TcpStream::connect("127.0.0.1:5016").unwrap();
+
+    let long = "x".repeat(1000);
+    assert_eq!(ask(&mut stream, &format!("append big {long}")), "1000\r\n");
+    assert_eq!(
+        ask(&mut stream, &format!("append big {}", "y".repeat(23))),
+        "*** error: \"value would be longer than 1022 bytes\"\r\n"
+    );
+    assert_eq!(
+        ask(&mut stream, &format!("append big {}", "y".repeat(22))),
+        "1022\r\n"
+    );
+    assert_eq!(
+        ask(&mut stream, "get big"),
+        format!("{long}{}\r\n", "y".repeat(22))
+    );
+
+    // written some other way, a value can still be too long, so that it cannot be sent whole
+    let store = Store::open(filepath).unwrap();
+    assert!(store.set("huge", &[b'z'; 2000]).is_ok());
+    assert_eq!(
+        ask(&mut stream, "get huge"),
+        "*** error: reply too long\r\n"
+    );
+    assert_eq!(
+        ask(&mut stream, "del huge"),
+        "*** error: reply too long\r\n"
+    );
+    assert_eq!(ask(&mut stream, "get huge"), "*** no match found\r\n");
+}
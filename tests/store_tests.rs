@@ -0,0 +1,520 @@
+use coat_check::error::Error;
+use coat_check::file_syscalls::{
+    append_key_val, cas_key_val, compact, decr_key, delete_key, incr_key, inspect, read_key, stats,
+    verify, write_key_val,
+};
+use coat_check::format::{FileHeader, encode_record};
+use coat_check::hasher::hash_key;
+use coat_check::inspect::Filter;
+use coat_check::store::{Garbage, Stats, Store, StoreOptions, Ttl};
+use nix::fcntl::{Flock, FlockArg};
+use std::io::Write;
+use std::sync::{Arc, mpsc};
+use std::thread;
+use std::time::Duration;
+
+mod common;
+
+#[test]
+fn store_indexes_existing_file_on_open() {
+    let file_folder = common::generate_test_file(100);
+
+    let keys = ["uno", "dos", "tres"];
+    for key in keys {
+        assert!(write_key_val(file_folder.clone(), key, key.to_uppercase().as_bytes()).is_ok());
+    }
+
+    // a store opened afterwards finds everything written before it
+    let store = Store::open(file_folder.clone()).unwrap();
+    for key in keys {
+        match store.get(key) {
+            Ok(bytes) => match bytes {
+                Some(value_vector) => assert_eq!(value_vector, key.to_uppercase().as_bytes()),
+                None => panic!("no value for {key}"),
+            },
+            Err(e) => panic!("get failed: {e}"),
+        }
+    }
+    assert_eq!(store.get("cuatro"), Ok(None));
+}
+
+#[test]
+fn store_sees_changes_made_through_other_handles() {
+    let file_folder = common::generate_test_file(101);
+
+    let store = Store::open(file_folder.clone()).unwrap();
+    assert!(store.set("foo", b"first").is_ok());
+
+    // an append by someone else gets picked up by the existing keydir
+    assert!(write_key_val(file_folder.clone(), "bar", b"second").is_ok());
+    assert_eq!(store.get("bar"), Ok(Some(b"second".to_vec())));
+
+    // as does a delete, even though it only flips a flag in place
+    assert_eq!(
+        delete_key(file_folder.clone(), "foo"),
+        Ok(Some(b"first".to_vec()))
+    );
+    assert_eq!(store.get("foo"), Ok(None));
+
+    // and an upsert
+    assert!(write_key_val(file_folder.clone(), "bar", b"third").is_ok());
+    assert_eq!(store.get("bar"), Ok(Some(b"third".to_vec())));
+}
+
+#[test]
+fn store_keydir_follows_compaction() {
+    let file_folder = common::generate_test_file(102);
+
+    let store = Store::open(file_folder.clone()).unwrap();
+    for (key, val) in [("a", "alpha"), ("b", "beta"), ("c", "gamma")] {
+        assert!(store.set(key, val.as_bytes()).is_ok());
+    }
+    assert!(store.set("b", b"bravo").is_ok());
+    assert!(store.delete("a").is_ok());
+
+    // compacting through the store hands it the new offsets directly
+    assert_eq!(store.compact(), Ok(None));
+    assert_eq!(store.get("a"), Ok(None));
+    assert_eq!(store.get("b"), Ok(Some(b"bravo".to_vec())));
+    assert_eq!(store.get("c"), Ok(Some(b"gamma".to_vec())));
+
+    // compacting behind its back means the keydir has to be rebuilt from the new file
+    assert!(store.delete("c").is_ok());
+    assert_eq!(compact(file_folder.clone()), Ok(None));
+    assert_eq!(store.get("b"), Ok(Some(b"bravo".to_vec())));
+    assert_eq!(store.get("c"), Ok(None));
+}
+
+#[test]
+fn compaction_leaves_hint_file_for_next_open() {
+    let file_folder = common::generate_test_file(103);
+    let hint_file = format!("{file_folder}.hint");
+
+    for (key, val) in [("a", "alpha"), ("b", "beta"), ("c", "gamma")] {
+        assert!(write_key_val(file_folder.clone(), key, val.as_bytes()).is_ok());
+    }
+    assert!(delete_key(file_folder.clone(), "a").is_ok());
+    assert_eq!(compact(file_folder.clone()), Ok(None));
+    assert!(std::path::Path::new(&hint_file).exists());
+    assert!(!std::path::Path::new(&format!("{hint_file}.tmp")).exists());
+
+    // records appended after the compaction are not in the hint, so get scanned on open
+    assert!(write_key_val(file_folder.clone(), "d", b"delta").is_ok());
+    assert!(write_key_val(file_folder.clone(), "b", b"bravo").is_ok());
+
+    let store = Store::open(file_folder.clone()).unwrap();
+    assert_eq!(store.get("a"), Ok(None));
+    assert_eq!(store.get("b"), Ok(Some(b"bravo".to_vec())));
+    assert_eq!(store.get("c"), Ok(Some(b"gamma".to_vec())));
+    assert_eq!(store.get("d"), Ok(Some(b"delta".to_vec())));
+}
+
+#[test]
+fn hint_file_which_cannot_be_swapped_in_leaves_nothing_behind() {
+    let file_folder = common::generate_test_file(117);
+    let hint_file = format!("{file_folder}.hint");
+    // (a directory, with something in it, cannot be renamed over)
+    std::fs::create_dir_all(format!("{hint_file}/in-the-way")).unwrap();
+
+    assert!(write_key_val(file_folder.clone(), "a", b"alpha").is_ok());
+    assert_eq!(compact(file_folder.clone()), Ok(None));
+    assert!(!std::path::Path::new(&format!("{hint_file}.tmp")).exists());
+    assert_eq!(read_key(file_folder, "a"), Ok(Some(b"alpha".to_vec())));
+    std::fs::remove_dir_all(hint_file).unwrap();
+}
+
+#[test]
+fn stale_or_damaged_hint_file_falls_back_to_full_scan() {
+    let file_folder = common::generate_test_file(104);
+    let hint_file = format!("{file_folder}.hint");
+
+    assert!(write_key_val(file_folder.clone(), "a", b"alpha").is_ok());
+    assert_eq!(compact(file_folder.clone()), Ok(None));
+    let old_hint = std::fs::read(&hint_file).unwrap();
+
+    // a hint left over from before the latest compaction describes a different file
+    assert!(write_key_val(file_folder.clone(), "b", b"beta").is_ok());
+    assert!(delete_key(file_folder.clone(), "a").is_ok());
+    assert_eq!(compact(file_folder.clone()), Ok(None));
+    std::fs::write(&hint_file, old_hint).unwrap();
+
+    let store = Store::open(file_folder.clone()).unwrap();
+    assert_eq!(store.get("a"), Ok(None));
+    assert_eq!(store.get("b"), Ok(Some(b"beta".to_vec())));
+
+    // and one that is just garbage gets ignored
+    std::fs::write(&hint_file, b"not a hint file").unwrap();
+    let store = Store::open(file_folder.clone()).unwrap();
+    assert_eq!(store.get("b"), Ok(Some(b"beta".to_vec())));
+}
+
+#[test]
+fn different_key_with_the_same_hash_is_detected() {
+    let file_folder = common::generate_test_file(105);
+
+    // a record for "zulu" which (as if by an md5 collision) hashes the same as "alpha"
+    let file_header = FileHeader::current();
+    let mut bytes = file_header.encode().to_vec();
+    bytes.extend(encode_record(
+        &file_header,
+        &hash_key("alpha"),
+        b"zulu",
+        b"z",
+        None,
+    ));
+    std::fs::write(&file_folder, bytes).unwrap();
+
+    let store = Store::open(file_folder.clone()).unwrap();
+    assert_eq!(store.get("alpha"), Err(Error::KeyCollision));
+    assert_eq!(store.delete("alpha"), Err(Error::KeyCollision));
+
+    // and the record already there does not get overwritten
+    assert_eq!(store.set("alpha", b"a"), Err(Error::KeyCollision));
+    assert_eq!(std::fs::metadata(&file_folder).unwrap().len(), 32 + 62);
+}
+
+#[test]
+fn concurrent_upserts_of_one_key_leave_one_live_record() {
+    let file_folder = common::generate_test_file(106);
+    let store = Arc::new(Store::open(file_folder.clone()).unwrap());
+
+    // half the threads share the one store (as the server does), the rest each open their own
+    let mut handles = Vec::new();
+    for t in 0..16 {
+        let store = Arc::clone(&store);
+        let file_folder = file_folder.clone();
+        handles.push(thread::spawn(move || {
+            for i in 0..25 {
+                let val = format!("{t:02}-{i:02}");
+                let written = match t % 2 {
+                    0 => store.set("hot", val.as_bytes()),
+                    _ => write_key_val(file_folder.clone(), "hot", val.as_bytes()),
+                };
+                assert!(written.is_ok());
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    // every upsert but the last flagged its predecessor as deleted, leaving a single live record
+    // (checked before any compaction, which would keep only the latest record of the key anyway)
+    let live = inspect(file_folder.clone(), Filter::Live)
+        .unwrap_or_else(|e| panic!("inspect failed: {e}"))
+        .entries;
+    assert_eq!(live.len(), 1);
+    assert_eq!(live[0].hash, hash_key("hot"));
+    assert_eq!(
+        store.get("hot"),
+        Ok(Some(live[0].preview.clone().into_bytes()))
+    );
+    match verify(file_folder) {
+        Ok(report) => assert_eq!(report.problems, vec![]),
+        Err(e) => panic!("verify failed: {e}"),
+    }
+}
+
+#[test]
+fn cas_only_swaps_the_expected_value() {
+    let file_folder = common::generate_test_file(107);
+    let store = Store::open(file_folder.clone()).unwrap();
+
+    // nothing to compare against yet
+    assert_eq!(store.cas("foo", b"", b"first"), Ok(false));
+    assert_eq!(store.get("foo"), Ok(None));
+
+    assert!(store.set("foo", b"first").is_ok());
+    assert_eq!(store.cas("foo", b"wrong", b"second"), Ok(false));
+    assert_eq!(store.get("foo"), Ok(Some(b"first".to_vec())));
+    assert_eq!(
+        cas_key_val(file_folder.clone(), "foo", b"first", b"second"),
+        Ok(true)
+    );
+    assert_eq!(store.get("foo"), Ok(Some(b"second".to_vec())));
+
+    // and a deleted key no longer matches its last value
+    assert!(store.delete("foo").is_ok());
+    assert_eq!(store.cas("foo", b"second", b"third"), Ok(false));
+}
+
+#[test]
+fn concurrent_cas_loses_no_updates() {
+    let file_folder = common::generate_test_file(108);
+    let store = Arc::new(Store::open(file_folder.clone()).unwrap());
+    assert!(store.set("counter", b"0").is_ok());
+
+    // every thread keeps retrying its read-modify-write until nobody got in first
+    let mut handles = Vec::new();
+    for _ in 0..8 {
+        let store = Arc::clone(&store);
+        handles.push(thread::spawn(move || {
+            for _ in 0..20 {
+                loop {
+                    let current = store.get("counter").unwrap().unwrap();
+                    let n: u64 = str::from_utf8(&current).unwrap().parse().unwrap();
+                    let next = (n + 1).to_string();
+                    if store.cas("counter", &current, next.as_bytes()).unwrap() {
+                        break;
+                    }
+                }
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.get("counter"), Ok(Some(b"160".to_vec())));
+}
+
+#[test]
+fn garbage_counts_what_compaction_reclaims() {
+    let file_folder = common::generate_test_file(109);
+    let store = Store::open(file_folder.clone()).unwrap();
+    assert_eq!(store.garbage(), Garbage::default());
+
+    // 63 bytes a record: the replaced and deleted ones are garbage
+    assert!(store.set("a", b"alpha").is_ok());
+    assert!(store.set("a", b"apple").is_ok());
+    assert!(store.set("b", b"bravo").is_ok());
+    assert!(store.delete("b").is_ok());
+    let garbage = Garbage {
+        live_bytes: 63,
+        dead_bytes: 126,
+    };
+    assert_eq!(store.garbage(), garbage);
+    assert!((garbage.ratio() - 2.0 / 3.0).abs() < 1e-9);
+
+    // so much for the garbage, whether the keydir comes from a scan, or from the hint file
+    assert!(store.compact().is_ok());
+    let compacted = Garbage {
+        live_bytes: 63,
+        dead_bytes: 0,
+    };
+    assert_eq!(store.garbage(), compacted);
+    assert_eq!(Store::open(file_folder).unwrap().garbage(), compacted);
+}
+
+#[test]
+fn stats_count_up_every_record() {
+    let file_folder = common::generate_test_file(110);
+    assert_eq!(stats(file_folder.clone()), Ok(Stats::default()));
+
+    assert!(write_key_val(file_folder.clone(), "a", b"alpha").is_ok());
+    assert!(write_key_val(file_folder.clone(), "a", b"apple").is_ok());
+    assert!(write_key_val(file_folder.clone(), "b", b"bravo").is_ok());
+    assert!(write_key_val(file_folder.clone(), "c", b"charlie").is_ok());
+    assert!(delete_key(file_folder.clone(), "b").is_ok());
+    let expected = Stats {
+        records: 4,
+        live: 2,
+        deleted: 2,
+        live_bytes: 63 + 65,
+        dead_bytes: 2 * 63,
+        largest_value: 7,
+        file_size: 32 + 3 * 63 + 65,
+    };
+    assert_eq!(stats(file_folder.clone()), Ok(expected));
+    assert_eq!(
+        expected.to_json(),
+        r#"{"records":4,"live":2,"deleted":2,"live_bytes":128,"dead_bytes":126,"largest_value":7,"file_size":286}"#
+    );
+
+    // and what compaction leaves behind is all live
+    assert!(compact(file_folder.clone()).is_ok());
+    let compacted = Stats {
+        records: 2,
+        live: 2,
+        deleted: 0,
+        live_bytes: 63 + 65,
+        dead_bytes: 0,
+        largest_value: 7,
+        file_size: 32 + 63 + 65,
+    };
+    assert_eq!(stats(file_folder), Ok(compacted));
+}
+
+#[test]
+fn incr_and_decr_count_from_zero() {
+    let file_folder = common::generate_test_file(111);
+    let store = Store::open(file_folder.clone()).unwrap();
+
+    // a missing key starts at 0
+    assert_eq!(store.incr("hits", 1), Ok(1));
+    assert_eq!(incr_key(file_folder.clone(), "hits", 10), Ok(11));
+    assert_eq!(decr_key(file_folder.clone(), "hits", 20), Ok(-9));
+    assert_eq!(store.decr("misses", 1), Ok(-1));
+    assert_eq!(store.get("hits"), Ok(Some(b"-9".to_vec())));
+
+    // only ever an integer, which can neither overflow nor be counted if it is not one
+    assert!(store.set("name", b"coat").is_ok());
+    assert_eq!(store.incr("name", 1), Err(Error::NotInteger));
+    assert!(store.set("max", i64::MAX.to_string().as_bytes()).is_ok());
+    assert_eq!(store.incr("max", 1), Err(Error::NotInteger));
+    assert_eq!(store.decr("max", i64::MIN), Err(Error::NotInteger));
+    assert_eq!(
+        store.get("max"),
+        Ok(Some(i64::MAX.to_string().into_bytes()))
+    );
+
+    // and a counter keeps its expiry
+    assert!(store.set_ex("session", b"5", 100).is_ok());
+    assert_eq!(store.incr("session", 1), Ok(6));
+    assert!(matches!(store.ttl("session"), Ok(Ttl::Seconds(_))));
+}
+
+#[test]
+fn concurrent_incr_loses_no_updates() {
+    let file_folder = common::generate_test_file(112);
+
+    // a store (and so a file descriptor) each, as if every thread were a process of its own
+    let mut handles = Vec::new();
+    for _ in 0..8 {
+        let file_folder = file_folder.clone();
+        handles.push(thread::spawn(move || {
+            let store = Store::open(file_folder).unwrap();
+            for _ in 0..20 {
+                if let Err(e) = store.incr("counter", 2) {
+                    panic!("incr failed: {e}");
+                }
+                assert!(store.decr("counter", 1).is_ok());
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(
+        Store::open(file_folder).unwrap().get("counter"),
+        Ok(Some(b"160".to_vec()))
+    );
+}
+
+#[test]
+fn append_extends_the_value() {
+    let file_folder = common::generate_test_file(113);
+    let store = Store::open(file_folder.clone()).unwrap();
+
+    // a missing key starts out empty
+    assert_eq!(store.append("log", b"started;"), Ok(8));
+    assert_eq!(
+        append_key_val(file_folder.clone(), "log", b"stopped;"),
+        Ok(16)
+    );
+    assert_eq!(store.get("log"), Ok(Some(b"started;stopped;".to_vec())));
+    // appending nothing writes nothing
+    let len = std::fs::metadata(&file_folder).unwrap().len();
+    assert_eq!(store.append("log", b""), Ok(16));
+    assert_eq!(std::fs::metadata(&file_folder).unwrap().len(), len);
+
+    // and the expiry time stays with the value
+    assert!(store.set_ex("session", b"a", 100).is_ok());
+    assert_eq!(store.append("session", b"b"), Ok(2));
+    assert!(matches!(store.ttl("session"), Ok(Ttl::Seconds(_))));
+}
+
+#[test]
+fn concurrent_appends_lose_no_lines() {
+    let file_folder = common::generate_test_file(114);
+
+    let mut handles = Vec::new();
+    for t in 0..4 {
+        let file_folder = file_folder.clone();
+        handles.push(thread::spawn(move || {
+            let store = Store::open(file_folder).unwrap();
+            for i in 0..10 {
+                if let Err(e) = store.append("log", format!("{t}:{i};").as_bytes()) {
+                    panic!("append failed: {e}");
+                }
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    let log = Store::open(file_folder)
+        .unwrap()
+        .get("log")
+        .unwrap()
+        .unwrap();
+    let mut lines: Vec<&[u8]> = log
+        .split(|&b| b == b';')
+        .filter(|l| !l.is_empty())
+        .collect();
+    assert_eq!(lines.len(), 40);
+    lines.sort();
+    lines.dedup();
+    assert_eq!(lines.len(), 40);
+}
+
+#[test]
+fn mget_mset_and_exists_take_many_keys() {
+    let file_folder = common::generate_test_file(115);
+    let store = Store::open(file_folder).unwrap();
+
+    // set as one batch: a record each, between the two 57-byte markers
+    assert_eq!(
+        store.mset(&[("a", b"alpha"), ("b", b"bravo"), ("c", b"charlie")]),
+        Ok(57 + 63 + 63 + 65 + 57)
+    );
+    assert!(store.set("a", b"apple").is_ok());
+    assert!(store.delete("b").is_ok());
+
+    // the values come back in the order asked for, not the order they are in the file
+    assert_eq!(
+        store.mget(&["c", "b", "missing", "a", "c"]),
+        Ok(vec![
+            Some(b"charlie".to_vec()),
+            None,
+            None,
+            Some(b"apple".to_vec()),
+            Some(b"charlie".to_vec()),
+        ])
+    );
+    assert_eq!(store.mget(&[]), Ok(vec![]));
+    assert_eq!(store.exists(&["a", "b", "c", "missing"]), Ok(2));
+    assert_eq!(store.exists(&["a", "a"]), Ok(2));
+}
+
+#[test]
+fn read_only_opens_share_the_lock() {
+    let file_folder = common::generate_test_file(116);
+    assert!(write_key_val(file_folder.clone(), "a", b"alpha").is_ok());
+    // a torn append, which only a writer should clear up
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&file_folder)
+        .unwrap();
+    file.write_all(b"torn").unwrap();
+    let len = std::fs::metadata(&file_folder).unwrap().len();
+
+    // another reader, holding a shared lock for as long as this test runs
+    let held = Flock::lock(
+        std::fs::File::open(&file_folder).unwrap(),
+        FlockArg::LockSharedNonblock,
+    )
+    .unwrap();
+    let (sender, receiver) = mpsc::channel();
+    {
+        let file_folder = file_folder.clone();
+        thread::spawn(move || {
+            let options = StoreOptions {
+                read_only: true,
+                ..StoreOptions::default()
+            };
+            let store = Store::open_with(file_folder.clone(), options).unwrap();
+            sender.send(store.get("a")).unwrap();
+            sender.send(read_key(file_folder, "a")).unwrap();
+        });
+    }
+    for _ in 0..2 {
+        let read = receiver.recv_timeout(Duration::from_secs(5));
+        assert_eq!(read, Ok(Ok(Some(b"alpha".to_vec()))));
+    }
+    assert_eq!(std::fs::metadata(&file_folder).unwrap().len(), len);
+    drop(held);
+
+    // and the next writer truncates it
+    assert!(Store::open(file_folder.clone()).is_ok());
+    assert_eq!(std::fs::metadata(&file_folder).unwrap().len(), len - 4);
+}
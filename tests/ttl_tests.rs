@@ -0,0 +1,78 @@
+use coat_check::file_syscalls::{compact, read_key, write_key_val};
+use coat_check::store::{Store, Ttl};
+
+mod common;
+
+#[test]
+fn expired_key_reads_as_missing() {
+    let file_folder = common::generate_test_file(400);
+    let store = Store::open(file_folder.clone()).unwrap();
+
+    // no time to live at all, so it is already expired once written
+    assert!(store.set_ex("a", b"alpha", 0).is_ok());
+    assert!(store.set_ex("b", b"bravo", 3600).is_ok());
+    assert_eq!(store.get("a"), Ok(None));
+    assert_eq!(store.get("b"), Ok(Some(b"bravo".to_vec())));
+    assert_eq!(read_key(file_folder.clone(), "a"), Ok(None));
+    assert_eq!(store.ttl("a"), Ok(Ttl::Missing));
+
+    // and writing it again brings it back, for good this time
+    assert!(store.set("a", b"alpha").is_ok());
+    assert_eq!(store.get("a"), Ok(Some(b"alpha".to_vec())));
+    assert_eq!(store.ttl("a"), Ok(Ttl::Forever));
+}
+
+#[test]
+fn ttl_and_persist() {
+    let file_folder = common::generate_test_file(401);
+    let store = Store::open(file_folder.clone()).unwrap();
+
+    assert_eq!(store.ttl("a"), Ok(Ttl::Missing));
+    assert_eq!(store.persist("a"), Ok(false));
+
+    assert!(store.set_ex("a", b"alpha", 100).is_ok());
+    match store.ttl("a") {
+        Ok(Ttl::Seconds(secs)) => assert!(secs > 90 && secs <= 100),
+        other => panic!("unexpected ttl: {other:?}"),
+    }
+
+    // persisting drops the expiry, but keeps the value
+    assert_eq!(store.persist("a"), Ok(true));
+    assert_eq!(store.ttl("a"), Ok(Ttl::Forever));
+    assert_eq!(store.get("a"), Ok(Some(b"alpha".to_vec())));
+    assert_eq!(store.persist("a"), Ok(false));
+
+    // and setting the same value with a new expiry is not a no-op
+    assert!(store.set_ex("a", b"alpha", 100).unwrap() > 0);
+    assert!(matches!(store.ttl("a"), Ok(Ttl::Seconds(_))));
+    assert_eq!(store.set("a", b"alpha").map(|n| n > 0), Ok(true));
+    assert_eq!(store.ttl("a"), Ok(Ttl::Forever));
+}
+
+#[test]
+fn compaction_drops_expired_records() {
+    let file_folder = common::generate_test_file(402);
+    let store = Store::open(file_folder.clone()).unwrap();
+
+    assert!(store.set_ex("a", b"alpha", 0).is_ok());
+    assert!(store.set_ex("b", b"bravo", 3600).is_ok());
+    assert!(write_key_val(file_folder.clone(), "c", b"charlie").is_ok());
+
+    assert!(compact(file_folder.clone()).is_ok());
+
+    // header + "b" + "c", each with a 1-byte key and an 8-byte expiry
+    assert_eq!(
+        std::fs::metadata(&file_folder).unwrap().len(),
+        32 + (32 + 8 + 1 + 4 + 4 + 8 + 1) * 2 + 5 + 7
+    );
+    assert_eq!(read_key(file_folder.clone(), "a"), Ok(None));
+    assert_eq!(
+        read_key(file_folder.clone(), "b"),
+        Ok(Some(b"bravo".to_vec()))
+    );
+    assert_eq!(
+        read_key(file_folder.clone(), "c"),
+        Ok(Some(b"charlie".to_vec()))
+    );
+    assert!(matches!(store.ttl("b"), Ok(Ttl::Seconds(_))));
+}
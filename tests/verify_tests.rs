@@ -0,0 +1,243 @@
+use coat_check::batch::Batch;
+use coat_check::file_syscalls::{delete_key, read_key, repair, verify, write_key_val};
+use coat_check::hasher::hash_key;
+use coat_check::store::Store;
+use coat_check::verify::{Problem, Report, Salvage};
+use nix::fcntl::{Flock, FlockArg};
+use std::os::unix::fs::FileExt;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+mod common;
+
+// where the size and the deleted flag of a record start, with MD5 hashes
+const SIZE_AT: u64 = 32;
+const STATUS_AT: u64 = 32 + 8;
+
+// "a", "b" and "c", at offsets 32, 95 and 158, up to 223
+fn write_records(filepath: &str) {
+    assert!(write_key_val(filepath.to_string(), "a", b"alpha").is_ok());
+    assert!(write_key_val(filepath.to_string(), "b", b"bravo").is_ok());
+    assert!(write_key_val(filepath.to_string(), "c", b"charlie").is_ok());
+}
+
+fn overwrite(filepath: &str, offset: u64, bytes: &[u8]) {
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(filepath)
+        .unwrap();
+    file.write_at(bytes, offset).unwrap();
+}
+
+#[test]
+fn verify_passes_a_healthy_file() {
+    let file_folder = common::generate_test_file(900);
+    write_records(&file_folder);
+    assert!(write_key_val(file_folder.clone(), "a", b"apple").is_ok());
+    assert!(delete_key(file_folder.clone(), "b").is_ok());
+    let mut batch = Batch::new();
+    batch.set("c", b"cherry").delete("a");
+    assert!(
+        Store::open(file_folder.clone())
+            .unwrap()
+            .apply(&batch)
+            .is_ok()
+    );
+
+    // the four values, then the batch: its markers, the new "c" and the tombstone for "a"
+    match verify(file_folder) {
+        Ok(report) => assert_eq!(
+            report,
+            Report {
+                records: 8,
+                problems: vec![]
+            }
+        ),
+        Err(e) => panic!("verify failed: {e}"),
+    }
+}
+
+#[test]
+fn verify_reports_each_problem_with_its_offset() {
+    let file_folder = common::generate_test_file(901);
+    write_records(&file_folder);
+    overwrite(&file_folder, 157, b"X"); // the last byte of "bravo"
+    overwrite(&file_folder, 158 + STATUS_AT, &[9]);
+    let file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&file_folder)
+        .unwrap();
+    file.write_at(b"0123456789", 223).unwrap();
+
+    let report = verify(file_folder).unwrap();
+    assert!(!report.is_ok());
+    assert_eq!(report.records, 3);
+    assert_eq!(
+        report.problems,
+        vec![
+            Problem::ChecksumMismatch { offset: 95 },
+            Problem::InvalidStatus {
+                offset: 158,
+                status: 9
+            },
+            Problem::Truncated { offset: 223 },
+        ]
+    );
+    assert_eq!(
+        report.problems[2].to_string(),
+        "offset 223: record cut off by the end of the file"
+    );
+}
+
+#[test]
+fn verify_carries_on_past_an_impossible_size() {
+    let file_folder = common::generate_test_file(902);
+    write_records(&file_folder);
+    overwrite(&file_folder, 95 + SIZE_AT, &1000u64.to_le_bytes());
+
+    let report = verify(file_folder).unwrap();
+    assert_eq!(report.records, 2);
+    assert_eq!(
+        report.problems,
+        vec![Problem::ImpossibleSize {
+            offset: 95,
+            size: 1000,
+            next: 158
+        }]
+    );
+}
+
+#[test]
+fn verify_finds_duplicate_live_keys_and_uncommitted_batches() {
+    let file_folder = common::generate_test_file(903);
+    assert!(write_key_val(file_folder.clone(), "a", b"alpha").is_ok());
+    assert!(write_key_val(file_folder.clone(), "a", b"apple").is_ok());
+    // as if a crash had come between the upsert's append and its flagging the original deleted
+    overwrite(&file_folder, 32 + STATUS_AT, &[0]);
+
+    // and a batch whose commit marker (57 bytes, at the very end) never made it
+    let mut batch = Batch::new();
+    batch.set("b", b"bravo").set("c", b"charlie");
+    assert!(
+        Store::open(file_folder.clone())
+            .unwrap()
+            .apply(&batch)
+            .is_ok()
+    );
+    let len = std::fs::metadata(&file_folder).unwrap().len();
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(&file_folder)
+        .unwrap();
+    file.set_len(len - 57).unwrap();
+
+    let report = verify(file_folder).unwrap();
+    assert_eq!(report.records, 5);
+    assert_eq!(
+        report.problems,
+        vec![
+            Problem::DuplicateLiveKey {
+                offset: 95,
+                hash: hash_key("a"),
+                first: 32
+            },
+            Problem::Uncommitted { offset: 158 },
+        ]
+    );
+}
+
+#[test]
+fn repair_salvages_the_live_records_around_the_damage() {
+    let file_folder = common::generate_test_file(904);
+    let repaired = common::generate_test_file(905);
+    write_records(&file_folder);
+    assert!(write_key_val(file_folder.clone(), "d", b"delta").is_ok());
+    assert!(write_key_val(file_folder.clone(), "a", b"apple").is_ok());
+    overwrite(&file_folder, 157, b"X"); // the last byte of "bravo"
+    overwrite(&file_folder, 158 + SIZE_AT, &1000u64.to_le_bytes());
+
+    // "b" and "c" are lost (as one damaged stretch), and the replaced "alpha" stays behind
+    match repair(file_folder.clone(), repaired.clone()) {
+        Ok(salvage) => assert_eq!(
+            salvage,
+            Salvage {
+                salvaged: 2,
+                dropped: 1,
+                skipped: vec![(95, 63 + 65)],
+            }
+        ),
+        Err(e) => panic!("repair failed: {e}"),
+    }
+    assert_eq!(read_key(repaired.clone(), "a"), Ok(Some(b"apple".to_vec())));
+    assert_eq!(read_key(repaired.clone(), "b"), Ok(None));
+    assert_eq!(read_key(repaired.clone(), "c"), Ok(None));
+    assert_eq!(read_key(repaired.clone(), "d"), Ok(Some(b"delta".to_vec())));
+    assert!(verify(repaired).unwrap().is_ok());
+}
+
+#[test]
+fn repair_in_place_keeps_everyone_else_out() {
+    let file_folder = common::generate_test_file(908);
+    write_records(&file_folder);
+    overwrite(&file_folder, 95 + SIZE_AT, &1000u64.to_le_bytes());
+
+    // a reader (or writer) already holding a lock on the data file
+    let held = Flock::lock(
+        std::fs::File::open(&file_folder).unwrap(),
+        FlockArg::LockSharedNonblock,
+    )
+    .unwrap();
+    let (sender, receiver) = mpsc::channel();
+    {
+        let file_folder = file_folder.clone();
+        thread::spawn(move || {
+            // (the same file, by another name)
+            let same = file_folder.replacen("/tmp/", "/tmp/./", 1);
+            sender.send(repair(file_folder, same)).unwrap();
+        });
+    }
+    assert_eq!(
+        receiver.recv_timeout(Duration::from_millis(200)),
+        Err(mpsc::RecvTimeoutError::Timeout)
+    );
+    drop(held);
+
+    let repaired = receiver.recv_timeout(Duration::from_secs(5));
+    assert!(matches!(repaired, Ok(Ok(Salvage { salvaged: 2, .. }))));
+    assert_eq!(
+        read_key(file_folder.clone(), "a"),
+        Ok(Some(b"alpha".to_vec()))
+    );
+    assert_eq!(
+        read_key(file_folder.clone(), "c"),
+        Ok(Some(b"charlie".to_vec()))
+    );
+    assert!(verify(file_folder).unwrap().is_ok());
+}
+
+#[test]
+fn repair_leaves_out_a_batch_whose_commit_was_lost() {
+    let file_folder = common::generate_test_file(906);
+    let repaired = common::generate_test_file(907);
+    assert!(write_key_val(file_folder.clone(), "a", b"alpha").is_ok());
+    let mut batch = Batch::new();
+    batch.set("b", b"bravo").set("c", b"charlie");
+    assert!(
+        Store::open(file_folder.clone())
+            .unwrap()
+            .apply(&batch)
+            .is_ok()
+    );
+
+    // the commit marker, overwritten with junk
+    let len = std::fs::metadata(&file_folder).unwrap().len();
+    overwrite(&file_folder, len - 57, &[b'?'; 57]);
+
+    let salvage = repair(file_folder, repaired.clone()).unwrap();
+    assert_eq!(salvage.salvaged, 1);
+    assert_eq!(salvage.skipped, vec![(len - 57, 57)]);
+    assert_eq!(read_key(repaired.clone(), "a"), Ok(Some(b"alpha".to_vec())));
+    assert_eq!(read_key(repaired.clone(), "b"), Ok(None));
+    assert_eq!(read_key(repaired, "c"), Ok(None));
+}